@@ -0,0 +1,456 @@
+use nalgebra_glm as glm;
+use nalgebra_glm::Vec3;
+use rand::prelude::*;
+use std::f32::consts::PI;
+
+use crate::geom::*;
+use crate::intersect::*;
+use crate::material::*;
+use crate::medium::*;
+use crate::spectrum::*;
+use crate::trace::*;
+
+// Bidirectional path tracing, after Veach's thesis and its formulation in
+// PBRT. For each pixel, one subpath is traced from the camera and one from
+// the light, and every vertex of the one is connected to every vertex of the
+// other. The same full path can come from several such connections, so they
+// are weighted by multiple importance sampling with the balance heuristic.
+// Connecting a light subpath directly to the camera may land on any pixel,
+// so those contributions are splatted onto the image.
+//
+// Caustics and scenes lit mostly indirectly converge much faster than with
+// the path tracer. Participating media only attenuate light here though, as
+// if all the light they scatter was absorbed.
+pub struct Bidirectional;
+
+impl Integrator for Bidirectional {
+    fn name(&self) -> &'static str {
+        "bdpt"
+    }
+
+    fn radiance(
+        &self,
+        path: Path,
+        scene: &[Sphere],
+        depths: Depths,
+        view: &View,
+    ) -> Vec3 {
+        let ctx = Ctx {
+            scene,
+            depths,
+            view,
+            wavelengths: path.wavelengths,
+        };
+        let rng = path.rng;
+        let max_vertices = depths.max as usize + 2;
+        let mut camera = Vec::with_capacity(max_vertices);
+        let beta = Vec3::repeat(1.0);
+        camera.push(Vertex::endpoint(VertexKind::Camera, view.cam_pos, beta));
+        let pdf_dir = view.dir_pdf(path.ray.dir);
+        // Light from the background can only be found by the camera subpath
+        // running into it, so it's not weighted
+        let mut radiance = random_walk(
+            &ctx,
+            rng,
+            &mut camera,
+            path.ray,
+            path.media,
+            beta,
+            pdf_dir,
+        )
+        .unwrap_or_else(Vec3::zeros);
+        let mut light = Vec::with_capacity(max_vertices);
+        let intensity = upsample(light_intensity(), path.wavelengths);
+        light.push(Vertex::endpoint(VertexKind::Light, light_pos(), intensity));
+        let light_ray = Ray {
+            origin: light_pos(),
+            dir: uniform_sample_sphere(rng),
+        };
+        let pdf_dir = 1.0 / (4.0 * PI);
+        random_walk(
+            &ctx,
+            rng,
+            &mut light,
+            light_ray,
+            view.media(path.wavelengths),
+            intensity / pdf_dir,
+            pdf_dir,
+        );
+        for t in 1..=camera.len() {
+            for s in 1..=light.len() {
+                // Connecting the light straight to the camera would only show
+                // the light itself, which is a point and thus invisible.
+                if s + t == 2 || s + t - 2 > depths.max as usize + 1 {
+                    continue;
+                }
+                let (qs, pt) = (&light[s - 1], &camera[t - 1]);
+                let l = connect(&ctx, rng, qs, pt);
+                if l == Vec3::zeros() {
+                    continue;
+                }
+                let l = l * mis_weight(view, &light[..s], &camera[..t]);
+                if t > 1 {
+                    radiance += l;
+                } else if let Some(pixel) =
+                    view.pixel((qs.pos - pt.pos).normalize())
+                {
+                    let color = match path.wavelengths {
+                        Some(ls) => spectrum_to_rgb(l, ls),
+                        None => l,
+                    };
+                    view.splats.add(pixel, color);
+                }
+            }
+        }
+        radiance
+    }
+}
+
+// What's shared by everything traced for one pixel
+struct Ctx<'a> {
+    scene: &'a [Sphere],
+    depths: Depths,
+    view: &'a View<'a>,
+    wavelengths: Option<Vec3>,
+}
+
+#[derive(Clone)]
+enum VertexKind {
+    // The first vertex of a camera subpath
+    Camera,
+    // The first vertex of a light subpath
+    Light,
+    Surface(Hit),
+}
+
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    pos: Vec3,
+    // Direction towards the previous vertex of the subpath
+    wo: Vec3,
+    // The throughput of the subpath up to and including this vertex. For a
+    // light subpath, this is the light it carries.
+    beta: Vec3,
+    // Whether the subpath scattered off a delta lobe here, so that nothing
+    // can be connected to the vertex
+    delta: bool,
+    // Whether all but the hero wavelength have been dropped at this vertex
+    hero_only: bool,
+    // The densities per unit area of sampling this vertex, from the previous
+    // vertex of the subpath and from the next one, as if the subpath had
+    // been traced in the other direction. Delta lobes have a density of 0.
+    pdf_fwd: f32,
+    pdf_rev: f32,
+    // The media the subpath arrived through
+    media: MediumStack,
+}
+
+impl Vertex {
+    fn endpoint(kind: VertexKind, pos: Vec3, beta: Vec3) -> Self {
+        Vertex {
+            kind,
+            pos,
+            wo: Vec3::zeros(),
+            beta,
+            delta: false,
+            hero_only: false,
+            pdf_fwd: 1.0,
+            pdf_rev: 0.0,
+            media: MediumStack::new(),
+        }
+    }
+
+    fn hit(&self) -> Option<&Hit> {
+        match &self.kind {
+            VertexKind::Surface(hit) => Some(hit),
+            _ => None,
+        }
+    }
+
+    // The density per unit area of sampling `next` from this vertex, when
+    // the subpath came to it from `prev`
+    fn pdf(&self, view: &View, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        let w = (next.pos - self.pos).normalize();
+        let pdf_dir = match (&self.kind, prev) {
+            (VertexKind::Camera, _) => view.dir_pdf(w),
+            (VertexKind::Light, _) => 1.0 / (4.0 * PI),
+            (VertexKind::Surface(hit), Some(prev)) => {
+                hit.mat.pdf(w, (prev.pos - self.pos).normalize(), hit)
+            }
+            (VertexKind::Surface(_), None) => 0.0,
+        };
+        self.to_area(pdf_dir, next)
+    }
+
+    // Convert a density per unit solid angle of sampling the direction
+    // towards `next`, to a density per unit area at `next`
+    fn to_area(&self, pdf_dir: f32, next: &Vertex) -> f32 {
+        let d = next.pos - self.pos;
+        let dist2 = d.magnitude_squared();
+        match next.hit() {
+            Some(hit) => {
+                pdf_dir * hit.geo_normal.dot(&d).abs() / dist2.sqrt() / dist2
+            }
+            None => pdf_dir / dist2,
+        }
+    }
+}
+
+// Extend a subpath by a random walk, starting with `ray` leaving its last
+// vertex in a direction sampled with the density `pdf_dir`, through `media`,
+// and with the throughput `beta` so far. Returns the light from the
+// background if the walk escapes the scene.
+fn random_walk(
+    ctx: &Ctx,
+    rng: &mut dyn RngCore,
+    vertices: &mut Vec<Vertex>,
+    mut ray: Ray,
+    mut media: MediumStack,
+    mut beta: Vec3,
+    mut pdf_dir: f32,
+) -> Option<Vec3> {
+    let mut hero_only = false;
+    loop {
+        let hit = closest_hit(&ray, ctx.scene, rng);
+        if let Some(medium) = media.current() {
+            let t = hit.as_ref().map_or(SKY_DISTANCE, |hit| hit.t);
+            beta = beta.component_mul(&medium.tr(rng, &ray, t));
+        }
+        let mut hit = match hit {
+            Some(hit) => hit,
+            None => {
+                let background = upsample(background_color(), ctx.wavelengths)
+                    .component_mul(&beta);
+                return Some(if hero_only {
+                    keep_hero(background)
+                } else {
+                    background
+                });
+            }
+        };
+        hit.wavelength = ctx.wavelengths.map(|ls| ls.x);
+        hero_only =
+            hero_only || (ctx.wavelengths.is_some() && hit.mat.dispersive());
+        let wo = -ray.dir;
+        let pos = ray.origin + hit.t * ray.dir;
+        let prev = vertices.len() - 1;
+        let mut vertex = Vertex {
+            kind: VertexKind::Surface(hit.clone()),
+            pos,
+            wo,
+            beta,
+            delta: false,
+            hero_only,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            media: media.clone(),
+        };
+        vertex.pdf_fwd = vertices[prev].to_area(pdf_dir, &vertex);
+        vertices.push(vertex);
+        // Count the depth like the path tracer, from 0 at the first surface
+        let depth = prev;
+        if depth >= ctx.depths.max as usize {
+            return None;
+        }
+        // Light subpaths sample the BSDF with the roles of `wi` and `wo`
+        // swapped. That's exact for reciprocal BSDFs. Refraction isn't
+        // reciprocal, but the scaling of radiance it does cancels out
+        // between entering and leaving an object.
+        let sample = hit.mat.sample(rng, wo, &hit);
+        if sample.pdf == 0.0 || leaks(&hit, sample.wi, wo) {
+            return None;
+        }
+        let cosineterm = sample.wi.dot(&hit.normal).abs();
+        let mut weight =
+            upsample((sample.brdf * cosineterm) / sample.pdf, ctx.wavelengths);
+        // Russian roulette, by the weight of the bounce alone, as the
+        // throughput of light subpaths is in units of the light's intensity
+        if depth >= ctx.depths.min as usize {
+            let survival = glm::comp_max(&weight).min(1.0);
+            if rand(rng) < survival {
+                weight /= survival;
+            } else {
+                return None;
+            }
+        }
+        beta = beta.component_mul(&weight);
+        // The densities of the BSDF are used for the MIS weights, rather
+        // than that of the sample, so that they're the same whichever
+        // strategy made the path.
+        let delta = sample.lobe.is_delta();
+        let (pdf_fwd, pdf_rev) = if delta {
+            (0.0, 0.0)
+        } else {
+            (
+                hit.mat.pdf(sample.wi, wo, &hit),
+                hit.mat.pdf(wo, sample.wi, &hit),
+            )
+        };
+        pdf_dir = pdf_fwd;
+        vertices[prev].pdf_rev =
+            vertices[prev + 1].to_area(pdf_rev, &vertices[prev]);
+        vertices[prev + 1].delta = delta;
+        if sample.lobe.contains(Lobes::TRANSMISSION) {
+            if sample.wi.dot(&hit.geo_normal) < 0.0 {
+                let interior = interior_of(&hit, ctx.wavelengths);
+                media.enter(hit.object, interior, hit.mat.invisible())
+            } else {
+                media.exit(hit.object)
+            }
+        }
+        ray = Ray {
+            origin: pos + RAY_EPSILON * sample.wi,
+            dir: sample.wi,
+        };
+    }
+}
+
+// The light carried by the full path made by connecting the light subpath
+// ending in `qs` with the camera subpath ending in `pt`, before weighting
+fn connect(ctx: &Ctx, rng: &mut dyn RngCore, qs: &Vertex, pt: &Vertex) -> Vec3 {
+    if qs.delta || pt.delta {
+        return Vec3::zeros();
+    }
+    let d = qs.pos - pt.pos;
+    let dist = d.magnitude();
+    let w = d / dist;
+    // The BSDFs times the cosines at both ends of the connection. The light
+    // emits the same in every direction. The camera responds to light by its
+    // importance, which times the cosine at the camera is just the density
+    // of camera rays in that direction.
+    let f_pt = match &pt.kind {
+        VertexKind::Surface(hit) => {
+            surface_f(hit, w, pt.wo, ctx.wavelengths) * hit.normal.dot(&w).abs()
+        }
+        _ => Vec3::repeat(ctx.view.dir_pdf(w)),
+    };
+    let f_qs = match &qs.kind {
+        VertexKind::Surface(hit) => {
+            surface_f(hit, qs.wo, -w, ctx.wavelengths)
+                * hit.normal.dot(&w).abs()
+        }
+        _ => Vec3::repeat(1.0),
+    };
+    let l = pt
+        .beta
+        .component_mul(&f_pt)
+        .component_mul(&f_qs)
+        .component_mul(&qs.beta)
+        / (dist * dist);
+    if l == Vec3::zeros() {
+        return l;
+    }
+    let shadow_ray = Ray {
+        origin: pt.pos + RAY_EPSILON * w,
+        dir: w,
+    };
+    if occluded(&shadow_ray, dist - 2.0 * RAY_EPSILON, ctx.scene, rng) {
+        return Vec3::zeros();
+    }
+    // Whichever end is on a surface knows the medium of the connection
+    let medium = match (pt.hit(), qs.hit()) {
+        (Some(hit), _) => medium_towards(pt, hit, w, ctx.wavelengths),
+        (None, Some(hit)) => medium_towards(qs, hit, -w, ctx.wavelengths),
+        (None, None) => None,
+    };
+    let l = match medium {
+        Some(medium) => {
+            let ray = Ray {
+                origin: pt.pos,
+                dir: w,
+            };
+            l.component_mul(&medium.tr(rng, &ray, dist))
+        }
+        None => l,
+    };
+    if pt.hero_only || qs.hero_only {
+        keep_hero(l)
+    } else {
+        l
+    }
+}
+
+fn surface_f(hit: &Hit, wi: Vec3, wo: Vec3, wavelengths: Option<Vec3>) -> Vec3 {
+    if leaks(hit, wi, wo) {
+        Vec3::zeros()
+    } else {
+        upsample(hit.mat.eval(wi, wo, hit), wavelengths)
+    }
+}
+
+// The medium a ray leaving the surface vertex `v` in the direction `w`
+// travels through
+fn medium_towards(
+    v: &Vertex,
+    hit: &Hit,
+    w: Vec3,
+    wavelengths: Option<Vec3>,
+) -> Option<Medium> {
+    let mut media = v.media.clone();
+    let entering = w.dot(&hit.geo_normal) < 0.0;
+    if entering == (v.wo.dot(&hit.geo_normal) > 0.0) {
+        if entering {
+            let interior = interior_of(hit, wavelengths);
+            media.enter(hit.object, interior, hit.mat.invisible())
+        } else {
+            media.exit(hit.object)
+        }
+    }
+    media.current()
+}
+
+// The balance heuristic weight of connecting the light subpath `light` to
+// the camera subpath `camera`, among all the ways to connect subpaths into
+// the same full path. Following PBRT, this is computed from the ratios of
+// the densities of the other ways to that of this one, walking outwards from
+// the connection along both subpaths.
+fn mis_weight(view: &View, light: &[Vertex], camera: &[Vertex]) -> f32 {
+    let (s, t) = (light.len(), camera.len());
+    let (qs, pt) = (&light[s - 1], &camera[t - 1]);
+    let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+    let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+    // The reverse densities of the vertices around the connection, which
+    // weren't known when the subpaths were traced
+    let pt_rev = qs.pdf(view, qs_minus, pt);
+    let pt_minus_rev = pt_minus.map_or(0.0, |v| pt.pdf(view, Some(qs), v));
+    let qs_rev = pt.pdf(view, pt_minus, qs);
+    let qs_minus_rev = qs_minus.map_or(0.0, |v| qs.pdf(view, Some(pt), v));
+    // Delta lobes have a density of 0 in both directions, so the ratios
+    // through them cancel out
+    let remap0 = |pdf: f32| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+    for i in (1..t).rev() {
+        let pdf_rev = if i == t - 1 {
+            pt_rev
+        } else if i == t - 2 {
+            pt_minus_rev
+        } else {
+            camera[i].pdf_rev
+        };
+        ratio *= remap0(pdf_rev) / remap0(camera[i].pdf_fwd);
+        // The vertices of the connection are never delta
+        let delta = i != t - 1 && camera[i].delta;
+        if !delta && !camera[i - 1].delta {
+            sum += ratio;
+        }
+    }
+    ratio = 1.0;
+    for i in (0..s).rev() {
+        let pdf_rev = if i == s - 1 {
+            qs_rev
+        } else if i == s - 2 {
+            qs_minus_rev
+        } else {
+            light[i].pdf_rev
+        };
+        ratio *= remap0(pdf_rev) / remap0(light[i].pdf_fwd);
+        let delta = i != s - 1 && light[i].delta;
+        // Camera subpaths can't hit the light, as it's a point
+        if !delta && i > 0 && !light[i - 1].delta {
+            sum += ratio;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
@@ -0,0 +1,148 @@
+use nalgebra_glm::{Vec2, Vec3};
+use rand::prelude::*;
+
+use crate::intersect::*;
+use crate::material::*;
+use crate::medium::*;
+use crate::texture::*;
+
+// Offset in texture space used to approximate the derivatives of a bump map.
+const BUMP_DELTA: f32 = 0.0005;
+
+// Perturbs the shading normal of another material by a tangent-space normal
+// map, such as those usually stored in images.
+//
+// The map is expected to be encoded with each component in [0, 1], and with
+// +x along increasing `u` and +y towards decreasing `v`, i.e. "up" in the
+// image. This is the common OpenGL convention.
+pub struct NormalMapped {
+    pub base: Mat,
+    pub map: Tex<Vec3>,
+}
+
+impl Bsdf for NormalMapped {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        self.base.eval(wi, wo, hit)
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        self.base.sample(rng, wo, hit)
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        self.base.pdf(wi, wo, hit)
+    }
+
+    fn lobes(&self) -> Lobes {
+        self.base.lobes()
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        self.base.interior()
+    }
+
+    fn dispersive(&self) -> bool {
+        self.base.dispersive()
+    }
+
+    fn opacity(&self, hit: &Hit) -> f32 {
+        self.base.opacity(hit)
+    }
+
+    fn invisible(&self) -> bool {
+        self.base.invisible()
+    }
+
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        let n = self.base.shading_normal(hit);
+        let m = self.map.at(hit) * 2.0 - Vec3::repeat(1.0);
+        // Gram-Schmidt, as `dpdu` is not necessarily perpendicular to a
+        // normal which has already been perturbed.
+        let tangent = (hit.dpdu - n * n.dot(&hit.dpdu)).normalize();
+        let bitangent = n.cross(&tangent);
+        let ns = (tangent * m.x + bitangent * m.y + n * m.z).normalize();
+        if ns.iter().all(|c| c.is_finite()) {
+            ns
+        } else {
+            n
+        }
+    }
+}
+
+// Perturbs the shading normal of another material as if the surface was
+// displaced along the normal by a height map.
+pub struct BumpMapped {
+    pub base: Mat,
+    pub height: Tex<f32>,
+    // The displacement of a height of 1, in world units.
+    pub scale: f32,
+}
+
+impl BumpMapped {
+    // The height at `hit` moved by `d` in texture space.
+    fn height_offset(&self, hit: &Hit, d: Vec2) -> f32 {
+        let dp = hit.dpdu * d.x + hit.dpdv * d.y;
+        let moved = Hit {
+            pos: hit.pos + dp,
+            local_pos: hit.local_pos + dp,
+            uv: hit.uv + d,
+            ..hit.clone()
+        };
+        self.scale * self.height.at(&moved)
+    }
+}
+
+impl Bsdf for BumpMapped {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        self.base.eval(wi, wo, hit)
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        self.base.sample(rng, wo, hit)
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        self.base.pdf(wi, wo, hit)
+    }
+
+    fn lobes(&self) -> Lobes {
+        self.base.lobes()
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        self.base.interior()
+    }
+
+    fn dispersive(&self) -> bool {
+        self.base.dispersive()
+    }
+
+    fn opacity(&self, hit: &Hit) -> f32 {
+        self.base.opacity(hit)
+    }
+
+    fn invisible(&self) -> bool {
+        self.base.invisible()
+    }
+
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        let n = self.base.shading_normal(hit);
+        let h = self.height_offset(hit, Vec2::zeros());
+        let dhdu = (self.height_offset(hit, Vec2::new(BUMP_DELTA, 0.0)) - h)
+            / BUMP_DELTA;
+        let dhdv = (self.height_offset(hit, Vec2::new(0.0, BUMP_DELTA)) - h)
+            / BUMP_DELTA;
+        // The partial derivatives of the displaced surface, ignoring the
+        // change of the normal itself, which is small for most surfaces.
+        let dpdu = hit.dpdu + n * dhdu;
+        let dpdv = hit.dpdv + n * dhdv;
+        let ns = dpdu.cross(&dpdv).normalize();
+        if !ns.iter().all(|c| c.is_finite()) {
+            n
+        } else if ns.dot(&n) < 0.0 {
+            -ns
+        } else {
+            ns
+        }
+    }
+}
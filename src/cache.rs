@@ -0,0 +1,282 @@
+use nalgebra_glm::Vec3;
+use rand::prelude::*;
+use rayon::prelude::*;
+use std::cmp;
+use std::f32::consts::PI;
+
+use crate::geom::*;
+use crate::intersect::*;
+use crate::material::*;
+use crate::spectrum::*;
+use crate::trace::*;
+
+// Pixels only reuse the light of cells that saw a surface facing about the
+// same way as their own...
+const MIN_NORMAL_COS: f32 = 0.9;
+// ...and not further off the plane of it than this, relative to the
+// distance from the camera
+const MAX_REL_DIST: f32 = 0.02;
+
+// A cache of the light arriving at the surfaces the camera sees, traced at a
+// lower resolution than the image. The image is divided into cells of
+// `subsampling` by `subsampling` pixels. At the surface seen through the
+// corner of every cell, the incoming light is gathered over the hemisphere
+// as irradiance. While the camera and the scene stay put, every frame adds
+// another sample to every cell. The cache keeps improving even when the
+// image isn't accumulated.
+//
+// Every pixel of the image still traces its own ray from the camera, which
+// finds the surface and its normal like a G-buffer would. Light straight
+// from the light source is sampled for the pixel itself. When the BSDF picks
+// a diffuse lobe to sample, the reflected light is computed from the
+// irradiance of the cells around the pixel that saw the same surface.
+// Otherwise, like for highlights, or where no cell saw the same surface, the
+// path is traced on as by the path tracer. Surfaces without diffuse lobes,
+// like mirrors and glass, are path traced altogether, as is everything seen
+// through fog, where light also scatters before reaching a surface.
+pub struct RadianceCache {
+    subsampling: u32,
+    // Dimensions of the grid of cell corners
+    dims: [u32; 2],
+    cells: Vec<Cell>,
+    // Where the objects of the scene were when the cells were traced
+    placements: Vec<(Vec3, f32)>,
+}
+
+struct Cell {
+    surface: Option<Surface>,
+    // The mean of the irradiance samples so far, in RGB
+    irradiance: Vec3,
+    samples: u32,
+}
+
+// The surface seen through the corner of a cell
+#[derive(Clone, Copy)]
+struct Surface {
+    pos: Vec3,
+    // The geometric normal, on the side of the camera
+    normal: Vec3,
+    object: usize,
+}
+
+impl RadianceCache {
+    pub fn new() -> Self {
+        RadianceCache {
+            subsampling: 1,
+            dims: [0, 0],
+            cells: vec![],
+            placements: vec![],
+        }
+    }
+
+    // Forget the cached light, e.g. as the camera has moved
+    pub fn clear(&mut self) {
+        self.cells.clear()
+    }
+
+    // Add another irradiance sample to every cell, first tracing the cells
+    // anew if the view or the scene has changed since they were traced
+    pub fn update(
+        &mut self,
+        scene: &[Sphere],
+        view: &View,
+        depths: Depths,
+        spectral: bool,
+        subsampling: u8,
+    ) {
+        // Nothing is looked up in the cache through fog
+        if view.dims.contains(&0) || view.fog.is_some() {
+            return;
+        }
+        let s = subsampling as u32;
+        // Every pixel has cell corners on both sides of it
+        let dims = [(view.dims[0] - 1) / s + 2, (view.dims[1] - 1) / s + 2];
+        let placements: Vec<_> = scene.iter().map(Sphere::placement).collect();
+        if s != self.subsampling
+            || dims != self.dims
+            || placements != self.placements
+        {
+            self.clear()
+        }
+        if self.cells.is_empty() {
+            self.subsampling = s;
+            self.dims = dims;
+            self.placements = placements;
+            self.cells = (0..dims[0] * dims[1])
+                .into_par_iter()
+                .map(|k| {
+                    let mut rng = SmallRng::seed_from_u64(view.seed + k as u64);
+                    let (i, j) = (k % dims[0], k / dims[0]);
+                    let ray = view.ray(
+                        (i * s) as f32 / view.dims[0] as f32,
+                        (j * s) as f32 / view.dims[1] as f32,
+                    );
+                    Cell {
+                        surface: closest_hit(&ray, scene, &mut rng)
+                            .filter(cacheable)
+                            .map(|hit| Surface {
+                                pos: hit.pos,
+                                normal: facing(hit.geo_normal, -ray.dir),
+                                object: hit.object,
+                            }),
+                        irradiance: Vec3::zeros(),
+                        samples: 0,
+                    }
+                })
+                .collect();
+        }
+        let n = self.cells.len() as u64;
+        self.cells.par_iter_mut().enumerate().for_each(|(k, cell)| {
+            let surface = match cell.surface {
+                Some(surface) => surface,
+                None => return,
+            };
+            let mut rng = SmallRng::seed_from_u64(view.seed + n + k as u64);
+            let wavelengths = if spectral {
+                Some(sample_wavelengths(rng.gen()))
+            } else {
+                None
+            };
+            // Cosine-weighted directions, so the irradiance is just `π`
+            // times the radiance arriving from them
+            let wi = Frame::new(surface.normal)
+                .to_world(cosine_sample_hemisphere(&mut rng));
+            let [w, h] = view.dims;
+            let (i, j) = (k as u32 % dims[0], k as u32 / dims[0]);
+            let path = Path {
+                pixel: (cmp::min(i * s, w - 1), cmp::min(j * s, h - 1)),
+                ray: Ray {
+                    origin: surface.pos + RAY_EPSILON * wi,
+                    dir: wi,
+                },
+                depth: 1,
+                throughput: Vec3::repeat(1.0),
+                radiance: Vec3::zeros(),
+                media: view.media(wavelengths),
+                wavelengths,
+                hero_only: false,
+                rng: &mut rng,
+            };
+            let mut radiance = PathTracer.radiance(path, scene, depths, view);
+            if let Some(ls) = wavelengths {
+                radiance = spectrum_to_rgb(radiance, ls)
+            }
+            cell.samples += 1;
+            cell.irradiance +=
+                (PI * radiance - cell.irradiance) / cell.samples as f32;
+        });
+    }
+
+    // The radiance arriving at the camera along `path.ray`, which goes
+    // through the corner of the pixel `path.pixel`
+    pub fn radiance(
+        &self,
+        mut path: Path,
+        scene: &[Sphere],
+        depths: Depths,
+        view: &View,
+    ) -> Vec3 {
+        if path.media.current().is_some() {
+            return PathTracer.radiance(path, scene, depths, view);
+        }
+        let mut hit = match closest_hit(&path.ray, scene, path.rng) {
+            Some(hit) => hit,
+            None => return upsample(background_color(), path.wavelengths),
+        };
+        hit.wavelength = path.wavelengths.map(|ls| ls.x);
+        let wo = -path.ray.dir;
+        if !cacheable(&hit) {
+            return PathTracer.radiance(path, scene, depths, view);
+        }
+        let hit_pos = path.ray.origin + hit.t * path.ray.dir;
+        let direct = direct_light(&hit, hit_pos, wo, &mut path, scene);
+        let sample = hit.mat.sample(path.rng, wo, &hit);
+        if sample.pdf == 0.0 || leaks(&hit, sample.wi, wo) {
+            return direct;
+        }
+        let cosineterm = sample.wi.dot(&hit.normal).abs();
+        let weight =
+            upsample((sample.brdf * cosineterm) / sample.pdf, path.wavelengths);
+        // The weight of a direction sampled from a diffuse lobe is on average
+        // the fraction of the light arriving from all over the hemisphere
+        // that's reflected towards `wo`
+        if sample.lobe.contains(Lobes::DIFFUSE) {
+            if let Some(irradiance) = self.lookup(&hit, wo, path.pixel) {
+                let irradiance = upsample(irradiance, path.wavelengths);
+                return direct + weight.component_mul(&irradiance) / PI;
+            }
+        }
+        // Otherwise the path goes on as in the path tracer
+        let path = Path {
+            ray: Ray {
+                origin: hit_pos + RAY_EPSILON * sample.wi,
+                dir: sample.wi,
+            },
+            depth: 1,
+            throughput: weight,
+            radiance: direct,
+            ..path
+        };
+        PathTracer.radiance(path, scene, depths, view)
+    }
+
+    // The irradiance at the hit, interpolated between the cells around the
+    // pixel that saw the same surface, if any did
+    fn lookup(&self, hit: &Hit, wo: Vec3, (x, y): (u32, u32)) -> Option<Vec3> {
+        if self.cells.is_empty() {
+            return None;
+        }
+        let normal = facing(hit.geo_normal, wo);
+        let s = self.subsampling as f32;
+        let (fx, fy) = (x as f32 / s, y as f32 / s);
+        let (i, j) = (fx.floor(), fy.floor());
+        let (tx, ty) = (fx - i, fy - j);
+        let (i, j) = (i as u32, j as u32);
+        let corners = [
+            (i, j, (1.0 - tx) * (1.0 - ty)),
+            (i + 1, j, tx * (1.0 - ty)),
+            (i, j + 1, (1.0 - tx) * ty),
+            (i + 1, j + 1, tx * ty),
+        ];
+        let mut sum = Vec3::zeros();
+        let mut weight_sum = 0.0;
+        for &(i, j, weight) in &corners {
+            let cell = &self.cells[(j * self.dims[0] + i) as usize];
+            let same_surface = cell.surface.map_or(false, |surface| {
+                surface.object == hit.object
+                    && surface.normal.dot(&normal) >= MIN_NORMAL_COS
+                    && (surface.pos - hit.pos).dot(&surface.normal).abs()
+                        <= MAX_REL_DIST * hit.t
+            });
+            if same_surface {
+                sum += weight * cell.irradiance;
+                weight_sum += weight;
+            }
+        }
+        if weight_sum > 0.0 {
+            Some(sum / weight_sum)
+        } else {
+            None
+        }
+    }
+}
+
+// Only the light arriving from above the surface is cached, for reflecting
+// off diffuse lobes. Paths entering the surface would also have to enter its
+// medium, and paths off dispersive surfaces keep only the hero wavelength,
+// so those surfaces are left to the path tracer altogether.
+fn cacheable(hit: &Hit) -> bool {
+    let lobes = hit.mat.lobes();
+    lobes.contains(Lobes::DIFFUSE)
+        && !lobes.contains(Lobes::TRANSMISSION)
+        && !hit.mat.dispersive()
+}
+
+// `n` flipped to the side of `w`
+fn facing(n: Vec3, w: Vec3) -> Vec3 {
+    if n.dot(&w) < 0.0 {
+        -n
+    } else {
+        n
+    }
+}
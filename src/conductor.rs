@@ -0,0 +1,149 @@
+use nalgebra_glm::{vec3, Vec3};
+use rand::prelude::*;
+
+use crate::intersect::*;
+use crate::material::*;
+use crate::microfacet::*;
+use crate::texture::*;
+use crate::thinfilm::*;
+
+// A rough metal, reflecting according to the Fresnel equations for its
+// complex index of refraction `eta + i k`, which is given per color channel.
+#[derive(Clone)]
+pub struct Conductor {
+    pub roughness: Tex<f32>,
+    pub eta: Vec3,
+    pub k: Vec3,
+    // E.g. an oxide layer, like on heat-tinted titanium or steel
+    pub film: Option<ThinFilm>,
+}
+
+impl Conductor {
+    pub fn gold(roughness: f32) -> Self {
+        Conductor::new(
+            roughness,
+            vec3(0.143, 0.374, 1.442),
+            vec3(3.983, 2.385, 1.603),
+        )
+    }
+
+    pub fn copper(roughness: f32) -> Self {
+        Conductor::new(
+            roughness,
+            vec3(0.200, 0.924, 1.102),
+            vec3(3.912, 2.452, 2.142),
+        )
+    }
+
+    pub fn silver(roughness: f32) -> Self {
+        Conductor::new(
+            roughness,
+            vec3(0.155, 0.117, 0.138),
+            vec3(4.828, 3.122, 2.147),
+        )
+    }
+
+    pub fn aluminium(roughness: f32) -> Self {
+        Conductor::new(
+            roughness,
+            vec3(1.657, 0.880, 0.521),
+            vec3(9.224, 6.270, 4.837),
+        )
+    }
+
+    fn new(roughness: f32, eta: Vec3, k: Vec3) -> Self {
+        Conductor {
+            roughness: roughness.into(),
+            eta,
+            k,
+            film: None,
+        }
+    }
+
+    fn alpha(&self, hit: &Hit) -> f32 {
+        ggx_alpha(self.roughness.at(hit))
+    }
+
+    fn smooth(&self, hit: &Hit) -> bool {
+        is_smooth(self.roughness.at(hit))
+    }
+
+    fn fresnel(&self, hit: &Hit, cos_i: f32) -> Vec3 {
+        match &self.film {
+            Some(film) => film.reflectance(hit, cos_i, self.eta, self.k),
+            None => vec3(
+                fresnel_conductor(cos_i, self.eta.x, self.k.x),
+                fresnel_conductor(cos_i, self.eta.y, self.k.y),
+                fresnel_conductor(cos_i, self.eta.z, self.k.z),
+            ),
+        }
+    }
+
+    fn f(&self, hit: &Hit, wi: Vec3, wo: Vec3) -> Vec3 {
+        let (cos_i, cos_o) = (cos_theta(wi), cos_theta(wo));
+        if cos_i <= 0.0 || cos_o <= 0.0 || self.smooth(hit) {
+            return Vec3::zeros();
+        }
+        let alpha = self.alpha(hit);
+        let wh = (wi + wo).normalize();
+        self.fresnel(hit, wo.dot(&wh))
+            * (ggx_d(wh, alpha) * ggx_g(wi, wo, alpha) / (4.0 * cos_i * cos_o))
+    }
+}
+
+impl Bsdf for Conductor {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        let frame = Frame::new(hit.normal);
+        self.f(hit, frame.to_local(wi), frame.to_local(wo))
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        let frame = Frame::new(hit.normal);
+        let wo = frame.to_local(wo);
+        if self.smooth(hit) {
+            // A perfect mirror, see `SpecularReflector`
+            let wi = vec3(-wo.x, -wo.y, wo.z);
+            let cos_i = cos_theta(wi);
+            return DirSample {
+                wi: frame.to_world(wi),
+                pdf: if cos_i > 0.0 { 1.0 } else { 0.0 },
+                brdf: if cos_i > 0.0 {
+                    self.fresnel(hit, cos_i) / cos_i
+                } else {
+                    Vec3::zeros()
+                },
+                lobe: Lobes::DELTA,
+            };
+        }
+        let wh = ggx_sample_wh(self.alpha(hit), rand(rng), rand(rng));
+        let wi = reflect(wo, wh);
+        DirSample {
+            wi: frame.to_world(wi),
+            pdf: if cos_theta(wi) > 0.0 && cos_theta(wo) > 0.0 {
+                reflected_pdf(ggx_pdf_wh(wh, self.alpha(hit)), wo, wh)
+            } else {
+                0.0
+            },
+            brdf: self.f(hit, wi, wo),
+            lobe: Lobes::GLOSSY,
+        }
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        let frame = Frame::new(hit.normal);
+        let (wi, wo) = (frame.to_local(wi), frame.to_local(wo));
+        if cos_theta(wi) <= 0.0 || cos_theta(wo) <= 0.0 || self.smooth(hit) {
+            return 0.0;
+        }
+        let wh = (wi + wo).normalize();
+        reflected_pdf(ggx_pdf_wh(wh, self.alpha(hit)), wo, wh)
+    }
+
+    fn lobes(&self) -> Lobes {
+        ggx_lobes(&self.roughness)
+    }
+
+    fn dispersive(&self) -> bool {
+        self.film.is_some()
+    }
+}
@@ -0,0 +1,56 @@
+use nalgebra_glm::Vec3;
+use rand::prelude::*;
+
+use crate::intersect::*;
+use crate::material::*;
+use crate::medium::*;
+use crate::texture::*;
+
+// Cuts holes in another material, for e.g. leaves or fences modelled as
+// simple shapes with a mask. Where the opacity is 0, rays pass through the
+// surface as if it wasn't there, and where it's 1 the surface is solid.
+// Anything in between is stochastically transparent: each ray passes through
+// with the probability `1 - opacity`, so the surface is semi-transparent on
+// average.
+pub struct Cutout {
+    pub base: Mat,
+    pub opacity: Tex<f32>,
+}
+
+impl Bsdf for Cutout {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        self.base.eval(wi, wo, hit)
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        self.base.sample(rng, wo, hit)
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        self.base.pdf(wi, wo, hit)
+    }
+
+    fn lobes(&self) -> Lobes {
+        self.base.lobes()
+    }
+
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        self.base.shading_normal(hit)
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        self.base.interior()
+    }
+
+    fn dispersive(&self) -> bool {
+        self.base.dispersive()
+    }
+
+    fn opacity(&self, hit: &Hit) -> f32 {
+        self.base.opacity(hit) * self.opacity.at(hit)
+    }
+
+    fn invisible(&self) -> bool {
+        self.base.invisible()
+    }
+}
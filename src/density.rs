@@ -0,0 +1,306 @@
+use nalgebra_glm::{vec3, Vec3};
+use noise::{Fbm, MultiFractal, NoiseFn};
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::intersect::*;
+
+// Cells of the majorant grid along each axis
+const MAJORANT_RES: usize = 16;
+// Points sampled along each axis of a majorant cell, to estimate the max of a
+// density within it
+const MAX_SAMPLES: usize = 4;
+// Headroom on top of an estimated max, for the peaks between the samples
+const MAX_MARGIN: f32 = 1.25;
+
+// The density of a heterogeneous medium, like a cloud or smoke, which scales
+// its coefficients from point to point. Densities are defined in the box from
+// -1 to 1 on each axis, and are 0 outside of it.
+pub trait DensityField: Send + Sync {
+    fn at(&self, p: Vec3) -> f32;
+
+    // An upper bound of the density within the box from `min` to `max`. By
+    // default it's estimated from samples, which fits smooth fields like
+    // noise. Where the bound is too low, tracking makes up for it with
+    // negative weights, which can get very noisy, and where it's 0, the
+    // density in the box is missed altogether.
+    fn max_in(&self, min: Vec3, max: Vec3) -> f32 {
+        let n = MAX_SAMPLES;
+        let mut m = 0.0f32;
+        for x in 0..=n {
+            for y in 0..=n {
+                for z in 0..=n {
+                    let f = vec3(x as f32, y as f32, z as f32) / n as f32;
+                    m = m.max(self.at(min + (max - min).component_mul(&f)));
+                }
+            }
+        }
+        m * MAX_MARGIN
+    }
+}
+
+// A shared handle to a density placed in the scene, together with a coarse
+// grid of the max density in each of its cells. The max bounds the density in
+// the whole cell, which lets delta and ratio tracking step through empty and
+// thin parts of the medium in a few long strides. Like `Mat`, cloning is
+// cheap.
+#[derive(Clone)]
+pub struct Density {
+    grid: Arc<MajorantGrid>,
+    // Half the side of the box the density fills, in world units
+    size: f32,
+    // The centre of the box in the world
+    origin: Vec3,
+}
+
+struct MajorantGrid {
+    field: Box<dyn DensityField>,
+    cells: Vec<f32>,
+}
+
+impl Density {
+    pub fn new<F: DensityField + 'static>(field: F, size: f32) -> Self {
+        let n = MAJORANT_RES;
+        let cell = 2.0 / n as f32;
+        let mut cells = Vec::with_capacity(n * n * n);
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let min = vec3(x as f32, y as f32, z as f32) * cell
+                        - Vec3::repeat(1.0);
+                    cells.push(field.max_in(min, min + Vec3::repeat(cell)));
+                }
+            }
+        }
+        Density {
+            grid: Arc::new(MajorantGrid {
+                field: Box::new(field),
+                cells,
+            }),
+            size,
+            origin: Vec3::zeros(),
+        }
+    }
+
+    // The same density, centred on `origin`, e.g. the centre of the object
+    // that bounds it
+    pub fn placed(&self, origin: Vec3) -> Self {
+        Density {
+            origin,
+            ..self.clone()
+        }
+    }
+
+    // The density at the point `p` in the world
+    pub fn at(&self, p: Vec3) -> f32 {
+        let p = (p - self.origin) / self.size;
+        if p.iter().any(|x| x.abs() > 1.0) {
+            0.0
+        } else {
+            self.grid.field.at(p)
+        }
+    }
+
+    // Walk the cells of the majorant grid along `ray`, up to `t_max`. `f` is
+    // called with the span `t0` to `t1` of the ray within each cell, and the
+    // max density there, until it returns false. Parts of the ray outside of
+    // the box are skipped.
+    pub fn segments(
+        &self,
+        ray: &Ray,
+        t_max: f32,
+        mut f: impl FnMut(f32, f32, f32) -> bool,
+    ) {
+        let n = MAJORANT_RES as f32;
+        // The ray in grid coordinates, where each cell is of size 1
+        let scale = 0.5 * n / self.size;
+        let o = (ray.origin - self.origin) * scale + Vec3::repeat(0.5 * n);
+        let d = ray.dir * scale;
+        let (mut t0, mut t1) = (0.0f32, t_max);
+        for a in 0..3 {
+            let (ta, tb) = ((0.0 - o[a]) / d[a], (n - o[a]) / d[a]);
+            t0 = t0.max(ta.min(tb));
+            t1 = t1.min(ta.max(tb));
+        }
+        if t0 >= t1 {
+            return;
+        }
+        let p = o + t0 * d;
+        let mut cell = [0isize; 3];
+        let mut step = [0isize; 3];
+        let mut t_next = [std::f32::INFINITY; 3];
+        let mut t_delta = [std::f32::INFINITY; 3];
+        for a in 0..3 {
+            cell[a] = (p[a].floor() as isize)
+                .max(0)
+                .min(MAJORANT_RES as isize - 1);
+            if d[a] > 0.0 {
+                step[a] = 1;
+                t_next[a] = t0 + ((cell[a] + 1) as f32 - p[a]) / d[a];
+                t_delta[a] = 1.0 / d[a];
+            } else if d[a] < 0.0 {
+                step[a] = -1;
+                t_next[a] = t0 + (cell[a] as f32 - p[a]) / d[a];
+                t_delta[a] = -1.0 / d[a];
+            }
+        }
+        let mut t = t0;
+        loop {
+            let a = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] {
+                    0
+                } else {
+                    2
+                }
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+            let t_exit = t_next[a].min(t1);
+            let res = MAJORANT_RES as isize;
+            let i = cell[0] + res * (cell[1] + res * cell[2]);
+            if !f(t, t_exit, self.grid.cells[i as usize]) || t_exit >= t1 {
+                return;
+            }
+            t = t_exit;
+            cell[a] += step[a];
+            t_next[a] += t_delta[a];
+            if cell[a] < 0 || cell[a] >= res {
+                return;
+            }
+        }
+    }
+}
+
+// Billowing clouds of fractal noise, thinning out towards the edge of the box
+// so that they fit in the sphere within it
+pub struct NoiseDensity {
+    noise: Fbm,
+    // The frequency of the noise within the box
+    pub scale: f32,
+    // Noise values below this are clear air, which breaks the clouds up
+    pub threshold: f32,
+}
+
+impl NoiseDensity {
+    pub fn new(scale: f32, octaves: usize, threshold: f32) -> Self {
+        NoiseDensity {
+            noise: Fbm::new().set_octaves(octaves),
+            scale,
+            threshold,
+        }
+    }
+}
+
+impl DensityField for NoiseDensity {
+    fn at(&self, p: Vec3) -> f32 {
+        let q = p * self.scale;
+        let x = self.noise.get([q.x as f64, q.y as f64, q.z as f64]) as f32;
+        let x = (0.5 + 0.5 * x - self.threshold) / (1.0 - self.threshold);
+        let falloff = 1.0 - p.magnitude_squared();
+        (x * falloff).max(0.0).min(1.0)
+    }
+}
+
+// A grid of densities, e.g. smoke from a fluid simulation, interpolated
+// trilinearly between the voxel centres.
+//
+// Grids are loaded from a simple raw format: the number of voxels along x, y
+// and z as little-endian `u32`s, followed by the density of every voxel as a
+// little-endian `f32`, with x varying fastest and z slowest.
+pub struct VoxelGrid {
+    dims: [usize; 3],
+    values: Vec<f32>,
+}
+
+impl VoxelGrid {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        let invalid =
+            |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let word = |i: usize| -> [u8; 4] {
+            bytes[4 * i..4 * i + 4].try_into().unwrap()
+        };
+        if bytes.len() < 12 {
+            return Err(invalid("voxel grid too short for its header"));
+        }
+        let dim = |i| u32::from_le_bytes(word(i)) as usize;
+        let dims = [dim(0), dim(1), dim(2)];
+        let count = dims[0]
+            .checked_mul(dims[1])
+            .and_then(|n| n.checked_mul(dims[2]))
+            .filter(|&n| n > 0)
+            .ok_or_else(|| invalid("voxel grid has no voxels"))?;
+        if count.checked_mul(4).and_then(|n| n.checked_add(12))
+            != Some(bytes.len())
+        {
+            return Err(invalid("voxel grid size doesn't match its header"));
+        }
+        let values = (0..count)
+            .map(|i| f32::from_le_bytes(word(3 + i)))
+            .map(|x| Some(x.max(0.0)).filter(|_| x.is_finite()))
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid("voxel grid has non-finite densities"))?;
+        Ok(VoxelGrid { dims, values })
+    }
+
+    // The density of the voxel at `i`, clamped to the grid
+    fn voxel(&self, i: [isize; 3]) -> f32 {
+        let c = |a: usize| i[a].max(0).min(self.dims[a] as isize - 1) as usize;
+        self.values[c(0) + self.dims[0] * (c(1) + self.dims[1] * c(2))]
+    }
+
+    // The position of `p` in voxels, offset so that voxel centres are whole
+    fn voxel_pos(&self, p: Vec3) -> Vec3 {
+        let dims = vec3(
+            self.dims[0] as f32,
+            self.dims[1] as f32,
+            self.dims[2] as f32,
+        );
+        (p + Vec3::repeat(1.0)).component_mul(&dims) * 0.5 - Vec3::repeat(0.5)
+    }
+}
+
+impl DensityField for VoxelGrid {
+    fn at(&self, p: Vec3) -> f32 {
+        let u = self.voxel_pos(p);
+        let i = [u.x.floor(), u.y.floor(), u.z.floor()];
+        let f = vec3(u.x - i[0], u.y - i[1], u.z - i[2]);
+        let i = [i[0] as isize, i[1] as isize, i[2] as isize];
+        let mut sum = 0.0;
+        for corner in 0..8 {
+            let o = [corner & 1, (corner >> 1) & 1, corner >> 2];
+            let mut w = 1.0;
+            for a in 0..3 {
+                w *= if o[a] == 1 { f[a] } else { 1.0 - f[a] };
+            }
+            sum += w * self.voxel([i[0] + o[0], i[1] + o[1], i[2] + o[2]]);
+        }
+        sum
+    }
+
+    // Exact, as the interpolated density is never more than that of the
+    // voxels around
+    fn max_in(&self, min: Vec3, max: Vec3) -> f32 {
+        let (lo, hi) = (self.voxel_pos(min), self.voxel_pos(max));
+        let mut m = 0.0f32;
+        for z in lo.z.floor() as isize..=hi.z.floor() as isize + 1 {
+            for y in lo.y.floor() as isize..=hi.y.floor() as isize + 1 {
+                for x in lo.x.floor() as isize..=hi.x.floor() as isize + 1 {
+                    m = m.max(self.voxel([x, y, z]));
+                }
+            }
+        }
+        m
+    }
+}
+
+#[cfg(test)]
+mod tests;
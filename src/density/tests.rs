@@ -0,0 +1,189 @@
+// Checks of the voxel grid format, and of tracking through densities
+
+use nalgebra_glm as glm;
+use rand::prelude::*;
+use std::io;
+
+use super::*;
+use crate::medium::*;
+
+// A voxel grid file of the given size and densities
+fn bytes(dims: [u32; 3], values: &[f32]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &dim in &dims {
+        bytes.extend_from_slice(&dim.to_le_bytes());
+    }
+    for &x in values {
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    bytes
+}
+
+fn assert_invalid(bytes: &[u8], msg: &str) {
+    match VoxelGrid::parse(bytes) {
+        Ok(_) => panic!("parsed, expected the error \"{}\"", msg),
+        Err(e) => {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(e.to_string(), msg);
+        }
+    }
+}
+
+#[test]
+fn round_trip() {
+    let values = [0.0, 0.5, 1.0, 2.0, 0.25, 0.75];
+    let grid = VoxelGrid::parse(&bytes([3, 2, 1], &values)).unwrap();
+    assert_eq!(grid.dims, [3, 2, 1]);
+    assert_eq!(grid.values, values);
+    // The density at `(x, y)` in units of voxels from the corner of the grid
+    let at = |x: f32, y: f32| grid.at(vec3(x / 1.5 - 1.0, y - 1.0, 0.0));
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+    // That of the voxel at its centre, with x varying fastest in the file
+    assert!(close(at(1.5, 0.5), 0.5));
+    assert!(close(at(0.5, 1.5), 2.0));
+    // Halfway in between two voxels
+    assert!(close(at(1.0, 0.5), 0.25));
+}
+
+#[test]
+fn negative_densities_are_clamped() {
+    let grid = VoxelGrid::parse(&bytes([2, 1, 1], &[-1.0, 1.0])).unwrap();
+    assert_eq!(grid.values, [0.0, 1.0]);
+}
+
+#[test]
+fn header_too_short() {
+    assert_invalid(&[], "voxel grid too short for its header");
+    assert_invalid(
+        &[1, 0, 0, 0, 1, 0, 0, 0],
+        "voxel grid too short for its header",
+    );
+}
+
+#[test]
+fn no_voxels() {
+    assert_invalid(&bytes([4, 0, 4], &[]), "voxel grid has no voxels");
+}
+
+#[test]
+fn size_mismatch() {
+    let msg = "voxel grid size doesn't match its header";
+    assert_invalid(&bytes([2, 2, 1], &[1.0; 3]), msg);
+    assert_invalid(&bytes([2, 2, 1], &[1.0; 5]), msg);
+    // Not even a whole number of values
+    let mut b = bytes([1, 1, 1], &[1.0]);
+    b.push(0);
+    assert_invalid(&b, msg);
+    // Far more voxels than there's memory for
+    assert_invalid(&bytes([u32::MAX, u32::MAX, 1], &[1.0]), msg);
+}
+
+#[test]
+fn non_finite_values() {
+    let msg = "voxel grid has non-finite densities";
+    for &x in &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+        assert_invalid(&bytes([2, 1, 1], &[1.0, x]), msg);
+    }
+}
+
+// A density that's the same all over the box
+struct Constant(f32);
+
+impl DensityField for Constant {
+    fn at(&self, _: Vec3) -> f32 {
+        self.0
+    }
+}
+
+// A ray that crosses the box of a density of size 2 at an angle, through
+// many cells of the majorant grid, for `LENGTH_INSIDE`. It enters through the
+// side at x = -2 and leaves through the one at z = 2, without ever crossing
+// the planes between the cells along two axes at once.
+fn diagonal() -> Ray {
+    Ray {
+        origin: vec3(-3.0, -2.4, -2.1),
+        dir: vec3(1.0, 1.0, 1.0).normalize(),
+    }
+}
+
+const LENGTH_INSIDE: f32 = 3.1 * 1.732_050_8;
+// How far to follow the ray, well past the box
+const T_MAX: f32 = 10.0;
+
+fn medium(density: Density) -> Medium {
+    Medium {
+        sigma_a: Vec3::repeat(0.1),
+        sigma_s: vec3(0.2, 0.4, 0.6),
+        g: 0.0,
+        density: Some(density),
+    }
+}
+
+// The same constant density, as a field and as a voxel grid
+fn constant_densities(d: f32) -> Vec<(&'static str, Density)> {
+    let grid = VoxelGrid::parse(&bytes([4, 4, 4], &[d; 64])).unwrap();
+    vec![
+        ("field", Density::new(Constant(d), 2.0)),
+        ("voxel grid", Density::new(grid, 2.0)),
+    ]
+}
+
+#[test]
+fn segments_cover_the_box() {
+    for (name, density) in constant_densities(0.5) {
+        let mut spans = vec![];
+        density.segments(&diagonal(), T_MAX, |t0, t1, max_density| {
+            spans.push((t0, t1, max_density));
+            true
+        });
+        assert!(spans.len() > 16, "{}: only {} cells", name, spans.len());
+        for pair in spans.windows(2) {
+            assert_eq!(pair[0].1, pair[1].0, "{}: gap between cells", name);
+        }
+        let length = spans.last().unwrap().1 - spans[0].0;
+        assert!(
+            (length - LENGTH_INSIDE).abs() < 1e-4,
+            "{}: spans {} of {} inside the box",
+            name,
+            length,
+            LENGTH_INSIDE
+        );
+        for &(_, _, max_density) in &spans {
+            assert!(max_density >= 0.5, "{}: max {}", name, max_density);
+        }
+    }
+}
+
+// Ratio tracking for the transmittance, and weighted delta tracking for
+// sampling, both on average let through `exp(-σt d)` of the light
+#[test]
+fn tracking_matches_beer_lambert() {
+    const SAMPLES: usize = 20_000;
+    let mut rng = SmallRng::seed_from_u64(0);
+    for (name, density) in constant_densities(0.5) {
+        let medium = medium(density);
+        let expected = (-medium.sigma_t() * 0.5 * LENGTH_INSIDE).map(f32::exp);
+        let (mut ratio, mut delta) = (Vec3::zeros(), Vec3::zeros());
+        for _ in 0..SAMPLES {
+            ratio += medium.tr(&mut rng, &diagonal(), T_MAX);
+            if let MediumSample::Passed { weight } =
+                medium.sample(&mut rng, &diagonal(), T_MAX)
+            {
+                delta += weight;
+            }
+        }
+        let n = SAMPLES as f32;
+        for (method, tr, tolerance) in
+            &[("ratio", ratio / n, 0.01), ("delta", delta / n, 0.02)]
+        {
+            assert!(
+                glm::comp_max(&(tr - expected).abs()) < *tolerance,
+                "{} tracking through the {}: {:?} instead of {:?}",
+                method,
+                name,
+                tr,
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use nalgebra_glm::Vec3;
+
+use crate::geom::*;
+use crate::spectrum::*;
+use crate::trace::*;
+
+// Only the light arriving directly from the light source at the first
+// surface the camera sees, plus the background where it sees nothing. Shows
+// what the scene looks like without any indirect light. Delta surfaces like
+// mirrors and glass can't be lit this way, so they're black.
+pub struct DirectLighting;
+
+impl Integrator for DirectLighting {
+    fn name(&self) -> &'static str {
+        "direct"
+    }
+
+    fn radiance(
+        &self,
+        mut path: Path,
+        scene: &[Sphere],
+        _: Depths,
+        _: &View,
+    ) -> Vec3 {
+        match closest_hit(&path.ray, scene, path.rng) {
+            Some(mut hit) => {
+                hit.wavelength = path.wavelengths.map(|ls| ls.x);
+                let wo = -path.ray.dir;
+                let hit_pos = path.ray.origin + hit.t * path.ray.dir;
+                direct_light(&hit, hit_pos, wo, &mut path, scene)
+            }
+            None => upsample(background_color(), path.wavelengths),
+        }
+    }
+}
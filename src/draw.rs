@@ -160,11 +160,7 @@ impl TracerProgram {
     ) -> impl FnOnce(&Pipeline, &mut ShadingGate<GlutinSurface>, RenderState) + 'a
     {
         let tess = fullscreen_quad(surface);
-        let [sw, sh] = surface.size();
-        let dims = [
-            sw / tracer.subsampling() as u32,
-            sh / tracer.subsampling() as u32,
-        ];
+        let dims = tracer.frame_dims(surface.size());
         let pixels = tracer.trace_frame(cam, dims, scene);
         let n_mipmaps = 0;
         let sampler = texture::Sampler {
@@ -1,15 +1,29 @@
-use nalgebra_glm::{vec3, Vec3};
+use nalgebra_glm::{vec2, vec3, Vec2, Vec3};
 use noise::{NoiseFn, Perlin};
+use rand::prelude::*;
+use std::f32::consts::{FRAC_1_PI, PI};
 use std::time;
 
+use crate::bump::*;
+use crate::conductor::*;
+use crate::cutout::*;
 use crate::intersect::*;
+use crate::layered::*;
 use crate::material::*;
+use crate::matlib::*;
+use crate::medium::*;
+use crate::principled::*;
+use crate::procedural::*;
+use crate::spectrum::*;
+use crate::subsurface::*;
+use crate::texture::*;
+use crate::thinfilm::*;
 
 const SCENE_SIZE: isize = 6;
 
 pub type Scene = Vec<Sphere>;
 
-pub fn scene_0(t0: time::Instant) -> Scene {
+pub fn scene_0(_: &MatLib, t0: time::Instant) -> Scene {
     let a = t0.elapsed().as_secs_f64() * 10.0;
 
     vec![
@@ -26,51 +40,113 @@ pub fn scene_0(t0: time::Instant) -> Scene {
     ]
 }
 
-pub fn scene_1(_: time::Instant) -> Scene {
+pub fn scene_1(lib: &MatLib, _: time::Instant) -> Scene {
     vec![
         Sphere {
             centre: vec3(0.0, -101.0, 0.0),
             radius: 100.0,
-            mat: Mat::diffuse(vec3(0.3, 0.3, 0.3)),
+            mat: Mat::new(Dielectric {
+                color: mix(
+                    vec3(0.3, 0.3, 0.3),
+                    vec3(0.15, 0.15, 0.15),
+                    Checker(Mapping::new(Space::World).scaled(0.5)),
+                ),
+                fresnel: Vec3::zeros().into(),
+                shininess: 0.0.into(),
+            }),
         },
         Sphere {
             centre: vec3(0.0, 2.0, 0.0),
             radius: 3.0,
-            mat: Mat::mirror(),
+            mat: lib.get("chrome"),
         },
         Sphere {
             centre: vec3(8.0, 3.0, 8.0),
             radius: 4.0,
-            mat: Mat {
-                color: Vec3::zeros(),
-                fresnel: Vec3::repeat(1.0),
-                shininess: 1024.0,
-            },
+            mat: lib.get("brushed_chrome"),
         },
         Sphere {
             centre: vec3(-3.0, 0.0, 4.0),
             radius: 2.0,
-            mat: Mat::diffuse(vec3(0.0, 0.0, 1.0)),
+            mat: Mat::new(BumpMapped {
+                base: lib.get("blue"),
+                height: Tex::new(Noise::fbm(
+                    Mapping::new(Space::Object).scaled(2.0),
+                    4,
+                )),
+                scale: 0.1,
+            }),
         },
         Sphere {
             centre: vec3(3.0, 1.0, 4.0),
             radius: 1.8,
-            mat: Mat::diffuse(vec3(0.0, 1.0, 0.0)),
+            mat: lib.get("green"),
+        },
+        // A lattice, seen through to its inside
+        Sphere {
+            centre: vec3(-8.0, 2.0, 2.0),
+            radius: 2.5,
+            mat: Mat::new(Cutout {
+                base: Mat::diffuse(vec3(0.9, 0.6, 0.1)),
+                opacity: Tex::new(Checker(
+                    Mapping::new(Space::Object).scaled(2.0),
+                )),
+            }),
+        },
+        // A sphere that's half there, letting through half the light
+        Sphere {
+            centre: vec3(8.0, 1.0, -2.0),
+            radius: 1.5,
+            mat: Mat::new(Cutout {
+                base: Mat::diffuse(vec3(0.1, 0.3, 0.9)),
+                opacity: 0.5.into(),
+            }),
+        },
+        Sphere {
+            centre: vec3(-7.0, 1.0, -8.0),
+            radius: 2.0,
+            mat: lib.get("bricks"),
         },
         Sphere {
             centre: vec3(-5.0, 6.0, -4.0),
             radius: 2.0,
-            mat: Mat::diffuse(vec3(1.0, 0.0, 0.0)),
+            mat: Mat::new(Dielectric {
+                color: mix(
+                    vec3(1.0, 0.0, 0.0),
+                    vec3(1.0, 0.9, 0.9),
+                    Marble::new(Mapping::new(Space::Object).scaled(2.0), 4.0),
+                ),
+                fresnel: Vec3::repeat(0.04).into(),
+                shininess: 256.0.into(),
+            }),
+        },
+        Sphere {
+            centre: vec3(4.0, 8.0, -8.0),
+            radius: 3.0,
+            mat: lib.get("cloud"),
         },
     ]
 }
 
-pub fn scene_2(t0: time::Instant) -> Scene {
+pub fn scene_2(_: &MatLib, t0: time::Instant) -> Scene {
     let a = t0.elapsed().as_secs_f64() / 1.0;
     let p = Perlin::new();
+    // Blotches flowing up over the balls
+    let blotches = Mat::new(Lambertian {
+        color: mix(
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.4, 0.0, 0.1),
+            Noise::perlin(
+                Mapping::new(Space::Object)
+                    .scaled(4.0)
+                    .offset(vec3(0.0, -a as f32, 0.0)),
+            ),
+        ),
+    });
     let mut scene = (-SCENE_SIZE..SCENE_SIZE)
         .flat_map(|x| {
             let x = x as f32;
+            let blotches = blotches.clone();
             (-SCENE_SIZE..SCENE_SIZE).map(move |z| {
                 let z = z as f32;
                 let y = (x as f64 + a).sin() as f32
@@ -78,32 +154,300 @@ pub fn scene_2(t0: time::Instant) -> Scene {
                 Sphere {
                     centre: vec3(x, y, z),
                     radius: 0.4,
-                    mat: Mat::diffuse(vec3(1.0, 0.0, 0.0)),
+                    mat: blotches.clone(),
                 }
             })
         })
         .collect::<Vec<_>>();
+    // Cobblestones, each of its own shade, bulging up from the cracks
+    let cells = Mapping::new(Space::World).scaled(1.5);
     scene.push(Sphere {
         centre: vec3(0.0, -101.0, 0.0),
         radius: 100.0,
-        mat: Mat::diffuse(vec3(0.3, 0.3, 0.3)),
+        mat: Mat::new(BumpMapped {
+            base: Mat::new(Lambertian {
+                color: mix(
+                    vec3(0.2, 0.2, 0.2),
+                    vec3(0.4, 0.35, 0.3),
+                    Noise::worley(cells),
+                ),
+            }),
+            height: Tex::new(Noise::worley_distance(cells)),
+            scale: -0.05,
+        }),
     });
     scene
 }
 
-pub fn closest_hit(ray: &Ray, scene: &[Sphere]) -> Option<Hit> {
-    let basic_ray = BasicRay {
-        origin: ray.origin,
-        dir: ray.dir,
+// A row of the `Principled` presets, with layered and subsurface materials
+// behind them
+pub fn scene_3(_: &MatLib, _: time::Instant) -> Scene {
+    let presets = vec![
+        Principled::plastic(vec3(0.8, 0.1, 0.1), 0.3),
+        Principled::metal(vec3(1.0, 0.78, 0.34), 0.25),
+        Principled::glass(Vec3::repeat(1.0), 0.02),
+        Principled::car_paint(vec3(0.05, 0.1, 0.5)),
+        Principled::cloth(vec3(0.4, 0.1, 0.4)),
+        Principled::wax(vec3(0.9, 0.6, 0.5)),
+    ];
+    let n = presets.len();
+    let mut scene = presets
+        .into_iter()
+        .enumerate()
+        .map(|(i, mat)| Sphere {
+            centre: vec3(3.0 * (i as f32 - (n - 1) as f32 / 2.0), 0.2, 0.0),
+            radius: 1.2,
+            mat: Mat::new(mat),
+        })
+        .collect::<Vec<_>>();
+    // Layered materials in a row behind the presets
+    scene.push(Sphere {
+        centre: vec3(-1.5, 0.2, -4.0),
+        radius: 1.2,
+        mat: Mat::new(Layered {
+            coat: Coat {
+                roughness: 0.05.into(),
+                ior: 1.5.into(),
+                film: None,
+            },
+            base: Mat::new(Principled::metal(vec3(0.95, 0.64, 0.54), 0.5)),
+            thickness: 0.0,
+            absorption: Vec3::zeros().into(),
+        }),
+    });
+    scene.push(Sphere {
+        centre: vec3(1.5, 0.2, -4.0),
+        radius: 1.2,
+        mat: Mat::new(Layered {
+            coat: Coat {
+                roughness: 0.1.into(),
+                ior: 1.5.into(),
+                film: None,
+            },
+            base: Mat::new(Lambertian {
+                color: mix(
+                    vec3(0.6, 0.4, 0.2),
+                    vec3(0.3, 0.15, 0.05),
+                    Wood::new(Mapping::new(Space::Object).scaled(4.0), 0.3),
+                ),
+            }),
+            thickness: 0.05,
+            absorption: vec3(0.5, 1.0, 3.0).into(),
+        }),
+    });
+    // Subsurface scattering, skin-ish and jade-ish
+    scene.push(Sphere {
+        centre: vec3(-4.5, 0.2, -4.0),
+        radius: 1.2,
+        mat: Mat::new(Subsurface {
+            surface: Coat {
+                roughness: 0.3.into(),
+                ior: 1.4.into(),
+                film: None,
+            },
+            albedo: vec3(0.85, 0.6, 0.5),
+            mfp: vec3(0.4, 0.15, 0.08),
+        }),
+    });
+    scene.push(Sphere {
+        centre: vec3(4.5, 0.2, -4.0),
+        radius: 1.2,
+        mat: Mat::new(Subsurface {
+            surface: Coat {
+                roughness: 0.05.into(),
+                ior: 1.6.into(),
+                film: None,
+            },
+            albedo: vec3(0.4, 0.8, 0.5),
+            mfp: vec3(0.3, 0.6, 0.4),
+        }),
+    });
+    // Velvet at the end of the back row
+    scene.push(Sphere {
+        centre: vec3(7.5, 0.2, -4.0),
+        radius: 1.2,
+        mat: Mat::cloth(vec3(0.3, 0.02, 0.05), vec3(1.0, 0.6, 0.7), 0.3),
+    });
+    // A lens of dense flint glass in front, which shows chromatic aberration
+    // in spectral mode
+    scene.push(Sphere {
+        centre: vec3(0.0, -0.2, 3.5),
+        radius: 0.8,
+        mat: Mat::new(Coat {
+            roughness: 0.0.into(),
+            ior: Ior::dense_flint(),
+            film: None,
+        }),
+    });
+    // Colored glass, and a glass shell filled with colored liquid
+    scene.push(Sphere {
+        centre: vec3(3.0, -0.2, 3.5),
+        radius: 0.8,
+        mat: Mat::new(Absorbing {
+            base: Mat::new(Coat {
+                roughness: 0.0.into(),
+                ior: 1.5.into(),
+                film: None,
+            }),
+            absorption: vec3(1.5, 0.2, 1.0),
+        }),
+    });
+    scene.push(Sphere {
+        centre: vec3(-3.0, -0.2, 3.5),
+        radius: 0.8,
+        mat: Mat::new(Coat {
+            roughness: 0.0.into(),
+            ior: 1.5.into(),
+            film: None,
+        }),
+    });
+    scene.push(Sphere {
+        centre: vec3(-3.0, -0.2, 3.5),
+        radius: 0.7,
+        mat: Mat::new(Absorbing {
+            base: Mat::new(Coat {
+                roughness: 0.0.into(),
+                ior: 1.33.into(),
+                film: None,
+            }),
+            absorption: vec3(0.1, 0.8, 2.5),
+        }),
+    });
+    scene.push(Sphere {
+        centre: vec3(0.0, -101.0, 0.0),
+        radius: 100.0,
+        mat: Mat::new(Dielectric {
+            color: mix(
+                vec3(0.3, 0.3, 0.3),
+                vec3(0.15, 0.15, 0.15),
+                Checker(Mapping::new(Space::World).scaled(0.5)),
+            ),
+            fresnel: Vec3::zeros().into(),
+            shininess: 0.0.into(),
+        }),
+    });
+    scene
+}
+
+// Thin-film interference. Best viewed in spectral mode.
+pub fn scene_4(_: &MatLib, _: time::Instant) -> Scene {
+    // Film thickness in nm, varying smoothly over the surface
+    let swirls = |mapping, lo: f32, hi: f32| -> Tex<f32> {
+        Tex::new(Remap {
+            tex: Tex::new(Noise::fbm(mapping, 3)),
+            scale: hi - lo,
+            offset: lo,
+        })
     };
+    vec![
+        // A soap bubble. Air on both sides, but the IOR of the interface
+        // must be a bit above 1, as transmission is singular at exactly 1.
+        Sphere {
+            centre: vec3(0.0, 0.8, 0.0),
+            radius: 1.8,
+            mat: Mat::new(Coat {
+                roughness: 0.0.into(),
+                ior: 1.0001.into(),
+                film: Some(ThinFilm {
+                    thickness: swirls(
+                        Mapping::new(Space::Object).scaled(1.5),
+                        200.0,
+                        800.0,
+                    ),
+                    ior: 1.33,
+                }),
+            }),
+        },
+        Sphere {
+            centre: vec3(-4.0, 0.2, -1.0),
+            radius: 1.2,
+            mat: Mat::new(Conductor::gold(0.2)),
+        },
+        // Silver tinted by a layer of oxide
+        Sphere {
+            centre: vec3(4.0, 0.2, -1.0),
+            radius: 1.2,
+            mat: Mat::new(Conductor {
+                film: Some(ThinFilm {
+                    thickness: swirls(
+                        Mapping::new(Space::Object),
+                        150.0,
+                        450.0,
+                    ),
+                    ior: 2.4,
+                }),
+                ..Conductor::silver(0.15)
+            }),
+        },
+        // Oil on wet asphalt
+        Sphere {
+            centre: vec3(0.0, -101.0, 0.0),
+            radius: 100.0,
+            mat: Mat::new(Conductor {
+                roughness: 0.05.into(),
+                eta: Vec3::repeat(1.6),
+                k: Vec3::repeat(0.6),
+                film: Some(ThinFilm {
+                    thickness: swirls(
+                        Mapping::new(Space::World).scaled(0.3),
+                        300.0,
+                        1000.0,
+                    ),
+                    ior: 1.47,
+                }),
+            }),
+        },
+    ]
+}
+
+pub fn closest_hit(
+    ray: &Ray,
+    scene: &[Sphere],
+    rng: &mut dyn RngCore,
+) -> Option<Hit> {
     scene
         .iter()
-        .flat_map(|obj| obj.intersect(&basic_ray))
+        .enumerate()
+        .flat_map(|(i, obj)| {
+            opaque_hit(obj, ray, rng).map(|hit| Hit { object: i, ..hit })
+        })
         .min_by(|h1, h2| h1.t.partial_cmp(&h2.t).expect("sorting hits"))
+        .map(|mut hit| {
+            hit.normal = hit.mat.shading_normal(&hit);
+            hit
+        })
+}
+
+// Whether anything blocks `ray` before the distance `t_max`
+pub fn occluded(
+    ray: &Ray,
+    t_max: f32,
+    scene: &[Sphere],
+    rng: &mut dyn RngCore,
+) -> bool {
+    scene
+        .iter()
+        .any(|obj| opaque_hit(obj, ray, rng).map_or(false, |hit| hit.t < t_max))
 }
 
-pub fn any_hit(ray: &BasicRay, scene: &[Sphere]) -> Option<Hit> {
-    scene.iter().flat_map(|obj| obj.intersect(ray)).next()
+// The first hit with `obj` that isn't cut out, passing through the surface
+// where it is.
+fn opaque_hit(obj: &Sphere, ray: &Ray, rng: &mut dyn RngCore) -> Option<Hit> {
+    let mut t_passed = 0.0;
+    let mut ray = Ray {
+        origin: ray.origin,
+        dir: ray.dir,
+    };
+    loop {
+        let hit = obj.intersect(&ray)?;
+        let t = t_passed + hit.t;
+        let opacity = hit.mat.opacity(&hit);
+        if opacity >= 1.0 || opacity > 0.0 && rng.gen::<f32>() < opacity {
+            return Some(Hit { t, ..hit });
+        }
+        t_passed = t + RAY_EPSILON;
+        ray.origin = hit.pos + RAY_EPSILON * ray.dir;
+    }
 }
 
 pub struct Sphere {
@@ -113,7 +457,12 @@ pub struct Sphere {
 }
 
 impl Sphere {
-    pub fn intersect(&self, ray: &BasicRay) -> Option<Hit> {
+    // The centre and radius, to tell whether the sphere has moved
+    pub fn placement(&self) -> (Vec3, f32) {
+        (self.centre, self.radius)
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<Hit> {
         let oc = ray.origin - self.centre;
         let a = ray.dir.dot(&ray.dir);
         let b = 2.0 * oc.dot(&ray.dir);
@@ -126,19 +475,55 @@ impl Sphere {
             // Negative root here means it's behind us.
             let root0 = -b - sdiscriminant;
             let root1 = -b + sdiscriminant;
-            let mr = match (root0 <= root1, root0 >= 0.0, root1 >= 0.0) {
-                (true, true, _) => Some(root0),
-                (false, _, true) => Some(root1),
+            let mr = match (root0 >= 0.0, root1 >= 0.0) {
+                (true, _) => Some(root0),
+                // Inside the sphere
+                (false, true) => Some(root1),
                 _ => None,
             };
             mr.map(|r| {
                 let t = r / (2.0 * a);
+                let local_pos = oc + t * ray.dir;
+                let normal = local_pos / self.radius;
+                let (dpdu, dpdv) = sphere_dpduv(local_pos);
                 Hit {
                     t,
-                    normal: (oc + t * ray.dir) / self.radius,
+                    normal,
+                    geo_normal: normal,
+                    pos: ray.origin + t * ray.dir,
+                    local_pos,
+                    uv: sphere_uv(normal),
+                    dpdu,
+                    dpdv,
+                    wavelength: None,
+                    // Set by `closest_hit`, which knows about the scene
+                    object: 0,
                     mat: self.mat.clone(),
                 }
             })
         }
     }
 }
+
+// Spherical (equirectangular) mapping of a point on the unit sphere. `u` goes
+// around the y-axis, and `v` goes from the top pole to the bottom.
+fn sphere_uv(p: Vec3) -> Vec2 {
+    let u = 0.5 + p.x.atan2(p.z) / (2.0 * PI);
+    let v = 0.5 - p.y.max(-1.0).min(1.0).asin() * FRAC_1_PI;
+    vec2(u, v)
+}
+
+// Partial derivatives of a point on a sphere w.r.t. the coordinates of
+// `sphere_uv`, given the point relative to the centre.
+fn sphere_dpduv(p: Vec3) -> (Vec3, Vec3) {
+    let rho = (p.x * p.x + p.z * p.z).sqrt();
+    let dpdu = 2.0 * PI * vec3(p.z, 0.0, -p.x);
+    // At the poles, `u` is degenerate and any direction in the tangent plane
+    // will do.
+    let dpdv = if rho > 0.0 {
+        PI * vec3(p.y * p.x / rho, -rho, p.y * p.z / rho)
+    } else {
+        vec3(0.0, 0.0, PI * p.y)
+    };
+    (dpdu, dpdv)
+}
@@ -9,6 +9,16 @@ pub struct Gui {
     fps_t: time::Instant,
     fps_n: u16,
     fps: f32,
+    // Settings of the tracer to show
+    pub integrator: &'static str,
+    pub radiance_cache: bool,
+    pub min_depth: u8,
+    pub max_depth: u8,
+    pub photon_count: u32,
+    pub gather_radius: f32,
+    pub large_step: f32,
+    pub fog: bool,
+    pub fog_density: f32,
     pub emigui: Emigui,
     pub dims: [f32; 2],
 }
@@ -19,6 +29,15 @@ impl Gui {
             fps_t: time::Instant::now(),
             fps_n: 0,
             fps: 42.0,
+            integrator: "",
+            radiance_cache: false,
+            min_depth: 0,
+            max_depth: 0,
+            photon_count: 0,
+            gather_radius: 0.0,
+            large_step: 0.0,
+            fog: false,
+            fog_density: 0.0,
             emigui: Emigui::new(GUI_SCALE),
             dims: [0.0, 0.0],
         }
@@ -41,5 +60,29 @@ impl Gui {
         self.emigui.new_frame(raw_input);
         let mut region = self.emigui.whole_screen_region();
         region.add(emigui::label!("FPS: {:.2}", self.fps));
+        region.add(emigui::label!("Integrator: {} (I)", self.integrator));
+        region.add(emigui::label!(
+            "Radiance cache: {} (C)",
+            if self.radiance_cache { "on" } else { "off" }
+        ));
+        region.add(emigui::label!(
+            "Depth: min {} (9/0), max {} (-/=)",
+            self.min_depth,
+            self.max_depth
+        ));
+        region.add(emigui::label!(
+            "Photons: {} (J/K), radius {:.3} (U/O)",
+            self.photon_count,
+            self.gather_radius
+        ));
+        region.add(emigui::label!(
+            "Metropolis large steps: {:.2} (G/H)",
+            self.large_step
+        ));
+        region.add(emigui::label!(
+            "Fog: {} (F), density {:.3} (B/N)",
+            if self.fog { "on" } else { "off" },
+            self.fog_density
+        ));
     }
 }
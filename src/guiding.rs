@@ -0,0 +1,615 @@
+use nalgebra_glm as glm;
+use nalgebra_glm::{vec2, vec3, Vec2, Vec3};
+use rand::prelude::*;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::geom::*;
+use crate::intersect::*;
+use crate::material::*;
+use crate::spectrum::*;
+use crate::trace::*;
+
+// The probability of sampling a direction from the learned distribution
+// instead of the BSDF, where there is one to sample from
+const GUIDE_FRACTION: f32 = 0.5;
+// A region of space is split in two once it has gathered this many samples
+// in an iteration of one frame, scaled by the square root of the number of
+// frames in the iteration
+const SPLIT_SAMPLES: f32 = 4_000.0;
+// A quadrant of a directional distribution is subdivided when it holds more
+// than this fraction of the light arriving at its region
+const SUBDIVIDE_FRACTION: f32 = 0.01;
+const MAX_QUAD_DEPTH: u32 = 20;
+
+// A path tracer that learns where light comes from as it goes, after Müller
+// et al.'s "Practical Path Guiding for Efficient Light-Transport Simulation"
+// (2017). The light arriving at every vertex of the paths is recorded in an
+// "SD-tree": a binary tree splitting the scene into regions, with a quadtree
+// in every region over the directions light arrives from. The quadtrees are
+// subdivided where more light arrives, so directions can be sampled in
+// proportion to it.
+//
+// The learning happens in iterations of 1, 2, 4, 8, ... accumulated frames.
+// The distributions recorded in one iteration are sampled from in the next,
+// while new ones are recorded, and the tree is refined where the most samples
+// landed. Half of the directions are sampled from the BSDF and half from the
+// learned distribution, weighted by the density of both. Where light arrives
+// mostly indirectly, through a few bright paths, the image converges much
+// faster than with the path tracer, to the same result. The learning starts
+// over whenever the accumulation does.
+pub struct GuidedPathTracer {
+    tree: SdTree,
+}
+
+// A binary tree over the space of the scene, split at the middle along the
+// axes in turn
+struct SdTree {
+    // A cube around the scene
+    min: Vec3,
+    size: f32,
+    nodes: Vec<SpatialNode>,
+    regions: Vec<Region>,
+}
+
+#[derive(Clone, Copy)]
+enum SpatialNode {
+    Inner([usize; 2]),
+    Leaf(usize),
+}
+
+struct Region {
+    // Learned in the previous iteration, to sample from
+    sampling: DTree,
+    // Being learned in this iteration
+    recording: DTree,
+    // The number of samples recorded in this iteration
+    samples: AtomicU32,
+}
+
+// A distribution of directions, as a quadtree over the square `[0, 1)²`. A
+// direction maps to `x = (cos θ + 1) / 2` and `y = φ / 2π`, which preserves
+// areas, so the density over directions is the one over the square over 4π.
+struct DTree {
+    nodes: Vec<QuadNode>,
+}
+
+struct QuadNode {
+    // The light recorded in each quadrant, as atomic floats
+    sums: [AtomicU32; 4],
+    // The index of the node subdividing each quadrant, or 0 for none
+    children: [u32; 4],
+}
+
+// The light arriving at a vertex of a path along the direction sampled there,
+// known once the path is done
+struct Record<'t> {
+    region: &'t Region,
+    wi: Vec3,
+    // The density `wi` was sampled with
+    pdf: f32,
+    // The path's throughput and radiance after the vertex
+    throughput: Vec3,
+    radiance: Vec3,
+}
+
+struct GuidedSample {
+    wi: Vec3,
+    // `f * cos / pdf`, as for a BSDF sample
+    weight: Vec3,
+    lobe: Lobes,
+    // The density of the mixture of both distributions, or `None` for
+    // discrete directions, which can't be guided
+    pdf: Option<f32>,
+}
+
+impl GuidedPathTracer {
+    pub fn new() -> Self {
+        GuidedPathTracer {
+            tree: SdTree::new(&[]),
+        }
+    }
+}
+
+impl Integrator for GuidedPathTracer {
+    fn name(&self) -> &'static str {
+        "guided"
+    }
+
+    fn prepare(
+        &mut self,
+        scene: &[Sphere],
+        view: &View,
+        _: Depths,
+        _: Photons,
+        _: Mutations,
+        _: bool,
+    ) {
+        let frames = view.frame + 1;
+        if view.frame == 0 {
+            self.tree = SdTree::new(scene)
+        } else if frames.is_power_of_two() {
+            // The iteration that just ended took half of the frames so far
+            let iteration = (frames / 2) as f32;
+            self.tree.refine(SPLIT_SAMPLES * iteration.sqrt())
+        }
+    }
+
+    fn radiance(
+        &self,
+        mut path: Path,
+        scene: &[Sphere],
+        depths: Depths,
+        _: &View,
+    ) -> Vec3 {
+        let mut records = vec![];
+        loop {
+            let mut hit = closest_hit(&path.ray, scene, path.rng);
+            if !walk_medium(&mut path, &mut hit, scene, depths) {
+                break;
+            }
+            let mut hit = match hit {
+                Some(hit) => hit,
+                None => {
+                    let background =
+                        upsample(background_color(), path.wavelengths);
+                    path.radiance += background.component_mul(&path.throughput);
+                    break;
+                }
+            };
+            hit.wavelength = path.wavelengths.map(|ls| ls.x);
+            let wo = -path.ray.dir;
+            let hit_pos = path.ray.origin + hit.t * path.ray.dir;
+            let mut radiance =
+                direct_light(&hit, hit_pos, wo, &mut path, scene);
+            let region = self.tree.region(hit_pos);
+            let guide = if hit.mat.lobes().is_delta() {
+                None
+            } else {
+                Some(&region.sampling).filter(|d| d.trained())
+            };
+            let sample = sample(&hit, wo, guide, path.rng);
+            let mut throughput = match &sample {
+                Some(s) if !leaks(&hit, s.wi, wo) => path
+                    .throughput
+                    .component_mul(&upsample(s.weight, path.wavelengths)),
+                _ => Vec3::zeros(),
+            };
+            // The secondary wavelengths would have scattered in other
+            // directions than the hero. Keep only the hero, and compensate
+            // for the others.
+            let hero_only = path.hero_only
+                || (path.wavelengths.is_some() && hit.mat.dispersive());
+            if hero_only && !path.hero_only {
+                radiance = keep_hero(radiance);
+                throughput = keep_hero(throughput);
+            }
+            path.radiance += radiance.component_mul(&path.throughput);
+            let sample = match sample {
+                Some(sample) => sample,
+                None => break,
+            };
+            if sample.lobe.contains(Lobes::TRANSMISSION) {
+                cross_surface(
+                    &mut path.media,
+                    &hit,
+                    sample.wi,
+                    path.wavelengths,
+                )
+            }
+            // Russian roulette, as in the path tracer
+            let survival = if path.depth < depths.min {
+                1.0
+            } else {
+                glm::comp_max(&throughput).min(1.0)
+            };
+            if path.depth < depths.max && rand(path.rng) < survival {
+                path.ray = Ray {
+                    origin: hit_pos + RAY_EPSILON * sample.wi,
+                    dir: sample.wi,
+                };
+                path.depth += 1;
+                path.throughput = throughput / survival;
+                path.hero_only = hero_only;
+                if let Some(pdf) = sample.pdf {
+                    records.push(Record {
+                        region,
+                        wi: sample.wi,
+                        pdf,
+                        throughput: path.throughput,
+                        radiance: path.radiance,
+                    })
+                }
+            } else {
+                break;
+            }
+        }
+        // The light gathered after a vertex, without the throughput up to
+        // it, is what arrived there
+        for record in &records {
+            let gathered = path.radiance - record.radiance;
+            let incident = gathered.zip_map(&record.throughput, |l, t| {
+                if t > 0.0 {
+                    l / t
+                } else {
+                    0.0
+                }
+            });
+            let value = glm::comp_add(&incident) / 3.0 / record.pdf;
+            if value.is_finite() && value > 0.0 {
+                record.region.recording.record(record.wi, value)
+            }
+            record.region.samples.fetch_add(1, Ordering::Relaxed);
+        }
+        path.radiance
+    }
+}
+
+// Sample a direction from the BSDF, or from the learned distribution `guide`
+// if there is one
+fn sample(
+    hit: &Hit,
+    wo: Vec3,
+    guide: Option<&DTree>,
+    rng: &mut dyn RngCore,
+) -> Option<GuidedSample> {
+    let guide = match guide {
+        Some(guide) => guide,
+        None => {
+            let sample = hit.mat.sample(rng, wo, hit);
+            if sample.pdf == 0.0 {
+                return None;
+            }
+            let cosineterm = sample.wi.dot(&hit.normal).abs();
+            return Some(GuidedSample {
+                wi: sample.wi,
+                weight: (sample.brdf * cosineterm) / sample.pdf,
+                lobe: sample.lobe,
+                pdf: if sample.lobe.contains(Lobes::DELTA) {
+                    None
+                } else {
+                    Some(sample.pdf)
+                },
+            });
+        }
+    };
+    // The density of either way of sampling a direction, by how often it's
+    // taken. Discrete directions can only come from the BSDF.
+    let mixture = |wi: Vec3| {
+        (1.0 - GUIDE_FRACTION) * hit.mat.pdf(wi, wo, hit)
+            + GUIDE_FRACTION * guide.pdf(wi)
+    };
+    if rand(rng) < GUIDE_FRACTION {
+        let wi = guide.sample(rng)?;
+        let pdf = mixture(wi);
+        if pdf == 0.0 {
+            return None;
+        }
+        let cosineterm = wi.dot(&hit.normal).abs();
+        let through = wi.dot(&hit.geo_normal) * wo.dot(&hit.geo_normal) < 0.0;
+        Some(GuidedSample {
+            wi,
+            weight: hit.mat.eval(wi, wo, hit) * cosineterm / pdf,
+            lobe: if through {
+                Lobes::GLOSSY | Lobes::TRANSMISSION
+            } else {
+                Lobes::GLOSSY
+            },
+            pdf: Some(pdf),
+        })
+    } else {
+        let sample = hit.mat.sample(rng, wo, hit);
+        if sample.pdf == 0.0 {
+            return None;
+        }
+        let cosineterm = sample.wi.dot(&hit.normal).abs();
+        let weight = (sample.brdf * cosineterm) / sample.pdf;
+        if sample.lobe.contains(Lobes::DELTA) {
+            return Some(GuidedSample {
+                wi: sample.wi,
+                weight: weight / (1.0 - GUIDE_FRACTION),
+                lobe: sample.lobe,
+                pdf: None,
+            });
+        }
+        // The sample stands in for the BSDF's share of the mixture, as
+        // weighted by the balance heuristic
+        let pdf = mixture(sample.wi);
+        if pdf == 0.0 {
+            return None;
+        }
+        Some(GuidedSample {
+            wi: sample.wi,
+            weight: weight * hit.mat.pdf(sample.wi, wo, hit) / pdf,
+            lobe: sample.lobe,
+            pdf: Some(pdf),
+        })
+    }
+}
+
+impl SdTree {
+    // A single region around all of `scene`, with nothing learned
+    fn new(scene: &[Sphere]) -> Self {
+        let mut lo = Vec3::repeat(f32::INFINITY);
+        let mut hi = Vec3::repeat(f32::NEG_INFINITY);
+        for sphere in scene {
+            let (centre, radius) = sphere.placement();
+            lo = glm::min2(&lo, &(centre - Vec3::repeat(radius)));
+            hi = glm::max2(&hi, &(centre + Vec3::repeat(radius)));
+        }
+        if scene.is_empty() {
+            lo = Vec3::zeros();
+            hi = Vec3::zeros();
+        }
+        SdTree {
+            min: lo,
+            size: glm::comp_max(&(hi - lo)).max(1e-3),
+            nodes: vec![SpatialNode::Leaf(0)],
+            regions: vec![Region::new(DTree::new())],
+        }
+    }
+
+    // The region `p` is in. Points outside the cube go to the nearest one.
+    fn region(&self, p: Vec3) -> &Region {
+        let mut node = 0;
+        let mut lo = self.min;
+        let mut size = Vec3::repeat(self.size);
+        let mut axis = 0;
+        loop {
+            match self.nodes[node] {
+                SpatialNode::Leaf(i) => return &self.regions[i],
+                SpatialNode::Inner(children) => {
+                    size[axis] /= 2.0;
+                    let upper = p[axis] >= lo[axis] + size[axis];
+                    if upper {
+                        lo[axis] += size[axis]
+                    }
+                    node = children[upper as usize];
+                    axis = (axis + 1) % 3;
+                }
+            }
+        }
+    }
+
+    // End an iteration. Regions with more than `split_samples` samples are
+    // split, and the distributions recorded become the ones to sample.
+    fn refine(&mut self, split_samples: f32) {
+        self.split(0, split_samples);
+        for region in &mut self.regions {
+            let recorded =
+                std::mem::replace(&mut region.recording, DTree::new());
+            region.recording = recorded.refined();
+            region.sampling = recorded;
+            region.samples = AtomicU32::new(0);
+        }
+    }
+
+    fn split(&mut self, node: usize, split_samples: f32) {
+        match self.nodes[node] {
+            SpatialNode::Inner(children) => {
+                for &child in &children {
+                    self.split(child, split_samples)
+                }
+            }
+            SpatialNode::Leaf(i) => {
+                let samples = self.regions[i].samples.load(Ordering::Relaxed);
+                if samples as f32 <= split_samples {
+                    return;
+                }
+                // Each half is assumed to have gotten half of the samples,
+                // and starts out with what the whole learned
+                let half = samples / 2;
+                self.regions[i].samples = AtomicU32::new(half);
+                let mut region = Region::new(self.regions[i].sampling.copy());
+                region.recording = self.regions[i].recording.copy();
+                region.samples = AtomicU32::new(samples - half);
+                self.regions.push(region);
+                let child = self.nodes.len();
+                self.nodes.push(SpatialNode::Leaf(i));
+                self.nodes.push(SpatialNode::Leaf(self.regions.len() - 1));
+                self.nodes[node] = SpatialNode::Inner([child, child + 1]);
+                self.split(child, split_samples);
+                self.split(child + 1, split_samples);
+            }
+        }
+    }
+}
+
+impl Region {
+    fn new(sampling: DTree) -> Self {
+        Region {
+            sampling,
+            recording: DTree::new(),
+            samples: AtomicU32::new(0),
+        }
+    }
+}
+
+impl DTree {
+    fn new() -> Self {
+        DTree {
+            nodes: vec![QuadNode::new([0.0; 4])],
+        }
+    }
+
+    // Atomics can't be cloned
+    fn copy(&self) -> Self {
+        DTree {
+            nodes: self
+                .nodes
+                .iter()
+                .map(|node| QuadNode {
+                    children: node.children,
+                    ..QuadNode::new(node.sums())
+                })
+                .collect(),
+        }
+    }
+
+    // Whether any light has been recorded to sample from
+    fn trained(&self) -> bool {
+        self.nodes[0].total() > 0.0
+    }
+
+    fn record(&self, dir: Vec3, value: f32) {
+        let mut p = dir_to_square(dir);
+        let mut node = &self.nodes[0];
+        loop {
+            let q = quadrant(&mut p);
+            add_f32(&node.sums[q], value);
+            match node.children[q] {
+                0 => return,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+    }
+
+    // Pick a quadrant by the light in it, from the root down, and then a
+    // point uniformly in it. `None` if there's nothing to sample.
+    fn sample(&self, rng: &mut dyn RngCore) -> Option<Vec3> {
+        let mut node = &self.nodes[0];
+        let mut origin = Vec2::zeros();
+        let mut size = 1.0;
+        loop {
+            let total = node.total();
+            if total <= 0.0 {
+                return None;
+            }
+            let mut u = rand(rng) * total;
+            let mut q = 0;
+            while q < 3 && u >= node.sum(q) {
+                u -= node.sum(q);
+                q += 1;
+            }
+            size /= 2.0;
+            origin += size * vec2((q % 2) as f32, (q / 2) as f32);
+            match node.children[q] {
+                0 => break,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+        let p = origin + size * vec2(rand(rng), rand(rng));
+        Some(square_to_dir(p))
+    }
+
+    // The density of sampling `dir`, over solid angle
+    fn pdf(&self, dir: Vec3) -> f32 {
+        let mut p = dir_to_square(dir);
+        let mut node = &self.nodes[0];
+        let mut density = 1.0;
+        loop {
+            let total = node.total();
+            if total <= 0.0 {
+                return 0.0;
+            }
+            let q = quadrant(&mut p);
+            density *= 4.0 * node.sum(q) / total;
+            match node.children[q] {
+                0 => return density / (4.0 * PI),
+                child => node = &self.nodes[child as usize],
+            }
+        }
+    }
+
+    // An empty tree to record into anew, subdivided where this one recorded
+    // much of its light
+    fn refined(&self) -> DTree {
+        let mut tree = DTree::new();
+        let total = self.nodes[0].total();
+        if total > 0.0 {
+            self.build(&mut tree, 0, Some(0), self.nodes[0].sums(), total, 1);
+        }
+        tree
+    }
+
+    fn build(
+        &self,
+        tree: &mut DTree,
+        new: usize,
+        old: Option<usize>,
+        node_sums: [f32; 4],
+        total: f32,
+        depth: u32,
+    ) {
+        for (q, &sum) in node_sums.iter().enumerate() {
+            if sum / total <= SUBDIVIDE_FRACTION || depth >= MAX_QUAD_DEPTH {
+                continue;
+            }
+            let child = tree.nodes.len();
+            tree.nodes.push(QuadNode::new([0.0; 4]));
+            tree.nodes[new].children[q] = child as u32;
+            let old_child = old
+                .map(|o| self.nodes[o].children[q] as usize)
+                .filter(|&c| c != 0);
+            // Where the old tree didn't go as deep, the light is assumed to
+            // be spread evenly
+            let child_sums = match old_child {
+                Some(c) => self.nodes[c].sums(),
+                None => [sum / 4.0; 4],
+            };
+            self.build(tree, child, old_child, child_sums, total, depth + 1);
+        }
+    }
+}
+
+impl QuadNode {
+    fn new(sums: [f32; 4]) -> Self {
+        let atomic = |q: usize| AtomicU32::new(sums[q].to_bits());
+        QuadNode {
+            sums: [atomic(0), atomic(1), atomic(2), atomic(3)],
+            children: [0; 4],
+        }
+    }
+
+    fn sum(&self, q: usize) -> f32 {
+        f32::from_bits(self.sums[q].load(Ordering::Relaxed))
+    }
+
+    fn sums(&self) -> [f32; 4] {
+        [self.sum(0), self.sum(1), self.sum(2), self.sum(3)]
+    }
+
+    fn total(&self) -> f32 {
+        (0..4).map(|q| self.sum(q)).sum()
+    }
+}
+
+// The quadrant of the unit square `p` is in, with `p` scaled to where it is
+// within the quadrant
+fn quadrant(p: &mut Vec2) -> usize {
+    let (right, up) = (p.x >= 0.5, p.y >= 0.5);
+    p.x = 2.0 * p.x - right as u32 as f32;
+    p.y = 2.0 * p.y - up as u32 as f32;
+    right as usize + 2 * up as usize
+}
+
+fn dir_to_square(dir: Vec3) -> Vec2 {
+    let below_one = 1.0 - f32::EPSILON;
+    let x = ((dir.z + 1.0) / 2.0).max(0.0).min(below_one);
+    let y = dir.y.atan2(dir.x) / (2.0 * PI);
+    let y = if y < 0.0 { y + 1.0 } else { y };
+    vec2(x, y.max(0.0).min(below_one))
+}
+
+fn square_to_dir(p: Vec2) -> Vec3 {
+    let cos_theta = 2.0 * p.x - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * p.y;
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+fn add_f32(a: &AtomicU32, v: f32) {
+    let mut old = a.load(Ordering::Relaxed);
+    loop {
+        let new = (f32::from_bits(old) + v).to_bits();
+        match a.compare_exchange_weak(
+            old,
+            new,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return,
+            Err(current) => old = current,
+        }
+    }
+}
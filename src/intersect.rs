@@ -1,23 +1,37 @@
-use nalgebra_glm::Vec3;
-use rand::prelude::*;
+use nalgebra_glm::{Vec2, Vec3};
 
 use crate::material::*;
 
-pub struct Ray<'r> {
-    pub origin: Vec3,
-    pub dir: Vec3,
-    pub bounces: u8,
-    pub throughput: Vec3,
-    pub rng: &'r mut SmallRng,
-}
+// Offset of rays leaving a surface, so that they don't hit it again due to
+// rounding errors
+pub const RAY_EPSILON: f32 = 0.0001;
 
-pub struct BasicRay {
+pub struct Ray {
     pub origin: Vec3,
     pub dir: Vec3,
 }
 
+#[derive(Clone)]
 pub struct Hit {
     pub t: f32,
+    // The shading normal, which is the one materials should use. May differ
+    // from the geometric normal due to e.g. normal mapping.
     pub normal: Vec3,
+    // The actual normal of the surface
+    pub geo_normal: Vec3,
+    // The hit point in world space
+    pub pos: Vec3,
+    // The hit point relative to the object that was hit
+    pub local_pos: Vec3,
+    // Texture coordinates
+    pub uv: Vec2,
+    // Partial derivatives of the hit position w.r.t. the texture coordinates.
+    // Together with the normal these form the tangent space.
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    // The hero wavelength of the ray that hit, in spectral mode
+    pub wavelength: Option<f32>,
+    // Index of the object that was hit in the scene
+    pub object: usize,
     pub mat: Mat,
 }
@@ -0,0 +1,67 @@
+use nalgebra_glm::Vec3;
+use std::cmp::Ordering;
+
+// A balanced kd-tree of items at points in space, for finding all items
+// near some point. The tree is implicit in the order of the items: the
+// median of a range along the splitting axis is in the middle of it, with
+// the items before it on the one side of the splitting plane and the items
+// after it on the other.
+pub struct KdTree<T> {
+    items: Vec<(Vec3, T)>,
+}
+
+impl<T> KdTree<T> {
+    pub fn new(mut items: Vec<(Vec3, T)>) -> Self {
+        build(&mut items, 0);
+        KdTree { items }
+    }
+
+    // Call `f` for every item within the distance `radius` of `p`
+    pub fn within<F>(&self, p: Vec3, radius: f32, mut f: F)
+    where
+        F: FnMut(Vec3, &T),
+    {
+        within(&self.items, 0, p, radius, &mut f)
+    }
+}
+
+// The splitting axis cycles through x, y and z with the depth of the tree
+fn build<T>(items: &mut [(Vec3, T)], axis: usize) {
+    if items.len() <= 1 {
+        return;
+    }
+    items.sort_unstable_by(|(a, _), (b, _)| {
+        a[axis].partial_cmp(&b[axis]).unwrap_or(Ordering::Equal)
+    });
+    let mid = items.len() / 2;
+    let (below, above) = items.split_at_mut(mid);
+    build(below, (axis + 1) % 3);
+    build(&mut above[1..], (axis + 1) % 3);
+}
+
+fn within<T, F>(
+    items: &[(Vec3, T)],
+    axis: usize,
+    p: Vec3,
+    radius: f32,
+    f: &mut F,
+) where
+    F: FnMut(Vec3, &T),
+{
+    if items.is_empty() {
+        return;
+    }
+    let mid = items.len() / 2;
+    let (pos, item) = &items[mid];
+    if (pos - p).magnitude_squared() <= radius * radius {
+        f(*pos, item)
+    }
+    // Distance from the splitting plane, signed by which side `p` is on
+    let d = p[axis] - pos[axis];
+    if d <= radius {
+        within(&items[..mid], (axis + 1) % 3, p, radius, f)
+    }
+    if d >= -radius {
+        within(&items[mid + 1..], (axis + 1) % 3, p, radius, f)
+    }
+}
@@ -0,0 +1,508 @@
+use nalgebra_glm::{vec3, Vec3};
+use rand::prelude::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::intersect::*;
+use crate::material::*;
+use crate::microfacet::*;
+use crate::spectrum::*;
+use crate::texture::*;
+use crate::thinfilm::*;
+
+// Max number of bounces between the coat and the base of a `Layered`
+const MAX_LAYER_BOUNCES: u8 = 8;
+// Number of pairs of random walks, one in each direction, averaged when
+// evaluating a `Layered`
+const LAYER_EVAL_PAIRS: u8 = 1;
+
+// A clear, possibly rough, dielectric interface. Reflects and refracts, but
+// doesn't absorb anything. On its own it's just the surface of a glass
+// object, but it's mostly useful as the top of a `Layered`.
+#[derive(Clone)]
+pub struct Coat {
+    pub roughness: Tex<f32>,
+    pub ior: Ior,
+    // A thin film on the outside of the interface, like a soap bubble
+    pub film: Option<ThinFilm>,
+}
+
+impl Coat {
+    fn alpha(&self, hit: &Hit) -> f32 {
+        ggx_alpha(self.roughness.at(hit))
+    }
+
+    // Whether the coat is perfectly smooth at `hit`. On its own, it then
+    // scatters in delta lobes, but as the top of a `Layered` it's still just
+    // the narrowest rough interface, as the random walks between the layers
+    // need to evaluate it.
+    fn smooth(&self, hit: &Hit) -> bool {
+        is_smooth(self.roughness.at(hit))
+    }
+
+    fn ior_at(&self, hit: &Hit) -> f32 {
+        self.ior.at(hit.wavelength)
+    }
+
+    fn fresnel(&self, hit: &Hit, cos_i: f32, eta: f32) -> Vec3 {
+        match &self.film {
+            Some(film) => film.dielectric_reflectance(hit, cos_i, eta),
+            None => Vec3::repeat(fresnel_dielectric(cos_i, eta)),
+        }
+    }
+
+    // The BSDF in the local frame of the surface, given the `alpha` and `ior`
+    // at `hit`.
+    fn local_f(
+        &self,
+        hit: &Hit,
+        alpha: f32,
+        ior: f32,
+        wi: Vec3,
+        wo: Vec3,
+    ) -> Vec3 {
+        rough_dielectric_f_with(wi, wo, alpha, ior, |c, eta| {
+            self.fresnel(hit, c, eta)
+        })
+    }
+
+    fn local_pdf(
+        &self,
+        hit: &Hit,
+        alpha: f32,
+        ior: f32,
+        wi: Vec3,
+        wo: Vec3,
+    ) -> f32 {
+        rough_dielectric_pdf_with(wi, wo, alpha, ior, |c, eta| {
+            luminance(self.fresnel(hit, c, eta))
+        })
+    }
+
+    fn local_sample_wi(
+        &self,
+        rng: &mut dyn RngCore,
+        hit: &Hit,
+        alpha: f32,
+        ior: f32,
+        wo: Vec3,
+    ) -> Option<Vec3> {
+        let u = [rand(rng), rand(rng), rand(rng)];
+        rough_dielectric_sample_wi_with(wo, alpha, ior, u, |c, eta| {
+            luminance(self.fresnel(hit, c, eta))
+        })
+    }
+
+    // Reflect or refract exactly, as a perfectly smooth surface does, picking
+    // either by the Fresnel reflectance. Like for `SpecularReflector`, the
+    // delta distribution is represented by dividing the BSDF by the cosine
+    // term, and the pdf is just the probability of the pick.
+    fn sample_smooth(
+        &self,
+        rng: &mut dyn RngCore,
+        hit: &Hit,
+        frame: &Frame,
+        wo: Vec3,
+    ) -> DirSample {
+        let eta = relative_eta(wo, self.ior_at(hit));
+        let r = self.fresnel(hit, cos_theta(wo), eta);
+        let p = luminance(r);
+        let n = vec3(0.0, 0.0, cos_theta(wo).signum());
+        let (wi, f, pdf, lobe) = if rand(rng) < p {
+            (vec3(-wo.x, -wo.y, wo.z), r, p, Lobes::DELTA)
+        } else {
+            match refract(wo, n, eta) {
+                Some(wi) => (
+                    wi,
+                    Vec3::repeat(1.0) - r,
+                    1.0 - p,
+                    Lobes::DELTA | Lobes::TRANSMISSION,
+                ),
+                None => (hit.normal, Vec3::zeros(), 0.0, Lobes::NONE),
+            }
+        };
+        if pdf <= 0.0 || cos_theta(wi) == 0.0 {
+            return DirSample {
+                wi: hit.normal,
+                pdf: 0.0,
+                brdf: Vec3::zeros(),
+                lobe: Lobes::NONE,
+            };
+        }
+        DirSample {
+            wi: frame.to_world(wi),
+            pdf,
+            brdf: f / cos_theta(wi).abs(),
+            lobe,
+        }
+    }
+}
+
+impl Bsdf for Coat {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        if self.smooth(hit) {
+            return Vec3::zeros();
+        }
+        let frame = Frame::new(hit.normal);
+        let (wi, wo) = (frame.to_local(wi), frame.to_local(wo));
+        self.local_f(hit, self.alpha(hit), self.ior_at(hit), wi, wo)
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        let frame = Frame::new(hit.normal);
+        let wo_l = frame.to_local(wo);
+        if self.smooth(hit) {
+            return self.sample_smooth(rng, hit, &frame, wo_l);
+        }
+        let (alpha, ior) = (self.alpha(hit), self.ior_at(hit));
+        match self.local_sample_wi(rng, hit, alpha, ior, wo_l) {
+            Some(wi_l) => DirSample {
+                wi: frame.to_world(wi_l),
+                pdf: self.local_pdf(hit, alpha, ior, wi_l, wo_l),
+                brdf: self.local_f(hit, alpha, ior, wi_l, wo_l),
+                lobe: if cos_theta(wi_l) * cos_theta(wo_l) > 0.0 {
+                    Lobes::GLOSSY
+                } else {
+                    Lobes::GLOSSY | Lobes::TRANSMISSION
+                },
+            },
+            None => DirSample {
+                wi: hit.normal,
+                pdf: 0.0,
+                brdf: Vec3::zeros(),
+                lobe: Lobes::NONE,
+            },
+        }
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        if self.smooth(hit) {
+            return 0.0;
+        }
+        let frame = Frame::new(hit.normal);
+        let (wi, wo) = (frame.to_local(wi), frame.to_local(wo));
+        self.local_pdf(hit, self.alpha(hit), self.ior_at(hit), wi, wo)
+    }
+
+    fn lobes(&self) -> Lobes {
+        ggx_lobes(&self.roughness) | Lobes::TRANSMISSION
+    }
+
+    fn dispersive(&self) -> bool {
+        self.ior.is_dispersive() || self.film.is_some()
+    }
+}
+
+// A `Coat` on top of some other material, with an absorbing medium of some
+// thickness in between. E.g. clearcoat over metal, or varnish over wood.
+// Layered materials can be used as the base, to stack more layers.
+//
+// Light bouncing around between the layers is evaluated stochastically by
+// random walks, like in "Position-Free Monte Carlo Simulation for Arbitrary
+// Layered BSDFs" by Guo et al. (2018). The layers are assumed to be
+// infinitely wide and the whole thing is opaque, i.e. light only ever leaves
+// on the side it came from.
+#[derive(Clone)]
+pub struct Layered {
+    pub coat: Coat,
+    pub base: Mat,
+    pub thickness: f32,
+    // Absorption coefficient of the medium between the layers. Light of a
+    // color with coefficient σ is attenuated by `exp(-σ d)` after traveling a
+    // distance `d`.
+    pub absorption: Tex<Vec3>,
+}
+
+// What a random walk through a `Layered` needs to know about the hit, in the
+// local frame of the surface.
+struct Walk<'h> {
+    hit: &'h Hit,
+    coat: &'h Coat,
+    frame: Frame,
+    alpha: f32,
+    ior: f32,
+    absorption: Vec3,
+    thickness: f32,
+}
+
+impl<'h> Walk<'h> {
+    // Transmittance of the medium when crossing the layer in direction `w`.
+    fn tr(&self, w: Vec3) -> Vec3 {
+        let d = self.thickness / cos_theta(w).abs().max(1e-4);
+        (-self.absorption * d).map(f32::exp)
+    }
+
+    fn coat_f(&self, wi: Vec3, wo: Vec3) -> Vec3 {
+        self.coat.local_f(self.hit, self.alpha, self.ior, wi, wo)
+    }
+
+    // Sample the coat for the out-direction `wo`. Returns the in-direction
+    // along with the weight `f cos / pdf`.
+    fn sample_coat(
+        &self,
+        rng: &mut dyn RngCore,
+        wo: Vec3,
+    ) -> Option<(Vec3, Vec3)> {
+        let (hit, alpha, ior) = (self.hit, self.alpha, self.ior);
+        let wi = self.coat.local_sample_wi(rng, hit, alpha, ior, wo)?;
+        let pdf = self.coat.local_pdf(hit, alpha, ior, wi, wo);
+        if pdf > 0.0 {
+            Some((wi, self.coat_f(wi, wo) * cos_theta(wi).abs() / pdf))
+        } else {
+            None
+        }
+    }
+
+    // The probability that `Layered::sample` samples the reflection off the
+    // coat, rather than the base, for the out-direction `wo`. Roughly the
+    // share of the light that the coat reflects, but always leaving some to
+    // each.
+    fn reflect_prob(&self, wo: Vec3) -> f32 {
+        luminance(self.coat_fresnel(wo)).max(0.1).min(0.9)
+    }
+
+    // The share of the light from `w` that a smooth coat would reflect
+    fn coat_fresnel(&self, w: Vec3) -> Vec3 {
+        self.coat.fresnel(self.hit, cos_theta(w), self.ior)
+    }
+
+    fn sample_coat_reflection(
+        &self,
+        rng: &mut dyn RngCore,
+        wo: Vec3,
+    ) -> Option<Vec3> {
+        let u = [rand(rng), rand(rng), 0.0];
+        rough_dielectric_sample_wi_with(wo, self.alpha, self.ior, u, |_, _| 1.0)
+    }
+
+    fn coat_reflection_pdf(&self, wi: Vec3, wo: Vec3) -> f32 {
+        rough_dielectric_pdf_with(wi, wo, self.alpha, self.ior, |_, _| 1.0)
+    }
+
+    fn eval_base(&self, base: &Mat, wi: Vec3, wo: Vec3) -> Vec3 {
+        base.eval(self.frame.to_world(wi), self.frame.to_world(wo), self.hit)
+    }
+
+    // Sample the base for the local out-direction `wo`. Returns the local
+    // in-direction along with the weight `f cos / pdf`, and the lobe.
+    fn sample_base(
+        &self,
+        base: &Mat,
+        rng: &mut dyn RngCore,
+        wo: Vec3,
+    ) -> Option<(Vec3, Vec3, Lobes)> {
+        let s = base.sample(rng, self.frame.to_world(wo), self.hit);
+        let wi = self.frame.to_local(s.wi);
+        if s.pdf > 0.0 && cos_theta(wi) > 0.0 {
+            Some((wi, s.brdf * cos_theta(wi) / s.pdf, s.lobe))
+        } else {
+            None
+        }
+    }
+}
+
+impl Layered {
+    fn walk<'h>(&'h self, hit: &'h Hit) -> Walk<'h> {
+        Walk {
+            hit,
+            coat: &self.coat,
+            frame: Frame::new(hit.normal),
+            alpha: self.coat.alpha(hit),
+            ior: self.coat.ior_at(hit),
+            absorption: self.absorption.at(hit),
+            thickness: self.thickness,
+        }
+    }
+}
+
+impl Bsdf for Layered {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        let walk = self.walk(hit);
+        let (wi, wo) = (walk.frame.to_local(wi), walk.frame.to_local(wo));
+        if cos_theta(wi) <= 0.0 || cos_theta(wo) <= 0.0 {
+            return Vec3::zeros();
+        }
+        // Evaluation has to be deterministic for a pair of directions, so
+        // seed the walks by the directions themselves. Half of the walks go
+        // each way, which keeps the estimate reciprocal.
+        let mut rng = SmallRng::seed_from_u64(hash_dirs(wi, wo));
+        let mut rng_rev = SmallRng::seed_from_u64(hash_dirs(wo, wi));
+        let mut f = Vec3::zeros();
+        for _ in 0..LAYER_EVAL_PAIRS {
+            f += self.walk_f(&walk, &mut rng, wi, wo)
+                + self.walk_f(&walk, &mut rng_rev, wo, wi);
+        }
+        walk.coat_f(wi, wo) + f / (2 * LAYER_EVAL_PAIRS) as f32
+    }
+
+    // Sample either the reflection off the coat, or the base as seen through
+    // a smooth coat. Both have known densities, which `pdf` mixes, and the
+    // bouncing around between the layers is left to `eval`. Delta lobes of
+    // the base can't be evaluated, so they're sampled with their weight
+    // instead, for the light that goes straight through the coat both ways.
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        let walk = self.walk(hit);
+        let wo_l = walk.frame.to_local(wo);
+        let failed = DirSample {
+            wi: hit.normal,
+            pdf: 0.0,
+            brdf: Vec3::zeros(),
+            lobe: Lobes::NONE,
+        };
+        if cos_theta(wo_l) <= 0.0 {
+            return failed;
+        }
+        let (wi_l, lobe) = if rand(rng) < walk.reflect_prob(wo_l) {
+            match walk.sample_coat_reflection(rng, wo_l) {
+                Some(wi) => (wi, Lobes::GLOSSY),
+                None => return failed,
+            }
+        } else {
+            let wo_in = match refract_up(wo_l, walk.ior) {
+                Some(wo) => wo,
+                None => return failed,
+            };
+            let s = self.base.sample(rng, walk.frame.to_world(wo_in), hit);
+            let wi_in = walk.frame.to_local(s.wi);
+            if s.pdf <= 0.0 || cos_theta(wi_in) <= 0.0 {
+                return failed;
+            }
+            let wi_l = match refract_up(wi_in, 1.0 / walk.ior) {
+                Some(wi) => wi,
+                None => return failed,
+            };
+            if s.lobe.contains(Lobes::DELTA) {
+                let white = Vec3::repeat(1.0);
+                let through = (white - walk.coat_fresnel(wo_l))
+                    .component_mul(&(white - walk.coat_fresnel(wi_l)))
+                    .component_mul(&walk.tr(wo_in))
+                    .component_mul(&walk.tr(wi_in));
+                return DirSample {
+                    wi: walk.frame.to_world(wi_l),
+                    pdf: (1.0 - walk.reflect_prob(wo_l)) * s.pdf,
+                    brdf: through.component_mul(&s.brdf) * cos_theta(wi_in)
+                        / cos_theta(wi_l),
+                    lobe: s.lobe,
+                };
+            }
+            (wi_l, s.lobe)
+        };
+        let wi = walk.frame.to_world(wi_l);
+        DirSample {
+            wi,
+            pdf: self.pdf(wi, wo, hit),
+            brdf: self.eval(wi, wo, hit),
+            lobe,
+        }
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        let walk = self.walk(hit);
+        let (wi, wo) = (walk.frame.to_local(wi), walk.frame.to_local(wo));
+        if cos_theta(wi) <= 0.0 || cos_theta(wo) <= 0.0 {
+            return 0.0;
+        }
+        let r = walk.reflect_prob(wo);
+        // Directions are squeezed together by refraction into the coat, by
+        // the ratio of the solid angles inside and outside. The base can't
+        // be seen at all in directions that are totally internally reflected,
+        // which only happens for coats of an IOR below 1.
+        let base_pdf =
+            match (refract_up(wi, walk.ior), refract_up(wo, walk.ior)) {
+                (Some(wi_in), Some(wo_in)) => {
+                    let squeeze = cos_theta(wi)
+                        / (walk.ior * walk.ior * cos_theta(wi_in));
+                    self.base.pdf(
+                        walk.frame.to_world(wi_in),
+                        walk.frame.to_world(wo_in),
+                        hit,
+                    ) * squeeze
+                }
+                _ => 0.0,
+            };
+        r * walk.coat_reflection_pdf(wi, wo) + (1.0 - r) * base_pdf
+    }
+
+    fn lobes(&self) -> Lobes {
+        Lobes::GLOSSY | self.base.lobes()
+    }
+
+    fn dispersive(&self) -> bool {
+        self.coat.dispersive() || self.base.dispersive()
+    }
+}
+
+impl Layered {
+    // One random walk estimating the light from `wi` that bounces around
+    // between the layers before leaving towards `wo`.
+    fn walk_f(
+        &self,
+        walk: &Walk,
+        rng: &mut dyn RngCore,
+        wi: Vec3,
+        wo: Vec3,
+    ) -> Vec3 {
+        let mut f = Vec3::zeros();
+        // Enter through the coat on the side of `wo`
+        let (mut w, mut beta) = match walk.sample_coat(rng, wo) {
+            Some((w, beta)) if cos_theta(w) < 0.0 => (w, beta),
+            _ => return f,
+        };
+        // Exit through the coat on the side of `wi`. Light actually flows in
+        // the opposite direction to how we sample it here, so scale by η² to
+        // account for the non-symmetric transmission.
+        let (wis, exit) = match walk.sample_coat(rng, wi) {
+            Some((w, beta)) if cos_theta(w) < 0.0 => {
+                (w, beta / cos_theta(w).abs() * walk.ior * walk.ior)
+            }
+            _ => return f,
+        };
+        for _ in 0..MAX_LAYER_BOUNCES {
+            beta = beta.component_mul(&walk.tr(w));
+            // Connect the base to the exit
+            f += beta.component_mul(
+                &(walk.eval_base(&self.base, -wis, -w) * cos_theta(wis).abs())
+                    .component_mul(&exit)
+                    .component_mul(&walk.tr(wis)),
+            );
+            let (w_up, weight, _) = match walk.sample_base(&self.base, rng, -w)
+            {
+                Some(s) => s,
+                None => break,
+            };
+            beta = beta.component_mul(&weight).component_mul(&walk.tr(w_up));
+            // Reflect off the underside of the coat, back down to the base.
+            // If the light instead leaves the layer, it has left in some
+            // other direction than `wi`.
+            match walk.sample_coat(rng, -w_up) {
+                Some((w_down, weight)) if cos_theta(w_down) < 0.0 => {
+                    beta = beta.component_mul(&weight);
+                    w = w_down;
+                }
+                _ => break,
+            }
+        }
+        f
+    }
+}
+
+// Refract the direction `w` pointing up out of a smooth interface, into the
+// side of relative IOR `eta`, where it points up as well. `None` for total
+// internal reflection.
+fn refract_up(w: Vec3, eta: f32) -> Option<Vec3> {
+    let (x, y) = (w.x / eta, w.y / eta);
+    let sin2 = x * x + y * y;
+    if sin2 >= 1.0 {
+        None
+    } else {
+        Some(vec3(x, y, (1.0 - sin2).sqrt()))
+    }
+}
+
+fn hash_dirs(wi: Vec3, wo: Vec3) -> u64 {
+    let mut h = DefaultHasher::new();
+    for c in wi.iter().chain(wo.iter()) {
+        c.to_bits().hash(&mut h);
+    }
+    h.finish()
+}
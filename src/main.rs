@@ -1,10 +1,35 @@
+mod bdpt;
+mod bump;
+mod cache;
 mod cam;
+mod conductor;
+mod cutout;
+mod density;
+mod direct;
 mod draw;
 mod geom;
 mod gui;
+mod guiding;
 mod intersect;
+mod kdtree;
+mod layered;
 mod material;
+mod matlib;
+mod medium;
+mod microfacet;
+mod mlt;
+mod occlusion;
+mod photon;
+mod principled;
+mod procedural;
+mod sheen;
+mod spectrum;
+mod sppm;
+mod subsurface;
+mod texture;
+mod thinfilm;
 mod trace;
+mod whitted;
 
 use {
     cam::*,
@@ -19,14 +44,29 @@ use {
         render_state::RenderState,
     },
     luminance_glutin::GlutinSurface,
+    matlib::{LibError, MatLib},
     nalgebra_glm::{vec2, vec3, Vec2, Vec3},
-    std::{collections::HashSet, time},
+    std::{collections::HashSet, io, time},
     trace::*,
 };
 
 const MOVE_SPEED: f32 = 8.0;
 
+// The library of named materials used by the scenes
+const MATERIALS_PATH: &str = "materials.toml";
+
 fn main() {
+    let lib = match MatLib::load(MATERIALS_PATH) {
+        Ok(lib) => lib,
+        Err(LibError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!("No {}, using only the presets", MATERIALS_PATH);
+            MatLib::presets()
+        }
+        Err(e) => {
+            eprintln!("Error loading {}: {}", MATERIALS_PATH, e);
+            std::process::exit(1)
+        }
+    };
     let mut surface =
         GlutinSurface::from_builders(|wb| wb.with_title("Tracer"), |cb| cb)
             .expect("Glutin surface creation");
@@ -43,7 +83,7 @@ fn main() {
     let mut gui = Gui::new();
     let t0 = time::Instant::now();
     let mut t_prev = time::Instant::now();
-    let scenes = [scene_0, scene_1, scene_2];
+    let scenes = [scene_0, scene_1, scene_2, scene_3, scene_4];
     let mut scene_i = 0;
     let mut cam = Cam::new(vec3(0.0, 4.0, 16.0), Vec3::zeros());
     let mut input_st = InputState::new(&mut surface);
@@ -75,6 +115,8 @@ fn main() {
             tracer.toggle_random_seed()
         } else if input_st.pressed(Key::M) {
             tracer.toggle_reset_on_move()
+        } else if input_st.pressed(Key::L) {
+            tracer.toggle_spectral()
         } else if input_st.pressed(Key::T) {
             tracer.toggle_accum()
         } else if input_st.pressed(Key::LBracket) {
@@ -85,7 +127,46 @@ fn main() {
             tracer.decrease_subsampling_denom()
         } else if input_st.pressed(Key::Period) {
             tracer.increase_subsampling_denom()
+        } else if input_st.pressed(Key::I) {
+            tracer.next_integrator()
+        } else if input_st.pressed(Key::Key9) {
+            tracer.decrease_min_depth()
+        } else if input_st.pressed(Key::Key0) {
+            tracer.increase_min_depth()
+        } else if input_st.pressed(Key::Minus) {
+            tracer.decrease_max_depth()
+        } else if input_st.pressed(Key::Equals) {
+            tracer.increase_max_depth()
+        } else if input_st.pressed(Key::J) {
+            tracer.decrease_photon_count()
+        } else if input_st.pressed(Key::K) {
+            tracer.increase_photon_count()
+        } else if input_st.pressed(Key::U) {
+            tracer.decrease_gather_radius()
+        } else if input_st.pressed(Key::O) {
+            tracer.increase_gather_radius()
+        } else if input_st.pressed(Key::C) {
+            tracer.toggle_radiance_cache()
+        } else if input_st.pressed(Key::G) {
+            tracer.decrease_large_step()
+        } else if input_st.pressed(Key::H) {
+            tracer.increase_large_step()
+        } else if input_st.pressed(Key::F) {
+            tracer.toggle_fog()
+        } else if input_st.pressed(Key::B) {
+            tracer.decrease_fog_density()
+        } else if input_st.pressed(Key::N) {
+            tracer.increase_fog_density()
         }
+        gui.integrator = tracer.integrator_name();
+        gui.radiance_cache = tracer.radiance_cache();
+        gui.min_depth = tracer.min_depth();
+        gui.max_depth = tracer.max_depth();
+        gui.photon_count = tracer.photon_count();
+        gui.gather_radius = tracer.gather_radius();
+        gui.large_step = tracer.large_step();
+        gui.fog = tracer.fog();
+        gui.fog_density = tracer.fog_density();
 
         let move_d = dt * MOVE_SPEED;
         if input_st.held(Key::W) {
@@ -112,7 +193,7 @@ fn main() {
             ERR_COLOR.2,
             1.0,
         ]);
-        let scene = scenes[scene_i](t0);
+        let scene = scenes[scene_i](&lib, t0);
         let tracer_painter =
             tracer_program.draw(&mut surface, &mut tracer, &cam, &scene);
         let gui_painter = gui_program.draw(&mut surface, &mut gui);
@@ -2,82 +2,209 @@ use nalgebra_glm as glm;
 use nalgebra_glm::{vec3, Vec3};
 use rand::prelude::*;
 use std::f32::consts::{FRAC_1_PI, PI};
+use std::ops::{BitOr, Deref};
+use std::sync::Arc;
 
+use crate::intersect::*;
+use crate::medium::*;
+use crate::sheen::*;
+use crate::texture::*;
+
+// A material is a shared handle to some scattering model. Cloning is cheap,
+// which matters as every `Hit` carries its own copy.
 #[derive(Clone)]
-pub struct Mat {
-    pub color: Vec3,
-    pub fresnel: Vec3,
-    pub shininess: f32,
-}
+pub struct Mat(Arc<dyn Bsdf>);
 
 impl Mat {
-    pub fn mirror() -> Self {
-        Self {
-            color: Vec3::repeat(0.0),
-            fresnel: Vec3::repeat(1.0),
-            shininess: 2048.0,
-        }
+    pub fn new<B: Bsdf + 'static>(bsdf: B) -> Self {
+        Mat(Arc::new(bsdf))
     }
 
     pub fn diffuse(color: Vec3) -> Self {
-        Self {
-            color,
-            fresnel: Vec3::zeros(),
-            shininess: 0.0,
-        }
+        Self::new(Lambertian {
+            color: color.into(),
+        })
+    }
+
+    // Just the sheen of cloth, without any base
+    pub fn sheen(color: Vec3, roughness: f32) -> Self {
+        Self::new(Sheen {
+            color: color.into(),
+            roughness: roughness.into(),
+            base: None,
+        })
+    }
+
+    // Fabric, with sheen on top of a diffuse base
+    pub fn cloth(color: Vec3, sheen: Vec3, roughness: f32) -> Self {
+        Self::new(Sheen {
+            color: sheen.into(),
+            roughness: roughness.into(),
+            base: Some(Self::new(Lambertian {
+                color: color.into(),
+            })),
+        })
+    }
+}
+
+impl Deref for Mat {
+    type Target = dyn Bsdf;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+// A bidirectional scattering distribution function. Describes how light
+// arriving from `wi` is scattered towards `wo` at the surface point `hit`.
+// Parameters may vary over the surface, e.g. by being textured.
+//
+// All directions point away from the surface.
+pub trait Bsdf: Send + Sync {
+    // The value of the BSDF for the given pair of directions. Delta lobes
+    // never contribute here, as the probability of hitting them exactly is 0.
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3;
+
+    // Sample an in-direction `wi` for the given out-direction.
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample;
+
+    // The probability (w.r.t. solid angle) that `sample` would have produced
+    // `wi`. Like `eval`, delta lobes are not accounted for.
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32;
+
+    // Which kinds of lobes this BSDF is made up of.
+    fn lobes(&self) -> Lobes;
+
+    // The normal to shade `hit` with, given its geometric normal. Called
+    // once when the hit is found, and the result is stored in `hit.normal`.
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        hit.normal
+    }
+
+    // The medium inside the object, for materials that let light through.
+    // Rays that are transmitted into the object travel through it until they
+    // leave through the surface again.
+    fn interior(&self) -> Option<Medium> {
+        None
+    }
+
+    // Whether the BSDF varies with wavelength in a way not captured by
+    // upsampling its color, e.g. due to a dispersive IOR. In spectral mode,
+    // only the hero wavelength of a path survives scattering off such a
+    // surface, as the other wavelengths would have scattered elsewhere.
+    fn dispersive(&self) -> bool {
+        false
+    }
+
+    // How much of the surface there is at `hit`, from 0 where it's cut out to
+    // 1 where it's solid. See `Cutout`.
+    fn opacity(&self, _hit: &Hit) -> f32 {
+        1.0
+    }
+
+    // Whether the surface merely bounds the medium inside, letting light
+    // through unchanged. Light from the light source reaches the medium
+    // through it, rather than being blocked as by other surfaces.
+    fn invisible(&self) -> bool {
+        false
+    }
+}
+
+// A set of lobe kinds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lobes(u8);
+
+impl Lobes {
+    pub const NONE: Self = Lobes(0);
+    // Scatters evenly-ish over the whole hemisphere.
+    pub const DIFFUSE: Self = Lobes(1);
+    // Scatters around some preferred direction.
+    pub const GLOSSY: Self = Lobes(1 << 1);
+    // Scatters in a single direction, e.g. a perfect mirror.
+    pub const DELTA: Self = Lobes(1 << 2);
+    // Scatters to the other side of the surface.
+    pub const TRANSMISSION: Self = Lobes(1 << 3);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    // Whether all lobes are delta lobes, i.e. the BSDF can't be evaluated
+    // for arbitrary directions at all.
+    pub fn is_delta(self) -> bool {
+        self.contains(Self::DELTA)
+            && !self.intersects(Self::DIFFUSE | Self::GLOSSY)
+    }
+}
+
+impl BitOr for Lobes {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Lobes(self.0 | other.0)
     }
 }
 
-// The result of a `*sample_wi` function. A sampled in-direction for a
-// out-direction and surface.
+// The result of `Bsdf::sample`. A sampled in-direction for a out-direction
+// and surface.
 pub struct DirSample {
     // wi ⇔ ω_i ⇔ Direction vector towards source of incoming light
     pub wi: Vec3,
     // Probability distribution function. The probability of sampling wi.
+    //
+    // For BSDFs made up of several lobes, this may be the probability of
+    // only the chosen lobe (scaled by the probability of choosing it), with
+    // `brdf` being the value of only that lobe. The quotient `brdf / pdf` is
+    // still an unbiased estimate.
     pub pdf: f32,
     // The BRDF for the sampled wi and whichever wo was used to
     // create this sample.
     pub brdf: Vec3,
+    // The kind of lobe that was sampled.
+    pub lobe: Lobes,
 }
 
-pub fn sample_wi(rng: &mut SmallRng, wo: Vec3, n: Vec3, mat: Mat) -> DirSample {
-    Sampler { rng, mat }.dielectric_sample_wi(wo, n)
+pub fn rand(rng: &mut dyn RngCore) -> f32 {
+    rng.gen::<f32>()
 }
 
-pub fn brdf(wi: Vec3, wo: Vec3, n: Vec3, mat: &Mat) -> Vec3 {
-    dielectric_brdf(wi, wo, n, mat)
+// A coating layer with a specular highlight on top of a diffuse base.
+#[derive(Clone)]
+pub struct Dielectric {
+    pub color: Tex<Vec3>,
+    pub fresnel: Tex<Vec3>,
+    pub shininess: Tex<f32>,
 }
 
-fn dielectric_brdf(wi: Vec3, wo: Vec3, n: Vec3, mat: &Mat) -> Vec3 {
-    dielectric_reflection_brdf(wi, wo, n, mat)
-        + dielectric_refraction_brdf(wi, wo, n, mat)
+impl Dielectric {
+    fn at(&self, hit: &Hit) -> Coating {
+        Coating {
+            color: self.color.at(hit),
+            fresnel: self.fresnel.at(hit),
+            shininess: self.shininess.at(hit),
+        }
+    }
 }
 
-struct Sampler<'r> {
-    rng: &'r mut SmallRng,
-    mat: Mat,
+// The parameters of a `Dielectric` at some specific point
+struct Coating {
+    color: Vec3,
+    fresnel: Vec3,
+    shininess: f32,
 }
 
-impl<'r> Sampler<'r> {
-    fn rand(&mut self) -> f32 {
-        self.rng.gen::<f32>()
-    }
-
-    fn dielectric_sample_wi(&mut self, wo: Vec3, n: Vec3) -> DirSample {
-        // Russian-roulette sampling of reflection vs refraction.
-        //
-        // Prefer sampling reflection when the fresnel-parameter `fresnel` (R0)
-        // is high.
-        let p = 0.5 + glm::comp_min(&self.mat.fresnel) / 2.0;
-        if self.rand() < p {
-            let mut sample = self.dielectric_reflection_sample_wi(wo, n);
-            sample.pdf *= p;
-            sample
-        } else {
-            let mut sample = self.dielectric_refraction_sample_wi(wo, n);
-            sample.pdf *= 1.0 - p;
-            sample
-        }
+impl Coating {
+    // Probability of choosing to sample the reflective layer over the
+    // underlying diffuse one.
+    //
+    // Prefer sampling reflection when the fresnel-parameter `fresnel` (R0)
+    // is high.
+    fn reflection_probability(&self) -> f32 {
+        0.5 + glm::comp_min(&self.fresnel) / 2.0
     }
 
     // Sample a direction based on the Microfacet brdf
@@ -85,8 +212,9 @@ impl<'r> Sampler<'r> {
     // # Preconditions
     // `wo` must be on the same side as `n`s plane, i.e. `dot(wo, n) >= 0.0`
     // must be true.
-    fn dielectric_reflection_sample_wi(
-        &mut self,
+    fn reflection_sample_wi(
+        &self,
+        rng: &mut dyn RngCore,
         wo: Vec3,
         n: Vec3,
     ) -> DirSample {
@@ -94,19 +222,23 @@ impl<'r> Sampler<'r> {
         // said       that it's "out of scope for this tutorial".
         //
         // Importance sample more values where the BRDF-value will be high.
-        let phi = 2.0 * PI * self.rand();
-        let cos_theta = self.rand().powf(1.0 / (self.mat.shininess + 1.0));
+        let phi = 2.0 * PI * rand(rng);
+        let cos_theta = rand(rng).powf(1.0 / (self.shininess + 1.0));
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         let wh = orthonormal_basis_inverse_transform(
             n,
             vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta),
         );
-        // TODO: Investigate whether `wh` can ever be on the wrong side of
-        //       `n`. TDA362 had an early-exit on `dot(wh, n) < 0.0f`.
-        let pdf_wh = (self.mat.shininess + 1.0)
-            * n.dot(&wh).powf(self.mat.shininess)
-            / (2.0 * PI);
         let wi = glm::reflect_vec(&-wo, &wh);
+        DirSample {
+            wi,
+            pdf: self.reflection_pdf(wi, wo, n),
+            brdf: dielectric_reflection_brdf(wi, wo, n, self),
+            lobe: Lobes::GLOSSY,
+        }
+    }
+
+    fn reflection_pdf(&self, wi: Vec3, wo: Vec3, n: Vec3) -> f32 {
         // TODO: Why exactly can an "invalid" `wi` be generated?
         //
         // If for some reason `wi` is not on the same side as `n`, set
@@ -114,63 +246,193 @@ impl<'r> Sampler<'r> {
         // the calculated `bdrf` won't make sense. This seems to happen
         // due to bad sampling algorithm. Could maybe be eliminated with a
         // better algorithm.
-        let pdf_wi = if wi.dot(&n) >= 0.0 {
-            pdf_wh / (4.0 * wo.dot(&wh))
-        } else {
-            0.0
-        };
-        DirSample {
-            wi,
-            pdf: pdf_wi,
-            brdf: dielectric_reflection_brdf(wi, wo, n, &self.mat),
+        if wi.dot(&n) < 0.0 {
+            return 0.0;
         }
+        let wh = (wo + wi).normalize();
+        // TODO: Investigate whether `wh` can ever be on the wrong side of
+        //       `n`. TDA362 had an early-exit on `dot(wh, n) < 0.0f`.
+        let pdf_wh = (self.shininess + 1.0) * n.dot(&wh).powf(self.shininess)
+            / (2.0 * PI);
+        pdf_wh / (4.0 * wo.dot(&wh))
     }
 
     // Sample a direction for the underlying layer
-    fn dielectric_refraction_sample_wi(
-        &mut self,
+    fn refraction_sample_wi(
+        &self,
+        rng: &mut dyn RngCore,
         wo: Vec3,
         n: Vec3,
     ) -> DirSample {
-        let mut sample = self.diffuse_sample_wi(wo, n);
+        let mut sample = diffuse_sample_wi(rng, wo, n, self.color);
         sample.brdf =
-            attenuate_diffuse_refraction(sample.wi, wo, sample.brdf, &self.mat);
+            attenuate_diffuse_refraction(sample.wi, wo, sample.brdf, self);
         sample
     }
+}
 
-    fn diffuse_sample_wi(&mut self, wo: Vec3, n: Vec3) -> DirSample {
-        let wi = orthonormal_basis_inverse_transform(
-            n,
-            self.cosine_sample_hemisphere(),
-        );
+impl Coating {
+    fn eval(&self, wi: Vec3, wo: Vec3, n: Vec3) -> Vec3 {
+        dielectric_reflection_brdf(wi, wo, n, self)
+            + dielectric_refraction_brdf(wi, wo, n, self)
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, n: Vec3) -> DirSample {
+        // Russian-roulette sampling of reflection vs refraction.
+        let p = self.reflection_probability();
+        if rand(rng) < p {
+            let mut sample = self.reflection_sample_wi(rng, wo, n);
+            sample.pdf *= p;
+            sample
+        } else {
+            let mut sample = self.refraction_sample_wi(rng, wo, n);
+            sample.pdf *= 1.0 - p;
+            sample
+        }
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, n: Vec3) -> f32 {
+        let p = self.reflection_probability();
+        p * self.reflection_pdf(wi, wo, n)
+            + (1.0 - p) * cosine_hemisphere_pdf(wi, n)
+    }
+}
+
+impl Bsdf for Dielectric {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        self.at(hit).eval(wi, wo, hit.normal)
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        self.at(hit).sample(rng, wo, hit.normal)
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        self.at(hit).pdf(wi, wo, hit.normal)
+    }
+
+    fn lobes(&self) -> Lobes {
+        Lobes::GLOSSY | Lobes::DIFFUSE
+    }
+}
+
+// Ideal diffuse reflection
+#[derive(Clone)]
+pub struct Lambertian {
+    pub color: Tex<Vec3>,
+}
+
+impl Bsdf for Lambertian {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        diffuse_brdf(wi, wo, hit.normal, self.color.at(hit))
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        diffuse_sample_wi(rng, wo, hit.normal, self.color.at(hit))
+    }
+
+    fn pdf(&self, wi: Vec3, _wo: Vec3, hit: &Hit) -> f32 {
+        cosine_hemisphere_pdf(wi, hit.normal)
+    }
+
+    fn lobes(&self) -> Lobes {
+        Lobes::DIFFUSE
+    }
+}
+
+// Perfectly smooth mirror
+#[derive(Clone)]
+pub struct SpecularReflector {
+    pub fresnel: Tex<Vec3>,
+}
+
+impl Bsdf for SpecularReflector {
+    fn eval(&self, _wi: Vec3, _wo: Vec3, _hit: &Hit) -> Vec3 {
+        Vec3::zeros()
+    }
+
+    fn sample(&self, _rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        let n = hit.normal;
+        let wi = glm::reflect_vec(&-wo, &n);
+        let cos_theta = wi.dot(&n);
+        // The delta distribution is represented by a pdf of 1 and dividing
+        // the BRDF with the cosine term, which cancel out in the rendering
+        // equation.
+        let brdf = if cos_theta > 0.0 {
+            F(wi, n, self.fresnel.at(hit)) / cos_theta
+        } else {
+            Vec3::zeros()
+        };
         DirSample {
-            // Direction sampled with a cosine distribution
             wi,
-            // Cosine probability to match our sampling distribution.
-            // Remember, $N ⋅ W = ||N|| ||W|| cos(θ) = 1 * 1 * cos(θ) = cos(θ)$.
-            pdf: 0.0f32.max(n.dot(&wi)) * FRAC_1_PI,
-            brdf: diffuse_brdf(wi, wo, n, &self.mat),
+            pdf: 1.0,
+            brdf,
+            lobe: Lobes::DELTA,
         }
     }
 
-    fn cosine_sample_hemisphere(&mut self) -> Vec3 {
-        let r1 = 2.0 * PI * self.rand();
-        let r2 = self.rand();
-        let r2s = r2.sqrt();
-        vec3(r1.cos() * r2s, r1.sin() * r2s, (1.0 - r2).sqrt())
+    fn pdf(&self, _wi: Vec3, _wo: Vec3, _hit: &Hit) -> f32 {
+        0.0
+    }
+
+    fn lobes(&self) -> Lobes {
+        Lobes::DELTA
     }
 }
 
+fn diffuse_sample_wi(
+    rng: &mut dyn RngCore,
+    wo: Vec3,
+    n: Vec3,
+    color: Vec3,
+) -> DirSample {
+    let wi =
+        orthonormal_basis_inverse_transform(n, cosine_sample_hemisphere(rng));
+    DirSample {
+        // Direction sampled with a cosine distribution
+        wi,
+        pdf: cosine_hemisphere_pdf(wi, n),
+        brdf: diffuse_brdf(wi, wo, n, color),
+        lobe: Lobes::DIFFUSE,
+    }
+}
+
+pub fn cosine_sample_hemisphere(rng: &mut dyn RngCore) -> Vec3 {
+    let r1 = 2.0 * PI * rand(rng);
+    let r2 = rand(rng);
+    let r2s = r2.sqrt();
+    vec3(r1.cos() * r2s, r1.sin() * r2s, (1.0 - r2).sqrt())
+}
+
+pub fn uniform_sample_sphere(rng: &mut dyn RngCore) -> Vec3 {
+    let z = 1.0 - 2.0 * rand(rng);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * rand(rng);
+    vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Cosine probability to match our sampling distribution.
+// Remember, $N ⋅ W = ||N|| ||W|| cos(θ) = 1 * 1 * cos(θ) = cos(θ)$.
+fn cosine_hemisphere_pdf(wi: Vec3, n: Vec3) -> f32 {
+    0.0f32.max(n.dot(&wi)) * FRAC_1_PI
+}
+
 // Torrance-sparrow specular highlight model with approximations.
 //
 // See [http://www.cse.chalmers.se/edu/year/2018/course/TDA361/Physically-Based%20Shading.pdf]
 // and [https://en.wikipedia.org/wiki/Specular_highlight].
-fn dielectric_reflection_brdf(wi: Vec3, wo: Vec3, n: Vec3, mat: &Mat) -> Vec3 {
+fn dielectric_reflection_brdf(
+    wi: Vec3,
+    wo: Vec3,
+    n: Vec3,
+    mat: &Coating,
+) -> Vec3 {
     // `wo` can be on the wrong side of `n` when the geometric normal and
     // shading normal are very different, e.g. due to normal mapping. When
     // this is the case, it doesn't make sense that any light can
-    // pass through that route.
-    if wo.dot(&n) < 0.0 {
+    // pass through that route. Nor can light from below the surface be
+    // reflected.
+    if wo.dot(&n) < 0.0 || wi.dot(&n) <= 0.0 {
         Vec3::zeros()
     } else {
         let wh = (wo + wi).normalize();
@@ -216,11 +478,16 @@ fn G(wi: Vec3, wo: Vec3, wh: Vec3, n: Vec3) -> f32 {
     )
 }
 
-fn dielectric_refraction_brdf(wi: Vec3, wo: Vec3, n: Vec3, mat: &Mat) -> Vec3 {
+fn dielectric_refraction_brdf(
+    wi: Vec3,
+    wo: Vec3,
+    n: Vec3,
+    mat: &Coating,
+) -> Vec3 {
     return attenuate_diffuse_refraction(
         wi,
         wo,
-        diffuse_brdf(wi, wo, n, mat),
+        diffuse_brdf(wi, wo, n, mat.color),
         mat,
     );
 }
@@ -229,17 +496,17 @@ fn attenuate_diffuse_refraction(
     wi: Vec3,
     wo: Vec3,
     brdf: Vec3,
-    mat: &Mat,
+    mat: &Coating,
 ) -> Vec3 {
     let wh = (wo + wi).normalize();
     (Vec3::repeat(1.0) - F(wi, wh, mat.fresnel)).component_mul(&brdf)
 }
 
-fn diffuse_brdf(wi: Vec3, wo: Vec3, n: Vec3, mat: &Mat) -> Vec3 {
+fn diffuse_brdf(wi: Vec3, wo: Vec3, n: Vec3, color: Vec3) -> Vec3 {
     // If `wi` and `wo` are not on the right side of the surface, no light
     // passes through.
     if wo.dot(&n) >= 0.0 && wi.dot(&n) >= 0.0 {
-        FRAC_1_PI * mat.color
+        FRAC_1_PI * color
     } else {
         Vec3::zeros()
     }
@@ -252,12 +519,45 @@ fn diffuse_brdf(wi: Vec3, wo: Vec3, n: Vec3, mat: &Mat) -> Vec3 {
 // E.g. do a hemisphere sample with world-up as center, then transform with
 // this to make it as if the hemisphere has n as center.
 fn orthonormal_basis_inverse_transform(normal: Vec3, wi: Vec3) -> Vec3 {
-    let w_up = if normal.x.abs() > 0.1 {
-        vec3(0.0, 1.0, 0.0)
-    } else {
-        vec3(1.0, 0.0, 0.0)
-    };
-    let tangent = normal.cross(&w_up).normalize();
-    let bitangent = normal.cross(&tangent).normalize();
-    tangent * wi.x + bitangent * wi.y + normal * wi.z
+    Frame::new(normal).to_world(wi)
+}
+
+// An orthonormal basis around a normal. In local coordinates, the normal is
+// the z-axis, so e.g. `cos(θ)` of a local direction is just its z-component.
+pub struct Frame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3,
+}
+
+impl Frame {
+    pub fn new(normal: Vec3) -> Self {
+        let w_up = if normal.x.abs() > 0.1 {
+            vec3(0.0, 1.0, 0.0)
+        } else {
+            vec3(1.0, 0.0, 0.0)
+        };
+        let tangent = normal.cross(&w_up).normalize();
+        let bitangent = normal.cross(&tangent).normalize();
+        Frame {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub fn to_local(&self, v: Vec3) -> Vec3 {
+        vec3(
+            v.dot(&self.tangent),
+            v.dot(&self.bitangent),
+            v.dot(&self.normal),
+        )
+    }
+
+    pub fn to_world(&self, v: Vec3) -> Vec3 {
+        self.tangent * v.x + self.bitangent * v.y + self.normal * v.z
+    }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,584 @@
+// Monte Carlo checks of every BSDF. The checks are statistical, but seeded,
+// so a given implementation either always passes or always fails.
+
+use nalgebra_glm::{vec2, vec3, Vec3};
+use rand::prelude::*;
+use std::f32::consts::PI;
+
+use super::*;
+use crate::bump::*;
+use crate::conductor::*;
+use crate::cutout::*;
+use crate::layered::*;
+use crate::principled::*;
+use crate::spectrum::*;
+use crate::subsurface::*;
+use crate::thinfilm::*;
+
+struct Case {
+    name: &'static str,
+    mat: Mat,
+    // Whether `eval(wi, wo) == eval(wo, wi)` when both are above the surface.
+    // Not the case for sheen, which scales its base by the albedo seen from
+    // `wo`.
+    reciprocal: bool,
+    // Whether a white version of the material reflects at most all of the
+    // light that hits it. Not the case for the few models that are known to
+    // gain energy, see `cases`.
+    conserving: bool,
+    // The hero wavelength to check at, as in spectral mode
+    wavelength: Option<f32>,
+}
+
+fn coat(roughness: f32) -> Coat {
+    Coat {
+        roughness: roughness.into(),
+        ior: 1.5.into(),
+        film: None,
+    }
+}
+
+// Every kind of BSDF, mostly white so that all the energy they lose is lost
+// to absorption by the model itself.
+fn cases() -> Vec<Case> {
+    let white = Vec3::repeat(1.0);
+    let case = |name, mat| Case {
+        name,
+        mat,
+        reciprocal: true,
+        conserving: true,
+        wavelength: None,
+    };
+    // A model that gains energy at grazing angles
+    let gains = |name, mat| Case {
+        conserving: false,
+        ..case(name, mat)
+    };
+    vec![
+        case(
+            "lambertian",
+            Mat::new(Lambertian {
+                color: white.into(),
+            }),
+        ),
+        // The legacy Blinn-Phong model only takes the Fresnel reflectance
+        // of the half-vector from the diffuse base, which is less than what
+        // the specular lobe actually reflects at grazing angles.
+        gains(
+            "dielectric diffuse",
+            Mat::new(Dielectric {
+                color: white.into(),
+                fresnel: Vec3::zeros().into(),
+                shininess: 0.0.into(),
+            }),
+        ),
+        case(
+            "dielectric glossy",
+            Mat::new(Dielectric {
+                color: Vec3::repeat(0.5).into(),
+                fresnel: Vec3::repeat(0.04).into(),
+                shininess: 64.0.into(),
+            }),
+        ),
+        case(
+            "specular reflector",
+            Mat::new(SpecularReflector {
+                fresnel: white.into(),
+            }),
+        ),
+        // The retro-reflection of the Disney diffuse, which is a fit to
+        // measurements, brightens grazing angles by design, and the specular
+        // lobe is added on top without taking its energy from the diffuse.
+        gains("plastic", Mat::new(Principled::plastic(white, 0.5))),
+        case("metal", Mat::new(Principled::metal(white, 0.4))),
+        case("glass", Mat::new(Principled::glass(white, 0.4))),
+        case("smooth metal", Mat::new(Principled::metal(white, 0.0))),
+        case("smooth glass", Mat::new(Principled::glass(white, 0.0))),
+        // Like plastic, and the clearcoat is added on top of the rest as
+        // well.
+        gains("car paint", Mat::new(Principled::car_paint(white))),
+        // The Disney sheen is an extra lobe on top, for the look of cloth
+        // at grazing angles, with no energy taken from the others.
+        gains("cloth", Mat::new(Principled::cloth(white))),
+        // The Disney subsurface approximation, which flattens the diffuse
+        // lobe, rises steeply at grazing angles.
+        gains("wax", Mat::new(Principled::wax(white))),
+        case("coat", Mat::new(coat(0.3))),
+        case("smooth coat", Mat::new(coat(0.0))),
+        // In spectral mode, where dispersion matters, and where the film
+        // is evaluated at a single wavelength rather than integrated over
+        // many, which would be slow
+        Case {
+            wavelength: Some(550.0),
+            ..case(
+                "dispersive coat with film",
+                Mat::new(Coat {
+                    roughness: 0.3.into(),
+                    ior: Ior::bk7(),
+                    film: Some(ThinFilm {
+                        thickness: 400.0.into(),
+                        ior: 1.33,
+                    }),
+                }),
+            )
+        },
+        case(
+            "absorbing",
+            Mat::new(Absorbing {
+                base: Mat::new(coat(0.3)),
+                absorption: vec3(1.0, 2.0, 3.0),
+            }),
+        ),
+        case(
+            "subsurface",
+            Mat::new(Subsurface {
+                surface: coat(0.3),
+                albedo: Vec3::repeat(0.8),
+                mfp: Vec3::repeat(0.1),
+            }),
+        ),
+        case(
+            "volume",
+            Mat::new(Volume {
+                medium: Medium::fog(0.5, 0.6),
+            }),
+        ),
+        case("gold", Mat::new(Conductor::gold(0.3))),
+        case("smooth gold", Mat::new(Conductor::gold(0.0))),
+        case(
+            "silver with film",
+            Mat::new(Conductor {
+                film: Some(ThinFilm {
+                    thickness: 300.0.into(),
+                    ior: 2.4,
+                }),
+                ..Conductor::silver(0.3)
+            }),
+        ),
+        Case {
+            reciprocal: false,
+            ..case("sheen", Mat::sheen(white, 0.5))
+        },
+        Case {
+            reciprocal: false,
+            ..case("cloth sheen", Mat::cloth(white, white, 0.5))
+        },
+        case(
+            "layered diffuse",
+            Mat::new(Layered {
+                coat: coat(0.2),
+                base: Mat::new(Lambertian {
+                    color: white.into(),
+                }),
+                thickness: 0.1,
+                absorption: Vec3::zeros().into(),
+            }),
+        ),
+        case(
+            "layered mirror",
+            Mat::new(Layered {
+                coat: coat(0.0),
+                base: Mat::new(SpecularReflector {
+                    fresnel: white.into(),
+                }),
+                thickness: 0.1,
+                absorption: Vec3::repeat(0.5).into(),
+            }),
+        ),
+        case(
+            "clearcoat over smooth metal",
+            Mat::new(Layered {
+                coat: coat(0.1),
+                base: Mat::new(Conductor::gold(0.0)),
+                thickness: 0.0,
+                absorption: Vec3::zeros().into(),
+            }),
+        ),
+        case(
+            "layered metal",
+            Mat::new(Layered {
+                coat: coat(0.1),
+                base: Mat::new(Principled::metal(white, 0.3)),
+                thickness: 0.1,
+                absorption: Vec3::repeat(0.5).into(),
+            }),
+        ),
+    ]
+}
+
+fn hit(mat: Mat, wavelength: Option<f32>) -> Hit {
+    let normal = vec3(0.0, 0.0, 1.0);
+    Hit {
+        t: 1.0,
+        normal,
+        geo_normal: normal,
+        pos: Vec3::zeros(),
+        local_pos: normal,
+        uv: vec2(0.5, 0.5),
+        dpdu: vec3(1.0, 0.0, 0.0),
+        dpdv: vec3(0.0, 1.0, 0.0),
+        wavelength,
+        object: 0,
+        mat,
+    }
+}
+
+fn dir(cos_theta: f32, phi: f32) -> Vec3 {
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+fn uniform_sphere(rng: &mut dyn RngCore) -> Vec3 {
+    dir(2.0 * rand(rng) - 1.0, 2.0 * PI * rand(rng))
+}
+
+// Out-directions from normal to grazing incidence, and from inside the
+// surface for materials that let light through.
+fn out_dirs(mat: &Mat) -> Vec<Vec3> {
+    let mut cos_thetas = vec![1.0, 0.7, 0.3, 0.1];
+    if mat.lobes().contains(Lobes::TRANSMISSION) {
+        cos_thetas.extend_from_slice(&[-0.9, -0.5]);
+    }
+    cos_thetas.into_iter().map(|c| dir(c, 0.3)).collect()
+}
+
+fn is_valid(f: Vec3) -> bool {
+    f.iter().all(|x| x.is_finite() && *x >= 0.0)
+}
+
+fn show(v: Vec3) -> String {
+    format!("({:.4}, {:.4}, {:.4})", v.x, v.y, v.z)
+}
+
+// Run `check` on every case, and fail with the first problem found with each
+// case that has any.
+fn check_cases(
+    filter: impl Fn(&Case) -> bool,
+    mut check: impl FnMut(&Case, &Hit) -> Result<(), String>,
+) {
+    let failures = cases()
+        .into_iter()
+        .filter(|case| filter(case))
+        .filter_map(|case| {
+            let h = hit(case.mat.clone(), case.wavelength);
+            check(&case, &h)
+                .err()
+                .map(|e| format!("{}: {}", case.name, e))
+        })
+        .collect::<Vec<_>>();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn non_negative() {
+    let mut rng = SmallRng::seed_from_u64(0);
+    check_cases(
+        |_| true,
+        |case, h| {
+            for _ in 0..2_000 {
+                let wi = uniform_sphere(&mut rng);
+                let wo = uniform_sphere(&mut rng);
+                let (f, pdf) =
+                    (case.mat.eval(wi, wo, h), case.mat.pdf(wi, wo, h));
+                if !(is_valid(f) && pdf >= 0.0 && pdf.is_finite()) {
+                    return Err(format!(
+                        "f = {}, pdf = {}, for wi {} and wo {}",
+                        show(f),
+                        pdf,
+                        show(wi),
+                        show(wo)
+                    ));
+                }
+            }
+            for wo in out_dirs(&case.mat) {
+                for _ in 0..2_000 {
+                    let s = case.mat.sample(&mut rng, wo, h);
+                    let valid = s.pdf == 0.0
+                        || s.pdf > 0.0 && s.pdf.is_finite() && is_valid(s.brdf);
+                    if !valid {
+                        return Err(format!(
+                            "sampled f = {}, pdf = {}, for wi {} and wo {}",
+                            show(s.brdf),
+                            s.pdf,
+                            show(s.wi),
+                            show(wo)
+                        ));
+                    }
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+#[test]
+fn reciprocal() {
+    let mut rng = SmallRng::seed_from_u64(1);
+    check_cases(
+        |case| case.reciprocal,
+        |case, h| {
+            for _ in 0..2_000 {
+                let wi = dir(rand(&mut rng), 2.0 * PI * rand(&mut rng));
+                let wo = dir(rand(&mut rng), 2.0 * PI * rand(&mut rng));
+                let f = case.mat.eval(wi, wo, h);
+                let f_rev = case.mat.eval(wo, wi, h);
+                if (f - f_rev).amax() > 1e-3 * f.amax().max(1.0) {
+                    return Err(format!(
+                        "f(wi, wo) = {} but f(wo, wi) = {}, for wi {} and wo {}",
+                        show(f),
+                        show(f_rev),
+                        show(wi),
+                        show(wo)
+                    ));
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+// A white material can at most reflect all of the light that hits it. The
+// albedo is estimated with `sample` from outside the surface. From inside, the
+// radiance of transmitted light is legitimately scaled by the relative IOR.
+#[test]
+fn white_furnace() {
+    check_furnace(|case| case.conserving)
+}
+
+#[test]
+#[ignore = "the legacy Blinn-Phong model and the Disney diffuse, sheen and \
+            clearcoat add energy at grazing angles by design"]
+fn white_furnace_of_gaining_models() {
+    check_furnace(|case| !case.conserving)
+}
+
+fn check_furnace(filter: impl Fn(&Case) -> bool) {
+    const SAMPLES: usize = 10_000;
+    let mut rng = SmallRng::seed_from_u64(2);
+    check_cases(filter, |case, h| {
+        for wo in out_dirs(&case.mat).into_iter().filter(|wo| wo.z > 0.0) {
+            let (mut sum, mut sum_sq) = (Vec3::zeros(), Vec3::zeros());
+            for _ in 0..SAMPLES {
+                let s = case.mat.sample(&mut rng, wo, h);
+                if s.pdf > 0.0 {
+                    let w = s.brdf * s.wi.z.abs() / s.pdf;
+                    sum += w;
+                    sum_sq += w.component_mul(&w);
+                }
+            }
+            let n = SAMPLES as f32;
+            let albedo = sum / n;
+            let variance = sum_sq / n - albedo.component_mul(&albedo);
+            let std_error = (variance / n).map(f32::sqrt);
+            if (albedo - 4.0 * std_error).amax() > 1.01 {
+                return Err(format!(
+                    "albedo {} ± {} exceeds 1 for wo {}",
+                    show(albedo),
+                    show(std_error),
+                    show(wo)
+                ));
+            }
+        }
+        Ok(())
+    })
+}
+
+// Bin sampled directions over the sphere, and compare the counts against
+// those expected from integrating `pdf` over each bin, with Pearson's
+// chi-square test. Samples of delta lobes, or that failed, are counted
+// together with the probability mass missing from `pdf`.
+#[test]
+fn pdf_matches_samples() {
+    const SAMPLES: usize = 10_000;
+    // Bins uniform in θ and φ, which resolve lobes around the normal better
+    // than bins of equal solid angle
+    const THETA_BINS: usize = 20;
+    const PHI_BINS: usize = 20;
+    let bin_size = (PI / THETA_BINS as f32, 2.0 * PI / PHI_BINS as f32);
+    let mut rng = SmallRng::seed_from_u64(3);
+    check_cases(
+        |_| true,
+        |case, h| {
+            for wo in out_dirs(&case.mat) {
+                let mut observed = vec![0.0; THETA_BINS * PHI_BINS + 1];
+                for _ in 0..SAMPLES {
+                    let s = case.mat.sample(&mut rng, wo, h);
+                    let i = if s.pdf > 0.0 && !s.lobe.contains(Lobes::DELTA) {
+                        let wi = s.wi.normalize();
+                        if case.mat.pdf(wi, wo, h) == 0.0 {
+                            return Err(format!(
+                                "sampled wi {} for wo {}, where the pdf is 0",
+                                show(wi),
+                                show(wo)
+                            ));
+                        }
+                        let phi = wi.y.atan2(wi.x).rem_euclid(2.0 * PI);
+                        let t = (wi.z.max(-1.0).min(1.0).acos() / bin_size.0)
+                            as usize;
+                        let p = (phi / bin_size.1) as usize;
+                        t.min(THETA_BINS - 1) * PHI_BINS + p.min(PHI_BINS - 1)
+                    } else {
+                        THETA_BINS * PHI_BINS
+                    };
+                    observed[i] += 1.0;
+                }
+                let mut expected = vec![0.0; THETA_BINS * PHI_BINS + 1];
+                let pdf = |theta: f32, phi: f32| {
+                    case.mat.pdf(dir(theta.cos(), phi), wo, h) * theta.sin()
+                };
+                for t in 0..THETA_BINS {
+                    for p in 0..PHI_BINS {
+                        let mass = integrate(
+                            &pdf,
+                            (
+                                t as f32 * bin_size.0,
+                                (t + 1) as f32 * bin_size.0,
+                            ),
+                            (
+                                p as f32 * bin_size.1,
+                                (p + 1) as f32 * bin_size.1,
+                            ),
+                            0.1 / SAMPLES as f32,
+                            6,
+                        );
+                        expected[t * PHI_BINS + p] = mass * SAMPLES as f32;
+                    }
+                }
+                let total = expected.iter().sum::<f32>();
+                expected[THETA_BINS * PHI_BINS] =
+                    (SAMPLES as f32 - total).max(0.0);
+                let z = chi_square_z(&observed, &expected);
+                if z > 4.0 {
+                    return Err(format!(
+                        "sampled directions don't match the pdf for wo {}, \
+                         with a z-score of {}",
+                        show(wo),
+                        z
+                    ));
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+// Materials that wrap another one must pass on what they don't change
+// themselves, or e.g. a bump mapped volume would block light.
+#[test]
+fn wrappers_forward_to_their_base() {
+    let base = || {
+        Mat::new(Volume {
+            medium: Medium::fog(0.5, 0.6),
+        })
+    };
+    let wrappers = vec![
+        (
+            "normal mapped",
+            Mat::new(NormalMapped {
+                base: base(),
+                map: vec3(0.5, 0.5, 1.0).into(),
+            }),
+        ),
+        (
+            "bump mapped",
+            Mat::new(BumpMapped {
+                base: base(),
+                height: 0.0.into(),
+                scale: 1.0,
+            }),
+        ),
+        (
+            "cutout",
+            Mat::new(Cutout {
+                base: base(),
+                opacity: 1.0.into(),
+            }),
+        ),
+        (
+            "absorbing",
+            Mat::new(Absorbing {
+                base: base(),
+                absorption: Vec3::repeat(0.5),
+            }),
+        ),
+    ];
+    for (name, mat) in wrappers {
+        assert!(mat.invisible(), "{} is not invisible", name);
+        assert!(mat.interior().is_some(), "{} has no interior", name);
+        assert_eq!(mat.lobes(), base().lobes(), "lobes of {}", name);
+    }
+}
+
+// The chi-square statistic of the bin counts, as a standard normal z-score
+// by the Wilson-Hilferty transformation. Bins expecting fewer than 5 samples
+// are pooled, as the test is not accurate for them. 0 if that leaves just one
+// bin, e.g. for a delta lobe, which is then trivially right.
+fn chi_square_z(observed: &[f32], expected: &[f32]) -> f32 {
+    let mut bins = observed.iter().zip(expected).collect::<Vec<_>>();
+    bins.sort_by(|a, b| a.1.partial_cmp(b.1).unwrap());
+    let (mut chi2, mut dof) = (0.0, 0);
+    let (mut pooled_obs, mut pooled_exp) = (0.0, 0.0);
+    for (&obs, &exp) in bins {
+        if exp < 5.0 || pooled_exp > 0.0 && pooled_exp < 5.0 {
+            pooled_obs += obs;
+            pooled_exp += exp;
+        } else {
+            chi2 += (obs - exp) * (obs - exp) / exp;
+            dof += 1;
+        }
+    }
+    if pooled_exp > 0.0 {
+        chi2 +=
+            (pooled_obs - pooled_exp) * (pooled_obs - pooled_exp) / pooled_exp;
+        dof += 1;
+    }
+    if dof < 2 {
+        return 0.0;
+    }
+    let k = (dof - 1) as f32;
+    let s = 2.0 / (9.0 * k);
+    ((chi2 / k).powf(1.0 / 3.0) - (1.0 - s)) / s.sqrt()
+}
+
+// Integrate `f(θ, φ)` over a rectangle of θ and φ with the midpoint rule,
+// subdividing the rectangle where a finer grid changes the result by more
+// than `tolerance`. Glossy lobes need much finer grids than the rest of the
+// sphere.
+fn integrate(
+    f: &impl Fn(f32, f32) -> f32,
+    t: (f32, f32),
+    p: (f32, f32),
+    tolerance: f32,
+    depth: u32,
+) -> f32 {
+    const N: usize = 4;
+    let midpoint = |(t, p): ((f32, f32), (f32, f32))| {
+        let (dt, dp) = ((t.1 - t.0) / N as f32, (p.1 - p.0) / N as f32);
+        (0..N * N)
+            .map(|i| {
+                let u = (i / N) as f32 + 0.5;
+                let v = (i % N) as f32 + 0.5;
+                f(t.0 + u * dt, p.0 + v * dp)
+            })
+            .sum::<f32>()
+            * dt
+            * dp
+    };
+    let (tm, pm) = ((t.0 + t.1) / 2.0, (p.0 + p.1) / 2.0);
+    let quarters = [
+        ((t.0, tm), (p.0, pm)),
+        ((t.0, tm), (pm, p.1)),
+        ((tm, t.1), (p.0, pm)),
+        ((tm, t.1), (pm, p.1)),
+    ];
+    let coarse = midpoint((t, p));
+    let fine = quarters.iter().cloned().map(midpoint).sum::<f32>();
+    if depth == 0 || (fine - coarse).abs() < tolerance {
+        fine
+    } else {
+        quarters
+            .iter()
+            .map(|&(t, p)| integrate(f, t, p, tolerance / 4.0, depth - 1))
+            .sum()
+    }
+}
@@ -0,0 +1,906 @@
+// A library of named materials, loaded from a text file so that materials
+// can be tweaked without recompiling. The format is a small subset of TOML:
+//
+//     # Comments start with a hash
+//     [red_plastic]
+//     inherits = "plastic"
+//     base_color = [0.8, 0.1, 0.1]
+//     roughness = 0.3
+//
+// Each table is a material, with a `type` and the parameters of that type.
+// A material may instead inherit the type and parameters of a preset, or of
+// another material in the library, and override some of them. The presets
+// are in `presets.toml`. Any material may also have an `opacity`, to make
+// it stochastically transparent, see `Cutout`, and a `normal_map`, the path
+// of an image of tangent-space normals, see `NormalMapped`.
+//
+// Values are numbers, strings, or arrays of numbers, of three for colors. A
+// number may be given where a color is expected, for a gray. Most colors and
+// numbers may instead be the path of an image, which is mapped onto the
+// object by its UV-coordinates. Images of colors are read as sRGB, and those
+// of other parameters as-is. `wrap` sets how the images of a material repeat,
+// and is one of "repeat" (the default), "mirror", or "clamp".
+
+use image::ImageResult;
+use nalgebra_glm::{vec3, Vec3};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{fmt, fs, io};
+
+use crate::bump::*;
+use crate::conductor::*;
+use crate::cutout::*;
+use crate::density::*;
+use crate::layered::*;
+use crate::material::*;
+use crate::medium::*;
+use crate::principled::*;
+use crate::sheen::*;
+use crate::spectrum::*;
+use crate::subsurface::*;
+use crate::texture::*;
+use crate::thinfilm::*;
+
+const PRESETS: &str = include_str!("presets.toml");
+
+#[derive(Debug)]
+pub enum LibError {
+    Io(io::Error),
+    // Something is wrong with the contents of the file, at `line`
+    Invalid { line: usize, msg: String },
+}
+
+impl fmt::Display for LibError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LibError::Io(e) => write!(f, "{}", e),
+            LibError::Invalid { line, msg } => {
+                write!(f, "line {}: {}", line, msg)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for LibError {
+    fn from(e: io::Error) -> Self {
+        LibError::Io(e)
+    }
+}
+
+fn invalid(line: usize, msg: String) -> LibError {
+    LibError::Invalid { line, msg }
+}
+
+pub struct MatLib {
+    mats: HashMap<String, Mat>,
+    // Names asked for that aren't in the library, each reported once
+    missing: Mutex<HashSet<String>>,
+}
+
+impl MatLib {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LibError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(src: &str) -> Result<Self, LibError> {
+        let presets = parse_tables(PRESETS).expect("material presets");
+        let tables = parse_tables(src)?;
+        for t in &tables {
+            if presets.iter().any(|p| p.name == t.name) {
+                let msg =
+                    format!("`{}` is already the name of a preset", t.name);
+                return Err(invalid(t.line, msg));
+            }
+        }
+        let mut builder = Builder {
+            tables: tables.into_iter().chain(presets).collect(),
+            built: HashMap::new(),
+        };
+        for i in 0..builder.tables.len() {
+            builder.build(i, &mut vec![])?;
+        }
+        let Builder { tables, mut built } = builder;
+        let mats = tables
+            .into_iter()
+            .enumerate()
+            .map(|(i, t)| (t.name, built.remove(&i).unwrap()))
+            .collect();
+        Ok(MatLib {
+            mats,
+            missing: Mutex::new(HashSet::new()),
+        })
+    }
+
+    // Just the presets, for when there's no library file
+    pub fn presets() -> Self {
+        Self::parse("").expect("material presets")
+    }
+
+    // The material or preset called `name`. If there is none, a loud magenta
+    // material, to make the mistake obvious in the render, and the name is
+    // reported the first time.
+    pub fn get(&self, name: &str) -> Mat {
+        self.mats.get(name).cloned().unwrap_or_else(|| {
+            if self.missing.lock().unwrap().insert(name.to_string()) {
+                eprintln!("No material or preset named `{}`", name);
+            }
+            Mat::diffuse(vec3(1.0, 0.0, 1.0))
+        })
+    }
+}
+
+#[derive(Clone)]
+enum Value {
+    Num(f32),
+    Str(String),
+    Color(Vec3),
+    // An array of any other length
+    List(Vec<f32>),
+}
+
+#[derive(Clone)]
+struct Entry {
+    value: Value,
+    line: usize,
+}
+
+impl Entry {
+    fn num(&self, key: &str) -> Result<f32, LibError> {
+        match self.value {
+            Value::Num(x) => Ok(x),
+            _ => Err(self.expected(key, "a number")),
+        }
+    }
+
+    fn color(&self, key: &str) -> Result<Vec3, LibError> {
+        match self.value {
+            Value::Num(x) => Ok(Vec3::repeat(x)),
+            Value::Color(c) => Ok(c),
+            Value::List(ref xs) => Err(invalid(
+                self.line,
+                format!("a color has three components, not {}", xs.len()),
+            )),
+            _ => Err(self.expected(key, "a color")),
+        }
+    }
+
+    fn string(&self, key: &str) -> Result<&str, LibError> {
+        match &self.value {
+            Value::Str(s) => Ok(s),
+            _ => Err(self.expected(key, "a string")),
+        }
+    }
+
+    // A number above 0, like a length
+    fn positive(&self, key: &str) -> Result<f32, LibError> {
+        let x = self.num(key)?;
+        if x > 0.0 && x.is_finite() {
+            Ok(x)
+        } else {
+            Err(self.out_of_range(key, "above 0", x))
+        }
+    }
+
+    // A color of components above 0, like lengths per color channel
+    fn positive_color(&self, key: &str) -> Result<Vec3, LibError> {
+        let c = self.color(key)?;
+        if c.iter().all(|x| *x > 0.0 && x.is_finite()) {
+            Ok(c)
+        } else {
+            let c = format!("[{}, {}, {}]", c.x, c.y, c.z);
+            Err(self.out_of_range(key, "above 0", c))
+        }
+    }
+
+    // A number of at least 0, like a thickness
+    fn non_negative(&self, key: &str) -> Result<f32, LibError> {
+        let x = self.num(key)?;
+        if x >= 0.0 && x.is_finite() {
+            Ok(x)
+        } else {
+            Err(self.out_of_range(key, "at least 0", x))
+        }
+    }
+
+    // A color of components of at least 0, like coefficients per color
+    // channel
+    fn non_negative_color(&self, key: &str) -> Result<Vec3, LibError> {
+        let c = self.color(key)?;
+        if c.iter().all(|x| *x >= 0.0 && x.is_finite()) {
+            Ok(c)
+        } else {
+            let c = format!("[{}, {}, {}]", c.x, c.y, c.z);
+            Err(self.out_of_range(key, "at least 0", c))
+        }
+    }
+
+    // A number in [0, 1]
+    fn fraction(&self, key: &str) -> Result<f32, LibError> {
+        let x = self.num(key)?;
+        if (0.0..=1.0).contains(&x) {
+            Ok(x)
+        } else {
+            Err(self.out_of_range(key, "between 0 and 1", x))
+        }
+    }
+
+    // A color of components in [0, 1], like an albedo
+    fn fraction_color(&self, key: &str) -> Result<Vec3, LibError> {
+        let c = self.color(key)?;
+        if c.iter().all(|x| (0.0..=1.0).contains(x)) {
+            Ok(c)
+        } else {
+            let c = format!("[{}, {}, {}]", c.x, c.y, c.z);
+            Err(self.out_of_range(key, "between 0 and 1", c))
+        }
+    }
+
+    fn expected(&self, key: &str, what: &str) -> LibError {
+        invalid(self.line, format!("expected {} for `{}`", what, key))
+    }
+
+    fn out_of_range(
+        &self,
+        key: &str,
+        range: &str,
+        value: impl fmt::Display,
+    ) -> LibError {
+        invalid(
+            self.line,
+            format!("`{}` must be {}, not {}", key, range, value),
+        )
+    }
+}
+
+struct Table {
+    name: String,
+    line: usize,
+    params: HashMap<String, Entry>,
+}
+
+fn parse_tables(src: &str) -> Result<Vec<Table>, LibError> {
+    let mut tables: Vec<Table> = vec![];
+    for (i, raw) in src.lines().enumerate() {
+        let line = i + 1;
+        let s = strip_comment(raw).trim();
+        if s.is_empty() {
+            continue;
+        } else if s.starts_with('[') {
+            if !s.ends_with(']') {
+                return Err(invalid(line, "expected `]`".to_string()));
+            }
+            let name = parse_key(&s[1..s.len() - 1], line)?;
+            if tables.iter().any(|t| t.name == name) {
+                let msg = format!("`{}` is defined twice", name);
+                return Err(invalid(line, msg));
+            }
+            tables.push(Table {
+                name,
+                line,
+                params: HashMap::new(),
+            });
+        } else {
+            let eq = s.find('=').ok_or_else(|| {
+                invalid(line, "expected `key = value` or `[name]`".to_string())
+            })?;
+            let key = parse_key(&s[..eq], line)?;
+            let value = parse_value(s[eq + 1..].trim())
+                .map_err(|msg| invalid(line, msg))?;
+            let table = tables.last_mut().ok_or_else(|| {
+                let msg = format!("`{}` is outside of any [material]", key);
+                invalid(line, msg)
+            })?;
+            if table.params.contains_key(&key) {
+                return Err(invalid(line, format!("`{}` is set twice", key)));
+            }
+            table.params.insert(key, Entry { value, line });
+        }
+    }
+    Ok(tables)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_key(s: &str, line: usize) -> Result<String, LibError> {
+    let s = s.trim();
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    if s.is_empty() || !s.chars().all(valid) {
+        Err(invalid(line, format!("invalid name `{}`", s)))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    let num = |s: &str| s.trim().replace('_', "").parse::<f32>().ok();
+    if s.starts_with('"') {
+        let inner = &s[1..];
+        match inner.find('"') {
+            Some(end) if end == inner.len() - 1 => {
+                Ok(Value::Str(inner[..end].to_string()))
+            }
+            Some(_) => Err(format!("unexpected text after `{}`", s)),
+            None => Err("unterminated string".to_string()),
+        }
+    } else if s.starts_with('[') {
+        if !s.ends_with(']') {
+            return Err("expected `]`".to_string());
+        }
+        let mut items = s[1..s.len() - 1].split(',').collect::<Vec<_>>();
+        // Allow a trailing comma
+        if items.last().map_or(false, |x| x.trim().is_empty()) {
+            items.pop();
+        }
+        let xs = items
+            .iter()
+            .map(|x| {
+                num(x).ok_or_else(|| format!("invalid number `{}`", x.trim()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if xs.len() == 3 {
+            Ok(Value::Color(vec3(xs[0], xs[1], xs[2])))
+        } else {
+            Ok(Value::List(xs))
+        }
+    } else {
+        num(s)
+            .map(Value::Num)
+            .ok_or_else(|| format!("invalid value `{}`", s))
+    }
+}
+
+// Builds the materials of a library, resolving inheritance and references to
+// other materials. Tables are referred to by index.
+struct Builder {
+    tables: Vec<Table>,
+    built: HashMap<usize, Mat>,
+}
+
+impl Builder {
+    fn find(&self, name: &str) -> Option<usize> {
+        self.tables.iter().position(|t| t.name == name)
+    }
+
+    // Find the table named by `entry`, for the parameter `key`
+    fn find_entry(&self, key: &str, entry: &Entry) -> Result<usize, LibError> {
+        let name = entry.string(key)?;
+        self.find(name).ok_or_else(|| {
+            let msg = format!("no material or preset named `{}`", name);
+            invalid(entry.line, msg)
+        })
+    }
+
+    // The parameters of the table `i` and all those it inherits from.
+    // `chain` is the tables currently being resolved, to catch cycles.
+    fn resolve(
+        &self,
+        i: usize,
+        chain: &mut Vec<usize>,
+    ) -> Result<HashMap<String, Entry>, LibError> {
+        let t = &self.tables[i];
+        if chain.contains(&i) {
+            let msg = format!("`{}` inherits from itself", t.name);
+            return Err(invalid(t.line, msg));
+        }
+        chain.push(i);
+        let mut params = match t.params.get("inherits") {
+            Some(e) => {
+                let parent =
+                    self.resolve(self.find_entry("inherits", e)?, chain)?;
+                if let Some(ty) = t.params.get("type") {
+                    return Err(invalid(
+                        ty.line,
+                        "can't change the type of an inherited material"
+                            .to_string(),
+                    ));
+                }
+                parent
+            }
+            None => HashMap::new(),
+        };
+        chain.pop();
+        for (key, e) in &t.params {
+            if key != "inherits" {
+                params.insert(key.clone(), e.clone());
+            }
+        }
+        Ok(params)
+    }
+
+    // Build the material of table `i`. `chain` is the materials currently
+    // being built, to catch materials which are their own base.
+    fn build(
+        &mut self,
+        i: usize,
+        chain: &mut Vec<usize>,
+    ) -> Result<Mat, LibError> {
+        if let Some(m) = self.built.get(&i) {
+            return Ok(m.clone());
+        }
+        let line = self.tables[i].line;
+        if chain.contains(&i) {
+            let msg = format!("`{}` is its own base", self.tables[i].name);
+            return Err(invalid(line, msg));
+        }
+        chain.push(i);
+        let mut p = Params {
+            entries: self.resolve(i, &mut vec![])?,
+            used: HashSet::new(),
+        };
+        let ty = p.get("type").ok_or_else(|| {
+            invalid(line, "missing `type` or `inherits`".to_string())
+        })?;
+        let mat = match ty.string("type")? {
+            "lambertian" => {
+                let mut m = Lambertian {
+                    color: Vec3::repeat(0.8).into(),
+                };
+                p.set_color_tex("color", |c| m.color = c)?;
+                Mat::new(m)
+            }
+            "dielectric" => {
+                let mut m = Dielectric {
+                    color: Vec3::repeat(0.8).into(),
+                    fresnel: Vec3::repeat(0.04).into(),
+                    shininess: 0.0.into(),
+                };
+                p.set_color_tex("color", |c| m.color = c)?;
+                p.set_color_tex("fresnel", |c| m.fresnel = c)?;
+                p.check_constant("shininess", |e| {
+                    e.non_negative("shininess").map(drop)
+                })?;
+                p.set_num_tex("shininess", |x| m.shininess = x)?;
+                Mat::new(m)
+            }
+            "mirror" => {
+                let mut m = SpecularReflector {
+                    fresnel: Vec3::repeat(1.0).into(),
+                };
+                p.set_color_tex("fresnel", |c| m.fresnel = c)?;
+                Mat::new(m)
+            }
+            "principled" => {
+                let mut m = Principled::default();
+                p.set_color_tex("base_color", |c| m.base_color = c)?;
+                p.set_fraction_tex("metallic", |x| m.metallic = x)?;
+                p.set_fraction_tex("roughness", |x| m.roughness = x)?;
+                p.set_fraction_tex("specular", |x| m.specular = x)?;
+                p.set_fraction_tex("specular_tint", |x| m.specular_tint = x)?;
+                p.set_fraction_tex("sheen", |x| m.sheen = x)?;
+                p.set_fraction_tex("sheen_tint", |x| m.sheen_tint = x)?;
+                p.set_fraction_tex("clearcoat", |x| m.clearcoat = x)?;
+                p.set_fraction_tex("clearcoat_gloss", |x| {
+                    m.clearcoat_gloss = x
+                })?;
+                p.set_fraction_tex("transmission", |x| m.transmission = x)?;
+                p.set_fraction_tex("subsurface", |x| m.subsurface = x)?;
+                if let Some(ior) = p.ior()? {
+                    m.ior = ior;
+                }
+                Mat::new(m)
+            }
+            "conductor" => {
+                // Either one of the measured metals, or any `eta` and `k`
+                let mut m = match p.get("metal") {
+                    Some(e) => {
+                        let mut m = metal(&e)?;
+                        p.set_color("eta", |c| m.eta = c)?;
+                        p.set_color("k", |c| m.k = c)?;
+                        m
+                    }
+                    None => Conductor {
+                        roughness: 0.2.into(),
+                        eta: p.required(line, "eta")?.color("eta")?,
+                        k: p.required(line, "k")?.color("k")?,
+                        film: None,
+                    },
+                };
+                m.film = p.film()?;
+                p.set_fraction_tex("roughness", |x| m.roughness = x)?;
+                Mat::new(m)
+            }
+            "coat" => {
+                let coat = p.coat()?;
+                match p.get("absorption") {
+                    Some(e) => Mat::new(Absorbing {
+                        base: Mat::new(coat),
+                        absorption: e.non_negative_color("absorption")?,
+                    }),
+                    None => Mat::new(coat),
+                }
+            }
+            "layered" => {
+                let base = p.required(line, "base")?;
+                let mut m = Layered {
+                    coat: p.coat()?,
+                    base: self.build(self.find_entry("base", &base)?, chain)?,
+                    thickness: 0.0,
+                    absorption: Vec3::zeros().into(),
+                };
+                if let Some(e) = p.get("thickness") {
+                    m.thickness = e.non_negative("thickness")?;
+                }
+                p.check_constant("absorption", |e| {
+                    e.non_negative_color("absorption").map(drop)
+                })?;
+                p.set_color_tex("absorption", |c| m.absorption = c)?;
+                Mat::new(m)
+            }
+            "sheen" => {
+                let mut m = Sheen {
+                    color: Vec3::repeat(1.0).into(),
+                    roughness: 0.3.into(),
+                    base: None,
+                };
+                p.set_color_tex("color", |c| m.color = c)?;
+                p.set_fraction_tex("roughness", |x| m.roughness = x)?;
+                if let Some(base) = p.get("base") {
+                    let j = self.find_entry("base", &base)?;
+                    m.base = Some(self.build(j, chain)?);
+                }
+                Mat::new(m)
+            }
+            "subsurface" => {
+                let mut m = Subsurface {
+                    surface: p.coat()?,
+                    albedo: Vec3::repeat(0.8),
+                    mfp: Vec3::repeat(0.1),
+                };
+                if let Some(e) = p.get("albedo") {
+                    m.albedo = e.fraction_color("albedo")?;
+                }
+                if let Some(e) = p.get("mfp") {
+                    m.mfp = e.positive_color("mfp")?;
+                }
+                Mat::new(m)
+            }
+            "volume" => {
+                let mut m = Medium::fog(0.5, 0.0);
+                if let Some(e) = p.get("sigma_a") {
+                    m.sigma_a = e.non_negative_color("sigma_a")?;
+                }
+                if let Some(e) = p.get("sigma_s") {
+                    m.sigma_s = e.non_negative_color("sigma_s")?;
+                }
+                // Any closer to ±1 and the phase function is singular
+                if let Some(e) = p.get("g") {
+                    m.g = e.num("g")?;
+                    if !(-0.99..=0.99).contains(&m.g) {
+                        return Err(e.out_of_range(
+                            "g",
+                            "between -0.99 and 0.99",
+                            m.g,
+                        ));
+                    }
+                }
+                m.density = p.density()?;
+                Mat::new(Volume { medium: m })
+            }
+            other => {
+                let msg = format!("unknown material type `{}`", other);
+                return Err(invalid(ty.line, msg));
+            }
+        };
+        // Any type of material can be normal mapped and cut out
+        let mat = match p.get("normal_map") {
+            Some(e) => {
+                let path = e.string("normal_map")?;
+                let map = p.image(&e, |p| Image::load_data(p), path)?;
+                Mat::new(NormalMapped {
+                    base: mat,
+                    map: Tex::new(map),
+                })
+            }
+            None => mat,
+        };
+        let mat = match p.get("opacity") {
+            Some(e) => Mat::new(Cutout {
+                base: mat,
+                opacity: e.fraction("opacity")?.into(),
+            }),
+            None => mat,
+        };
+        p.check_unused(&ty)?;
+        chain.pop();
+        self.built.insert(i, mat.clone());
+        Ok(mat)
+    }
+}
+
+// The measured metal named by `e`, with the default roughness
+fn metal(e: &Entry) -> Result<Conductor, LibError> {
+    match e.string("metal")? {
+        "gold" => Ok(Conductor::gold(0.2)),
+        "copper" => Ok(Conductor::copper(0.2)),
+        "silver" => Ok(Conductor::silver(0.2)),
+        "aluminium" => Ok(Conductor::aluminium(0.2)),
+        _ => Err(invalid(
+            e.line,
+            "expected \"gold\", \"copper\", \"silver\", or \"aluminium\" \
+             for `metal`"
+                .to_string(),
+        )),
+    }
+}
+
+// The parameters of a material being built, keeping track of which are used
+struct Params {
+    entries: HashMap<String, Entry>,
+    used: HashSet<String>,
+}
+
+impl Params {
+    fn get(&mut self, key: &str) -> Option<Entry> {
+        self.used.insert(key.to_string());
+        self.entries.get(key).cloned()
+    }
+
+    fn required(&mut self, line: usize, key: &str) -> Result<Entry, LibError> {
+        self.get(key)
+            .ok_or_else(|| invalid(line, format!("missing `{}`", key)))
+    }
+
+    fn set_num(
+        &mut self,
+        key: &str,
+        set: impl FnOnce(f32),
+    ) -> Result<(), LibError> {
+        if let Some(e) = self.get(key) {
+            set(e.num(key)?)
+        }
+        Ok(())
+    }
+
+    fn set_color(
+        &mut self,
+        key: &str,
+        set: impl FnOnce(Vec3),
+    ) -> Result<(), LibError> {
+        if let Some(e) = self.get(key) {
+            set(e.color(key)?)
+        }
+        Ok(())
+    }
+
+    fn set_num_tex(
+        &mut self,
+        key: &str,
+        set: impl FnOnce(Tex<f32>),
+    ) -> Result<(), LibError> {
+        if let Some(e) = self.get(key) {
+            set(match &e.value {
+                Value::Str(path) => {
+                    Tex::new(self.image(&e, |p| Image::load_data(p), path)?)
+                }
+                _ => e.num(key)?.into(),
+            })
+        }
+        Ok(())
+    }
+
+    // Like `set_num_tex`, for numbers in [0, 1]. Images can't hold anything
+    // else.
+    fn set_fraction_tex(
+        &mut self,
+        key: &str,
+        set: impl FnOnce(Tex<f32>),
+    ) -> Result<(), LibError> {
+        self.check_constant(key, |e| e.fraction(key).map(drop))?;
+        self.set_num_tex(key, set)
+    }
+
+    // Check the value of `key` with `check`, if it's given and isn't the
+    // path of an image
+    fn check_constant(
+        &mut self,
+        key: &str,
+        check: impl FnOnce(&Entry) -> Result<(), LibError>,
+    ) -> Result<(), LibError> {
+        match self.get(key) {
+            Some(e) => match e.value {
+                Value::Str(_) => Ok(()),
+                _ => check(&e),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn set_color_tex(
+        &mut self,
+        key: &str,
+        set: impl FnOnce(Tex<Vec3>),
+    ) -> Result<(), LibError> {
+        if let Some(e) = self.get(key) {
+            set(match &e.value {
+                Value::Str(path) => {
+                    Tex::new(self.image(&e, |p| Image::load_color(p), path)?)
+                }
+                _ => e.color(key)?.into(),
+            })
+        }
+        Ok(())
+    }
+
+    // The image at `path`, set by `e`, as a texture
+    fn image(
+        &mut self,
+        e: &Entry,
+        load: impl FnOnce(&str) -> ImageResult<Image>,
+        path: &str,
+    ) -> Result<ImageTex, LibError> {
+        let image = load(path).map_err(|err| {
+            invalid(e.line, format!("can't load `{}`: {}", path, err))
+        })?;
+        Ok(ImageTex {
+            image: Arc::new(image),
+            wrap: self.wrap()?,
+        })
+    }
+
+    fn wrap(&mut self) -> Result<Wrap, LibError> {
+        match self.get("wrap") {
+            None => Ok(Wrap::Repeat),
+            Some(e) => match e.string("wrap")? {
+                "repeat" => Ok(Wrap::Repeat),
+                "mirror" => Ok(Wrap::Mirror),
+                "clamp" => Ok(Wrap::Clamp),
+                _ => Err(invalid(
+                    e.line,
+                    "expected \"repeat\", \"mirror\", or \"clamp\" for `wrap`"
+                        .to_string(),
+                )),
+            },
+        }
+    }
+
+    // Either a number, the coefficients `[a, b]` of Cauchy's equation, or the
+    // name of a kind of glass. It must be above 1 at every wavelength, as
+    // transmission is singular at exactly 1.
+    fn ior(&mut self) -> Result<Option<Ior>, LibError> {
+        let e = match self.get("ior") {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let ior = match &e.value {
+            Value::Num(x) => Some(Ior::Const(*x)),
+            Value::List(xs) if xs.len() == 2 => {
+                Some(Ior::Cauchy { a: xs[0], b: xs[1] })
+            }
+            Value::Str(s) if s == "bk7" => Some(Ior::bk7()),
+            Value::Str(s) if s == "dense_flint" => Some(Ior::dense_flint()),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            invalid(
+                e.line,
+                "expected a number, `[a, b]`, \"bk7\", or \"dense_flint\" \
+                 for `ior`"
+                    .to_string(),
+            )
+        })?;
+        // Cauchy's equation is monotonic, so the ends of the range are enough
+        let lowest = ior.at(Some(LAMBDA_MIN)).min(ior.at(Some(LAMBDA_MAX)));
+        if lowest.is_finite() && lowest > 1.0 {
+            Ok(Some(ior))
+        } else {
+            Err(e.out_of_range("ior", "above 1", lowest))
+        }
+    }
+
+    // The density of a heterogeneous volume, if `density` is given, either
+    // as "noise" for clouds, or as the path of a voxel grid file. It fills
+    // the box of half side `size` around the centre of the object.
+    fn density(&mut self) -> Result<Option<Density>, LibError> {
+        let e = match self.get("density") {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let mut size = 1.0;
+        if let Some(e) = self.get("size") {
+            size = e.positive("size")?;
+        }
+        let density = match e.string("density")? {
+            "noise" => {
+                let mut scale = 2.0;
+                let mut octaves = 5;
+                let mut threshold = 0.3;
+                if let Some(e) = self.get("scale") {
+                    scale = e.positive("scale")?;
+                }
+                if let Some(e) = self.get("octaves") {
+                    let x = e.num("octaves")?;
+                    if x < 1.0 || x.fract() != 0.0 {
+                        return Err(e.out_of_range(
+                            "octaves",
+                            "a whole number of at least 1",
+                            x,
+                        ));
+                    }
+                    octaves = x as usize;
+                }
+                // All clear air at 1
+                if let Some(e) = self.get("threshold") {
+                    threshold = e.num("threshold")?;
+                    if !(0.0..1.0).contains(&threshold) {
+                        return Err(e.out_of_range(
+                            "threshold",
+                            "at least 0 and below 1",
+                            threshold,
+                        ));
+                    }
+                }
+                let noise = NoiseDensity::new(scale, octaves, threshold);
+                Density::new(noise, size)
+            }
+            path => {
+                let grid = VoxelGrid::load(path).map_err(|err| {
+                    invalid(e.line, format!("can't load `{}`: {}", path, err))
+                })?;
+                Density::new(grid, size)
+            }
+        };
+        Ok(Some(density))
+    }
+
+    // A thin film, if `film_thickness` is given
+    fn film(&mut self) -> Result<Option<ThinFilm>, LibError> {
+        let ior = match self.get("film_ior") {
+            Some(e) => e.positive("film_ior")?,
+            None => 1.5,
+        };
+        match self.get("film_thickness") {
+            Some(e) => Ok(Some(ThinFilm {
+                thickness: e.non_negative("film_thickness")?.into(),
+                ior,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn coat(&mut self) -> Result<Coat, LibError> {
+        let mut coat = Coat {
+            roughness: 0.0.into(),
+            ior: 1.5.into(),
+            film: self.film()?,
+        };
+        self.set_fraction_tex("roughness", |x| coat.roughness = x)?;
+        if let Some(ior) = self.ior()? {
+            coat.ior = ior;
+        }
+        Ok(coat)
+    }
+
+    // Report the first parameter, by line, that the type doesn't have
+    fn check_unused(&self, ty: &Entry) -> Result<(), LibError> {
+        let unused = self
+            .entries
+            .iter()
+            .filter(|(key, _)| !self.used.contains(*key))
+            .min_by_key(|(_, e)| e.line);
+        match unused {
+            Some((key, e)) => Err(invalid(
+                e.line,
+                format!(
+                    "unknown parameter `{}` for type `{}`",
+                    key,
+                    ty.string("type")?
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,268 @@
+// Checks of the parsing of material libraries, and of the errors reported for
+// broken ones
+
+use nalgebra_glm::{vec2, vec3, Vec3};
+use std::f32::consts::FRAC_1_PI;
+
+use super::*;
+use crate::intersect::*;
+
+fn hit(mat: Mat) -> Hit {
+    let normal = vec3(0.0, 0.0, 1.0);
+    Hit {
+        t: 1.0,
+        normal,
+        geo_normal: normal,
+        pos: Vec3::zeros(),
+        local_pos: normal,
+        uv: vec2(0.5, 0.5),
+        dpdu: vec3(1.0, 0.0, 0.0),
+        dpdv: vec3(0.0, 1.0, 0.0),
+        wavelength: None,
+        object: 0,
+        mat,
+    }
+}
+
+// The color of a diffuse material, from its BRDF
+fn diffuse_color(mat: Mat) -> Vec3 {
+    let n = vec3(0.0, 0.0, 1.0);
+    mat.eval(n, n, &hit(mat.clone())) / FRAC_1_PI
+}
+
+fn assert_close(a: Vec3, b: Vec3) {
+    assert!((a - b).amax() < 1e-5, "{:?} != {:?}", a, b);
+}
+
+// Parse `src`, which must fail with `msg` at `line`
+fn assert_error(src: &str, line: usize, msg: &str) {
+    match MatLib::parse(src) {
+        Err(LibError::Invalid { line: l, msg: m }) => {
+            assert_eq!((l, m.as_str()), (line, msg), "for:\n{}", src)
+        }
+        Err(e) => panic!("unexpected error {} for:\n{}", e, src),
+        Ok(_) => panic!("no error for:\n{}", src),
+    }
+}
+
+#[test]
+fn inherits_and_overrides() {
+    let lib = MatLib::parse(
+        r#"
+        # A comment
+        [red]   # Another one
+        inherits = "diffuse"
+        color = [0.8, 0.1, 0.1,]
+
+        [dark_red]
+        inherits = "red"
+        color = [0.4, 0.05, 0.05]
+
+        [gray]
+        type = "lambertian"
+        color = 0.5
+        "#,
+    )
+    .unwrap();
+    assert_close(diffuse_color(lib.get("red")), vec3(0.8, 0.1, 0.1));
+    assert_close(diffuse_color(lib.get("dark_red")), vec3(0.4, 0.05, 0.05));
+    assert_close(diffuse_color(lib.get("gray")), Vec3::repeat(0.5));
+    assert_close(diffuse_color(lib.get("diffuse")), Vec3::repeat(0.8));
+}
+
+#[test]
+fn every_preset_builds() {
+    let src = PRESETS
+        .lines()
+        .filter(|l| l.starts_with('['))
+        .map(|l| {
+            let name = &l[1..l.len() - 1];
+            format!("[my_{}]\ninherits = \"{}\"\n", name, name)
+        })
+        .collect::<String>();
+    MatLib::parse(&src).unwrap();
+}
+
+#[test]
+fn mirror_is_perfectly_smooth() {
+    let lib = MatLib::presets();
+    assert_eq!(lib.get("mirror").lobes(), Lobes::DELTA);
+}
+
+#[test]
+fn missing_material_is_magenta() {
+    let lib = MatLib::presets();
+    assert_close(diffuse_color(lib.get("nothing")), vec3(1.0, 0.0, 1.0));
+}
+
+#[test]
+fn syntax_errors() {
+    assert_error("[a", 1, "expected `]`");
+    assert_error("[a b]", 1, "invalid name `a b`");
+    assert_error("\ncolor = 1", 2, "`color` is outside of any [material]");
+    assert_error("[a]\ncolor", 2, "expected `key = value` or `[name]`");
+    assert_error("[a]\ntype = \"lambertian", 2, "unterminated string");
+    assert_error(
+        "[a]\ntype = \"lambertian\"\ncolor = [1, 2]",
+        3,
+        "a color has three components, not 2",
+    );
+    assert_error("[a]\ncolor = [1, x, 2]", 2, "invalid number `x`");
+    assert_error("[a]\ncolor = red", 2, "invalid value `red`");
+}
+
+#[test]
+fn definition_errors() {
+    assert_error("[a]\ntype = \"lambertian\"\n[a]", 3, "`a` is defined twice");
+    assert_error(
+        "[a]\ntype = \"lambertian\"\ncolor = 1\ncolor = 2",
+        4,
+        "`color` is set twice",
+    );
+    assert_error(
+        "[gold]\ntype = \"lambertian\"",
+        1,
+        "`gold` is already the name of a preset",
+    );
+    assert_error("[a]\ncolor = 1", 1, "missing `type` or `inherits`");
+    assert_error(
+        "[a]\ntype = \"plasma\"",
+        2,
+        "unknown material type `plasma`",
+    );
+    assert_error(
+        "[a]\ntype = \"lambertian\"\nshininess = 2",
+        3,
+        "unknown parameter `shininess` for type `lambertian`",
+    );
+    assert_error("[a]\ntype = 1", 2, "expected a string for `type`");
+    assert_error(
+        "[a]\ntype = \"conductor\"\nmetal = \"tin\"",
+        3,
+        "expected \"gold\", \"copper\", \"silver\", or \"aluminium\" for \
+         `metal`",
+    );
+    assert_error("[a]\ntype = \"conductor\"", 1, "missing `eta`");
+}
+
+#[test]
+fn value_errors() {
+    assert_error(
+        "[a]\ntype = \"layered\"\nbase = \"diffuse\"\nior = 0.9",
+        4,
+        "`ior` must be above 1, not 0.9",
+    );
+    assert_error(
+        "[a]\ntype = \"coat\"\nior = [1, 0]",
+        3,
+        "`ior` must be above 1, not 1",
+    );
+    assert_error(
+        "[a]\ntype = \"coat\"\nior = [1.5, 0.01, 0]",
+        3,
+        "expected a number, `[a, b]`, \"bk7\", or \"dense_flint\" for `ior`",
+    );
+    assert_error(
+        "[a]\ntype = \"subsurface\"\nmfp = [0.1, 0, 0.1]",
+        3,
+        "`mfp` must be above 0, not [0.1, 0, 0.1]",
+    );
+    assert_error(
+        "[a]\ntype = \"subsurface\"\nmfp = -1",
+        3,
+        "`mfp` must be above 0, not [-1, -1, -1]",
+    );
+    assert_error(
+        "[a]\ninherits = \"diffuse\"\nopacity = 1.5",
+        3,
+        "`opacity` must be between 0 and 1, not 1.5",
+    );
+    assert_error(
+        "[a]\ntype = \"volume\"\ndensity = \"noise\"\nsize = 0",
+        4,
+        "`size` must be above 0, not 0",
+    );
+    assert_error(
+        "[a]\ntype = \"volume\"\nsigma_s = [1, -0.5, 1]",
+        3,
+        "`sigma_s` must be at least 0, not [1, -0.5, 1]",
+    );
+    assert_error(
+        "[a]\ntype = \"volume\"\ng = 1",
+        3,
+        "`g` must be between -0.99 and 0.99, not 1",
+    );
+    assert_error(
+        "[a]\ntype = \"volume\"\ndensity = \"noise\"\nthreshold = 1",
+        4,
+        "`threshold` must be at least 0 and below 1, not 1",
+    );
+    assert_error(
+        "[a]\ninherits = \"metal\"\nmetallic = 2",
+        3,
+        "`metallic` must be between 0 and 1, not 2",
+    );
+    assert_error(
+        "[a]\ninherits = \"gold\"\nroughness = -0.1",
+        3,
+        "`roughness` must be between 0 and 1, not -0.1",
+    );
+    assert_error(
+        "[a]\ntype = \"layered\"\nbase = \"diffuse\"\nthickness = -1",
+        4,
+        "`thickness` must be at least 0, not -1",
+    );
+    assert_error(
+        "[a]\ntype = \"coat\"\nfilm_thickness = -300",
+        3,
+        "`film_thickness` must be at least 0, not -300",
+    );
+    assert_error(
+        "[a]\ntype = \"subsurface\"\nalbedo = [0.5, 1.5, 0.5]",
+        3,
+        "`albedo` must be between 0 and 1, not [0.5, 1.5, 0.5]",
+    );
+}
+
+#[test]
+fn reference_errors() {
+    assert_error(
+        "[a]\ninherits = \"b\"",
+        2,
+        "no material or preset named `b`",
+    );
+    assert_error(
+        "[a]\ninherits = \"b\"\n[b]\ninherits = \"a\"",
+        1,
+        "`a` inherits from itself",
+    );
+    assert_error(
+        "[a]\ninherits = \"diffuse\"\ntype = \"lambertian\"",
+        3,
+        "can't change the type of an inherited material",
+    );
+    assert_error(
+        "[a]\ntype = \"layered\"\nbase = \"b\"\n[b]\ntype = \"layered\"\n\
+         base = \"a\"",
+        1,
+        "`a` is its own base",
+    );
+}
+
+// Cauchy's equation for the IOR, by its coefficients
+#[test]
+fn cauchy_ior() {
+    let lib = MatLib::parse(
+        "[a]\ntype = \"coat\"\nroughness = 0.3\nior = [1.5, 0.0042]",
+    )
+    .unwrap();
+    let mat = lib.get("a");
+    let n = vec3(0.0, 0.0, 1.0);
+    let mut h = hit(mat.clone());
+    // Glass reflects more of blue light, of a higher IOR
+    h.wavelength = Some(450.0);
+    let blue = mat.eval(n, n, &h);
+    h.wavelength = Some(650.0);
+    let red = mat.eval(n, n, &h);
+    assert!(blue.x > red.x, "{:?} <= {:?}", blue, red);
+}
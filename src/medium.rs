@@ -0,0 +1,463 @@
+use nalgebra_glm as glm;
+use nalgebra_glm::{vec3, Vec3};
+use rand::prelude::*;
+use std::f32::consts::PI;
+
+use crate::density::*;
+use crate::intersect::*;
+use crate::material::*;
+use crate::spectrum::*;
+
+// Max depth of nested media, e.g. ice in a drink in a glass
+const MAX_NESTED_MEDIA: usize = 4;
+// The absorption coefficient of fog, relative to its scattering coefficient
+const FOG_ABSORPTION: f32 = 0.1;
+
+// A participating medium, e.g. the inside of a translucent object, fog, or a
+// cloud. It's homogeneous unless it has a density.
+#[derive(Clone)]
+pub struct Medium {
+    // Absorption coefficient. Probability per unit distance that light is
+    // absorbed.
+    pub sigma_a: Vec3,
+    // Scattering coefficient. Probability per unit distance that light
+    // changes direction.
+    pub sigma_s: Vec3,
+    // The anisotropy of the Henyey-Greenstein phase function, the mean
+    // cosine of the angle light is scattered by. 0 scatters evenly in all
+    // directions, positive values mostly forwards, like fog and clouds do,
+    // and negative values mostly backwards.
+    pub g: f32,
+    // How thick the medium is from point to point, scaling the coefficients
+    // above, which are those where the density is 1
+    pub density: Option<Density>,
+}
+
+// The result of sampling a distance along a ray through a medium.
+pub enum MediumSample {
+    // The ray scatters at distance `t`, before reaching the surface.
+    Scattered { t: f32, weight: Vec3 },
+    // The ray makes it through to the surface.
+    Passed { weight: Vec3 },
+}
+
+impl Medium {
+    // A medium that only absorbs light, attenuating it according to the
+    // Beer-Lambert law, like colored glass or liquid.
+    pub fn absorbing(sigma_a: Vec3) -> Self {
+        Medium {
+            sigma_a,
+            sigma_s: Vec3::zeros(),
+            g: 0.0,
+            density: None,
+        }
+    }
+
+    // Gray fog, which scatters light with a probability of `density` per
+    // unit distance, by the anisotropy `g`. A little of the light is
+    // absorbed, so that it doesn't scatter forever.
+    pub fn fog(density: f32, g: f32) -> Self {
+        Medium {
+            sigma_a: Vec3::repeat(FOG_ABSORPTION * density),
+            sigma_s: Vec3::repeat(density),
+            g,
+            density: None,
+        }
+    }
+
+    // A medium which scatters light with a mean free path of `mfp`, so that
+    // the object as a whole appears to have the color `albedo`.
+    //
+    // Uses the mapping from multiple-scattering albedo to single-scattering
+    // albedo of Chiang et al. "Practical and Controllable Subsurface
+    // Scattering for Production Path Tracing" (2016).
+    pub fn subsurface(albedo: Vec3, mfp: Vec3) -> Self {
+        let single_albedo = albedo.map(|a| {
+            let s = 4.09712 + 4.20863 * a
+                - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+            1.0 - s * s
+        });
+        let sigma_t = mfp.map(|l| 1.0 / l);
+        Medium {
+            sigma_a: sigma_t
+                .component_mul(&(Vec3::repeat(1.0) - single_albedo)),
+            sigma_s: sigma_t.component_mul(&single_albedo),
+            g: 0.0,
+            density: None,
+        }
+    }
+
+    // The medium as seen by a spectral path with the given wavelengths, or
+    // as is for an RGB path.
+    pub fn upsampled(&self, wavelengths: Option<Vec3>) -> Self {
+        Medium {
+            sigma_a: upsample(self.sigma_a, wavelengths),
+            sigma_s: upsample(self.sigma_s, wavelengths),
+            g: self.g,
+            density: self.density.clone(),
+        }
+    }
+
+    // The medium inside of the object hit at `hit`, with its density centred
+    // on the object
+    pub fn placed(self, hit: &Hit) -> Self {
+        let origin = hit.pos - hit.local_pos;
+        Medium {
+            density: self.density.map(|d| d.placed(origin)),
+            ..self
+        }
+    }
+
+    pub fn sigma_t(&self) -> Vec3 {
+        self.sigma_a + self.sigma_s
+    }
+
+    // The density of the medium at the point `p`
+    pub fn density_at(&self, p: Vec3) -> f32 {
+        self.density.as_ref().map_or(1.0, |d| d.at(p))
+    }
+
+    // Transmittance along `ray` over a distance `t`.
+    //
+    // Through a heterogeneous medium, it's estimated by ratio tracking, after
+    // Novák et al. "Residual Ratio Tracking for Estimating Attenuation in
+    // Participating Media" (2014). Tentative collisions are sampled by the
+    // majorant, the max of the extinction coefficient, and each one lets
+    // through the fraction of it that's only made up, the null collisions.
+    pub fn tr(&self, rng: &mut dyn RngCore, ray: &Ray, t: f32) -> Vec3 {
+        let density = match &self.density {
+            Some(density) => density,
+            None => return (-self.sigma_t() * t).map(f32::exp),
+        };
+        let sigma_t = self.sigma_t();
+        let sigma_max = glm::comp_max(&sigma_t);
+        let mut tr = Vec3::repeat(1.0);
+        density.segments(ray, t, |t0, t1, max_density| {
+            let majorant = sigma_max * max_density;
+            if majorant <= 0.0 {
+                return true;
+            }
+            let mut t = t0;
+            loop {
+                t -= (1.0 - rand(rng)).ln() / majorant;
+                if t >= t1 {
+                    return true;
+                }
+                let d = density.at(ray.origin + t * ray.dir);
+                tr = tr.component_mul(
+                    &(Vec3::repeat(1.0) - sigma_t * (d / majorant)),
+                );
+                if tr == Vec3::zeros() {
+                    return false;
+                }
+            }
+        });
+        tr
+    }
+
+    // Sample a distance along `ray` to the next scattering event, where
+    // `t_max` is the distance to the next surface along the ray.
+    //
+    // In a homogeneous medium, the coefficients may differ per color
+    // channel, so a channel is picked at random to sample by, and the pdf is
+    // the average over all channels.
+    pub fn sample(
+        &self,
+        rng: &mut dyn RngCore,
+        ray: &Ray,
+        t_max: f32,
+    ) -> MediumSample {
+        if self.sigma_s == Vec3::zeros() {
+            // Nothing to scatter off, so just attenuate
+            return MediumSample::Passed {
+                weight: self.tr(rng, ray, t_max),
+            };
+        }
+        if let Some(density) = &self.density {
+            return self.track(rng, density, ray, t_max);
+        }
+        let sigma_t = self.sigma_t();
+        let channel = ((rand(rng) * 3.0) as usize).min(2);
+        let t = -(1.0 - rand(rng)).ln() / sigma_t[channel];
+        if t < t_max {
+            let tr = self.tr(rng, ray, t);
+            let pdf = mean(sigma_t.component_mul(&tr));
+            MediumSample::Scattered {
+                t,
+                weight: self.sigma_s.component_mul(&tr) / pdf,
+            }
+        } else {
+            let tr = self.tr(rng, ray, t_max);
+            MediumSample::Passed {
+                weight: tr / mean(tr),
+            }
+        }
+    }
+
+    // Sampling through a heterogeneous medium, by weighted delta tracking.
+    // Tentative collisions are sampled by the majorant as for ratio tracking,
+    // and each is picked to be either a real scattering event or a null
+    // collision to go on from, by how much of the majorant each makes up.
+    // Absorption is left to the weights. The majorant is the same for all
+    // channels, so the weights make up for the channels where collisions of
+    // either kind are more or less likely than picked. Null collisions may
+    // also come out negative where a density exceeds its max, and are then
+    // picked by their magnitude.
+    fn track(
+        &self,
+        rng: &mut dyn RngCore,
+        density: &Density,
+        ray: &Ray,
+        t_max: f32,
+    ) -> MediumSample {
+        let sigma_t = self.sigma_t();
+        let sigma_max = glm::comp_max(&sigma_t);
+        let mut weight = Vec3::repeat(1.0);
+        let mut scattered = None;
+        density.segments(ray, t_max, |t0, t1, max_density| {
+            let majorant = sigma_max * max_density;
+            if majorant <= 0.0 {
+                return true;
+            }
+            let mut t = t0;
+            loop {
+                t -= (1.0 - rand(rng)).ln() / majorant;
+                if t >= t1 {
+                    return true;
+                }
+                let d = density.at(ray.origin + t * ray.dir);
+                let sigma_s = self.sigma_s * d;
+                let sigma_n = Vec3::repeat(majorant) - sigma_t * d;
+                let p_s = mean(sigma_s);
+                let p_n = mean(sigma_n.abs());
+                if p_s + p_n <= 0.0 {
+                    // Nothing but absorption
+                    weight = Vec3::zeros();
+                    return false;
+                }
+                if rand(rng) * (p_s + p_n) < p_s {
+                    weight = weight.component_mul(&sigma_s)
+                        * ((p_s + p_n) / (majorant * p_s));
+                    scattered = Some(t);
+                    return false;
+                }
+                weight = weight.component_mul(&sigma_n)
+                    * ((p_s + p_n) / (majorant * p_n));
+            }
+        });
+        match scattered {
+            Some(t) => MediumSample::Scattered { t, weight },
+            None => MediumSample::Passed { weight },
+        }
+    }
+
+    // The density of light traveling along `dir` being scattered into
+    // `new_dir`, per unit solid angle
+    pub fn phase(&self, dir: Vec3, new_dir: Vec3) -> f32 {
+        let g = self.g;
+        let denom = 1.0 + g * g - 2.0 * g * dir.dot(&new_dir);
+        (1.0 - g * g) / (4.0 * PI * denom * denom.max(0.0).sqrt())
+    }
+
+    // Sample a new direction after scattering, by the phase function, for
+    // light traveling along `dir`. The weight of the sample is 1.
+    pub fn sample_phase(&self, rng: &mut dyn RngCore, dir: Vec3) -> Vec3 {
+        let g = self.g;
+        if g.abs() < 1e-3 {
+            // Isotropic
+            let z = 1.0 - 2.0 * rand(rng);
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            let phi = 2.0 * PI * rand(rng);
+            return vec3(r * phi.cos(), r * phi.sin(), z);
+        }
+        // Inverting the CDF of the cosine of the scattering angle
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * rand(rng));
+        let cos_theta = ((1.0 + g * g - s * s) / (2.0 * g)).max(-1.0).min(1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * rand(rng);
+        Frame::new(dir).to_world(vec3(
+            sin_theta * phi.cos(),
+            sin_theta * phi.sin(),
+            cos_theta,
+        ))
+    }
+}
+
+// The media a ray is inside of, innermost last. Objects may overlap or be
+// nested, so on leaving one we can't just go back to vacuum.
+//
+// An entry is kept for every object entered, even those without a medium, so
+// that e.g. a clear glass bead in colored liquid isn't colored inside.
+//
+// Outside of all objects, the ray is in the medium filling the scene, if any,
+// like fog.
+#[derive(Clone)]
+pub struct MediumStack {
+    // The objects entered, their media, and whether their surfaces are
+    // invisible
+    entries: [(usize, Option<Medium>, bool); MAX_NESTED_MEDIA],
+    len: usize,
+    outside: Option<Medium>,
+}
+
+impl MediumStack {
+    pub fn new() -> Self {
+        MediumStack {
+            entries: Default::default(),
+            len: 0,
+            outside: None,
+        }
+    }
+
+    // Outside of all objects, in the medium `outside`
+    pub fn in_medium(outside: Option<Medium>) -> Self {
+        MediumStack {
+            outside,
+            ..MediumStack::new()
+        }
+    }
+
+    // The medium the ray is currently in, if not vacuum
+    pub fn current(&self) -> Option<Medium> {
+        if self.len == 0 {
+            self.outside.clone()
+        } else {
+            self.entries[self.len - 1].1.clone()
+        }
+    }
+
+    // Whether the light source, which is outside of all objects, can shine
+    // into the current medium. Only the invisible surfaces of the objects
+    // entered let its light through.
+    pub fn lit(&self) -> bool {
+        self.entries[..self.len]
+            .iter()
+            .all(|&(_, _, invisible)| invisible)
+    }
+
+    // Enter the object with index `object`, whose surface may be invisible.
+    // If nested too deep, the object is ignored and the ray stays in the
+    // current medium.
+    pub fn enter(
+        &mut self,
+        object: usize,
+        medium: Option<Medium>,
+        invisible: bool,
+    ) {
+        if self.len < MAX_NESTED_MEDIA {
+            self.entries[self.len] = (object, medium, invisible);
+            self.len += 1;
+        }
+    }
+
+    // Leave the object with index `object`. It's not necessarily the
+    // innermost, as objects may overlap.
+    pub fn exit(&mut self, object: usize) {
+        let entered = &self.entries[..self.len];
+        if let Some(i) = entered.iter().rposition(|&(o, _, _)| o == object) {
+            self.entries[i..self.len].rotate_left(1);
+            self.len -= 1;
+        }
+    }
+}
+
+// Gives a transmissive material an absorbing interior, e.g. for colored glass
+// or sunglasses. The absorption coefficient is per unit distance, so light
+// traveling a distance `d` through the object is attenuated by `exp(-σ d)`.
+// Thick parts of an object come out darker and more saturated than thin ones.
+#[derive(Clone)]
+pub struct Absorbing {
+    pub base: Mat,
+    pub absorption: Vec3,
+}
+
+impl Bsdf for Absorbing {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        self.base.eval(wi, wo, hit)
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        self.base.sample(rng, wo, hit)
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        self.base.pdf(wi, wo, hit)
+    }
+
+    fn lobes(&self) -> Lobes {
+        self.base.lobes()
+    }
+
+    fn shading_normal(&self, hit: &Hit) -> Vec3 {
+        self.base.shading_normal(hit)
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        Some(Medium::absorbing(self.absorption))
+    }
+
+    fn dispersive(&self) -> bool {
+        self.base.dispersive()
+    }
+
+    fn opacity(&self, hit: &Hit) -> f32 {
+        self.base.opacity(hit)
+    }
+
+    fn invisible(&self) -> bool {
+        self.base.invisible()
+    }
+}
+
+// An object made of nothing but a medium, like a cloud or a puff of smoke. The
+// surface only bounds the medium, and lets light through unchanged, also
+// light on its way to be scattered in the medium. See `Bsdf::invisible`.
+#[derive(Clone)]
+pub struct Volume {
+    pub medium: Medium,
+}
+
+impl Bsdf for Volume {
+    fn eval(&self, _wi: Vec3, _wo: Vec3, _hit: &Hit) -> Vec3 {
+        Vec3::zeros()
+    }
+
+    fn sample(&self, _: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        // Cancels out the cosine term
+        let cos = wo.dot(&hit.normal).abs();
+        DirSample {
+            wi: -wo,
+            pdf: 1.0,
+            brdf: Vec3::repeat(if cos > 0.0 { 1.0 / cos } else { 0.0 }),
+            lobe: Lobes::DELTA | Lobes::TRANSMISSION,
+        }
+    }
+
+    fn pdf(&self, _wi: Vec3, _wo: Vec3, _hit: &Hit) -> f32 {
+        0.0
+    }
+
+    fn lobes(&self) -> Lobes {
+        Lobes::DELTA | Lobes::TRANSMISSION
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        Some(self.medium.clone())
+    }
+
+    fn invisible(&self) -> bool {
+        true
+    }
+}
+
+// The medium inside of the object hit at `hit`, if any, for a path with the
+// given wavelengths
+pub fn interior_of(hit: &Hit, wavelengths: Option<Vec3>) -> Option<Medium> {
+    hit.mat
+        .interior()
+        .map(|m| m.upsampled(wavelengths).placed(hit))
+}
+
+fn mean(v: Vec3) -> f32 {
+    (v.x + v.y + v.z) / 3.0
+}
@@ -0,0 +1,335 @@
+// Building blocks for microfacet based BSDFs.
+//
+// All directions are in the local shading frame of a `Frame`, i.e. the normal
+// is the z-axis.
+
+use nalgebra_glm::{vec3, Vec3};
+use std::f32::consts::{FRAC_1_PI, PI};
+
+use crate::material::*;
+use crate::texture::*;
+
+// The narrowest GGX distribution used. Surfaces any smoother than this are
+// perfectly smooth, and scatter in delta lobes instead, as the peak of such a
+// narrow distribution would just make fireflies of any light seen in it.
+pub const MIN_ALPHA: f32 = 0.001;
+
+pub fn cos_theta(w: Vec3) -> f32 {
+    w.z
+}
+
+// The GGX `alpha` of a surface of perceptual `roughness`
+pub fn ggx_alpha(roughness: f32) -> f32 {
+    (roughness * roughness).max(MIN_ALPHA)
+}
+
+pub fn is_smooth(roughness: f32) -> bool {
+    roughness * roughness < MIN_ALPHA
+}
+
+// The kind of lobe of a GGX distribution of the possibly textured
+// `roughness`. Delta where the surface is smooth, and glossy elsewhere.
+pub fn ggx_lobes(roughness: &Tex<f32>) -> Lobes {
+    match roughness.constant() {
+        Some(r) if is_smooth(r) => Lobes::DELTA,
+        Some(_) => Lobes::GLOSSY,
+        None => Lobes::DELTA | Lobes::GLOSSY,
+    }
+}
+
+// The Trowbridge-Reitz (GGX) distribution of microfacet normals.
+pub fn ggx_d(wh: Vec3, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let c2 = cos_theta(wh) * cos_theta(wh);
+    let denom = c2 * (a2 - 1.0) + 1.0;
+    a2 * FRAC_1_PI / (denom * denom)
+}
+
+// Smith's masking function for the GGX distribution.
+pub fn ggx_g1(w: Vec3, alpha: f32) -> f32 {
+    let c = cos_theta(w).abs();
+    let a2 = alpha * alpha;
+    2.0 * c / (c + (a2 + (1.0 - a2) * c * c).sqrt())
+}
+
+// Separable masking-shadowing.
+pub fn ggx_g(wi: Vec3, wo: Vec3, alpha: f32) -> f32 {
+    ggx_g1(wi, alpha) * ggx_g1(wo, alpha)
+}
+
+// Sample a microfacet normal in the upper hemisphere, proportional to
+// `ggx_d(wh) * cos(θ_h)`.
+pub fn ggx_sample_wh(alpha: f32, u1: f32, u2: f32) -> Vec3 {
+    let phi = 2.0 * PI * u1;
+    let tan2_theta = alpha * alpha * u2 / (1.0 - u2).max(1e-7);
+    let cos_theta = 1.0 / (1.0 + tan2_theta).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn ggx_pdf_wh(wh: Vec3, alpha: f32) -> f32 {
+    ggx_d(wh, alpha) * cos_theta(wh).abs()
+}
+
+// The "generalized Trowbridge-Reitz" distribution with γ = 1, which has a
+// longer tail than GGX. Used for clearcoats.
+pub fn gtr1_d(wh: Vec3, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let c2 = cos_theta(wh) * cos_theta(wh);
+    (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * c2))
+}
+
+pub fn gtr1_sample_wh(alpha: f32, u1: f32, u2: f32) -> Vec3 {
+    let a2 = alpha * alpha;
+    let phi = 2.0 * PI * u1;
+    let cos_theta = ((1.0 - a2.powf(1.0 - u2)) / (1.0 - a2)).max(0.0).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    vec3(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+pub fn gtr1_pdf_wh(wh: Vec3, alpha: f32) -> f32 {
+    gtr1_d(wh, alpha) * cos_theta(wh).abs()
+}
+
+// The "Charlie" distribution of Estevez & Kulla, "Production Friendly
+// Microfacet Sheen BRDF" (2017). Models the fibers of cloth, which mostly
+// face sideways, so the highlight is at grazing angles instead.
+pub fn charlie_d(wh: Vec3, alpha: f32) -> f32 {
+    let sin_theta = (1.0 - cos_theta(wh) * cos_theta(wh)).max(0.0).sqrt();
+    (2.0 + 1.0 / alpha) * sin_theta.powf(1.0 / alpha) / (2.0 * PI)
+}
+
+// Visibility term to go with `charlie_d`, from Neubelt & Pettineo "Crafting a
+// Next-Gen Material Pipeline for The Order: 1886" (2013). Includes the usual
+// `1 / (4 cos_i cos_o)` of a microfacet BRDF.
+pub fn sheen_visibility(wi: Vec3, wo: Vec3) -> f32 {
+    let (ci, co) = (cos_theta(wi).abs(), cos_theta(wo).abs());
+    1.0 / (4.0 * (ci + co - ci * co))
+}
+
+// Converts the pdf of sampling a microfacet normal to the pdf of the
+// direction reflected by it.
+pub fn reflected_pdf(pdf_wh: f32, wo: Vec3, wh: Vec3) -> f32 {
+    pdf_wh / (4.0 * wo.dot(&wh).abs())
+}
+
+pub fn reflect(wo: Vec3, wh: Vec3) -> Vec3 {
+    2.0 * wo.dot(&wh) * wh - wo
+}
+
+// Refract `wo` through a surface with normal `wh` on the same side as `wo`.
+// `eta` is the ratio of the index of refraction on the other side to that on
+// the side of `wo`. Returns `None` on total internal reflection.
+pub fn refract(wo: Vec3, wh: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = wo.dot(&wh);
+    let sin2_i = (1.0 - cos_i * cos_i).max(0.0);
+    let sin2_t = sin2_i / (eta * eta);
+    if sin2_t >= 1.0 {
+        None
+    } else {
+        let cos_t = (1.0 - sin2_t).sqrt();
+        Some(-wo / eta + (cos_i / eta - cos_t) * wh)
+    }
+}
+
+// Fresnel reflectance of an interface between two dielectrics, for
+// unpolarized light. `cos_i` is the cosine of the incident angle, and `eta`
+// the ratio of the index of refraction on the transmitted side to that on the
+// incident side.
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = cos_i.abs().min(1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        // Total internal reflection
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_parl * r_parl + r_perp * r_perp) / 2.0
+}
+
+// Fresnel reflectance of a conductor with the complex index of refraction
+// `eta + i k`, for unpolarized light coming from a dielectric with an IOR of 1.
+pub fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_i.abs().min(1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let (eta2, k2) = (eta * eta, k * k);
+    let t0 = eta2 - k2 - sin2;
+    let a2b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2b2 + cos2;
+    let a = (0.5 * (a2b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos_i.abs().min(1.0) * a;
+    let r_s = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let r_p = r_s * (t3 - t4) / (t3 + t4);
+    (r_s + r_p) / 2.0
+}
+
+// Ratio of the IOR on the other side of a dielectric interface to that on
+// the side of `wo`. The upper side is assumed to be air.
+pub fn relative_eta(wo: Vec3, ior: f32) -> f32 {
+    if cos_theta(wo) > 0.0 {
+        ior
+    } else {
+        1.0 / ior
+    }
+}
+
+// Rough dielectric interface, reflecting and refracting according to the
+// exact Fresnel equations. See Walter et al. "Microfacet Models for
+// Refraction through Rough Surfaces" (2007).
+//
+// This is the BSDF for radiance, i.e. where `wi` points towards the light.
+pub fn rough_dielectric_f(wi: Vec3, wo: Vec3, alpha: f32, ior: f32) -> f32 {
+    rough_dielectric_f_with(wi, wo, alpha, ior, |c, eta| {
+        Vec3::repeat(fresnel_dielectric(c, eta))
+    })
+    .x
+}
+
+// Like `rough_dielectric_f`, but with the Fresnel reflectance given by
+// `fresnel(cos, eta)` for the cosine between `wo` and the microfacet normal
+// and the relative IOR. The reflectance may be colored, e.g. by a thin film,
+// and whatever isn't reflected is transmitted.
+pub fn rough_dielectric_f_with(
+    wi: Vec3,
+    wo: Vec3,
+    alpha: f32,
+    ior: f32,
+    fresnel: impl Fn(f32, f32) -> Vec3,
+) -> Vec3 {
+    let (cos_i, cos_o) = (cos_theta(wi), cos_theta(wo));
+    let eta = relative_eta(wo, ior);
+    let g = ggx_g(wi, wo, alpha);
+    if cos_i * cos_o > 0.0 {
+        let wh = upper(wi + wo);
+        fresnel(wo.dot(&wh), eta) * ggx_d(wh, alpha) * g
+            / (4.0 * (cos_i * cos_o).abs())
+    } else {
+        let wh = upper(wo + wi * eta);
+        let (o_h, i_h) = (wo.dot(&wh), wi.dot(&wh));
+        if !front_facing(wh, wi, wo) {
+            return Vec3::zeros();
+        }
+        let denom = o_h + eta * i_h;
+        // The factor of η² from the change of solid angle cancels with the
+        // 1/η² of radiance being compressed into a smaller solid angle.
+        (Vec3::repeat(1.0) - fresnel(o_h, eta))
+            * ggx_d(wh, alpha)
+            * g
+            * (i_h * o_h).abs()
+            / ((cos_i * cos_o).abs() * denom * denom)
+    }
+}
+
+pub fn rough_dielectric_pdf(wi: Vec3, wo: Vec3, alpha: f32, ior: f32) -> f32 {
+    rough_dielectric_pdf_with(wi, wo, alpha, ior, fresnel_dielectric)
+}
+
+// Like `rough_dielectric_pdf`, but reflecting with the probability
+// `fresnel(cos, eta)`, like `rough_dielectric_f_with`.
+pub fn rough_dielectric_pdf_with(
+    wi: Vec3,
+    wo: Vec3,
+    alpha: f32,
+    ior: f32,
+    fresnel: impl Fn(f32, f32) -> f32,
+) -> f32 {
+    let eta = relative_eta(wo, ior);
+    if cos_theta(wi) * cos_theta(wo) > 0.0 {
+        let wh = upper(wi + wo);
+        fresnel(wo.dot(&wh), eta) * reflected_pdf(ggx_pdf_wh(wh, alpha), wo, wh)
+    } else {
+        let wh = upper(wo + wi * eta);
+        let (o_h, i_h) = (wo.dot(&wh), wi.dot(&wh));
+        if !front_facing(wh, wi, wo) {
+            return 0.0;
+        }
+        let denom = o_h + eta * i_h;
+        (1.0 - fresnel(o_h, eta))
+            * ggx_pdf_wh(wh, alpha)
+            * (eta * eta * i_h).abs()
+            / (denom * denom)
+    }
+}
+
+// Sample either reflection or refraction, proportionally to the Fresnel
+// reflectance of the sampled microfacet.
+pub fn rough_dielectric_sample_wi(
+    wo: Vec3,
+    alpha: f32,
+    ior: f32,
+    u1: f32,
+    u2: f32,
+    u3: f32,
+) -> Option<Vec3> {
+    rough_dielectric_sample_wi_with(
+        wo,
+        alpha,
+        ior,
+        [u1, u2, u3],
+        fresnel_dielectric,
+    )
+}
+
+// Like `rough_dielectric_sample_wi`, but reflecting with the probability
+// `fresnel(cos, eta)`, like `rough_dielectric_f_with`.
+pub fn rough_dielectric_sample_wi_with(
+    wo: Vec3,
+    alpha: f32,
+    ior: f32,
+    [u1, u2, u3]: [f32; 3],
+    fresnel: impl Fn(f32, f32) -> f32,
+) -> Option<Vec3> {
+    let mut wh = ggx_sample_wh(alpha, u1, u2);
+    if cos_theta(wo) < 0.0 {
+        wh = -wh
+    }
+    if wo.dot(&wh) <= 0.0 {
+        return None;
+    }
+    let eta = relative_eta(wo, ior);
+    if u3 < fresnel(wo.dot(&wh), eta) {
+        let wi = reflect(wo, wh);
+        if cos_theta(wi) * cos_theta(wo) > 0.0 {
+            Some(wi)
+        } else {
+            None
+        }
+    } else {
+        let wi = refract(wo, wh, eta)?;
+        if cos_theta(wi) * cos_theta(wo) < 0.0 {
+            Some(wi)
+        } else {
+            None
+        }
+    }
+}
+
+// Whether both directions see the front of the microfacet `wh` when refracting
+// through it. Light can't be refracted by the backside of a microfacet, which
+// is also never sampled.
+fn front_facing(wh: Vec3, wi: Vec3, wo: Vec3) -> bool {
+    wi.dot(&wh) * cos_theta(wi) > 0.0 && wo.dot(&wh) * cos_theta(wo) > 0.0
+}
+
+// Flip a half-vector to the upper hemisphere and normalize it.
+fn upper(wh: Vec3) -> Vec3 {
+    let wh = wh.normalize();
+    if cos_theta(wh) < 0.0 {
+        -wh
+    } else {
+        wh
+    }
+}
+
+// `(1 - cos)^5`, the angular part of Schlick's Fresnel approximation.
+pub fn schlick_weight(cos: f32) -> f32 {
+    (1.0 - cos.abs().min(1.0)).powi(5)
+}
+
+pub fn luminance(c: Vec3) -> f32 {
+    c.dot(&vec3(0.2126, 0.7152, 0.0722))
+}
@@ -0,0 +1,392 @@
+use nalgebra_glm::Vec3;
+use rand::prelude::*;
+use rayon::prelude::*;
+use std::cmp;
+use std::f32::consts::PI;
+
+use crate::geom::*;
+use crate::material::*;
+use crate::microfacet::*;
+use crate::spectrum::*;
+use crate::trace::*;
+
+// Markov chains run in parallel, kept from one frame to the next
+const CHAINS: usize = 256;
+// Independent paths traced when the accumulation starts over, to estimate
+// how bright the image is and to pick where the chains start
+const BOOTSTRAP_PATHS: u32 = 16_384;
+// The standard deviation of a small step of a primary sample
+const SMALL_STEP_SIGMA: f32 = 0.01;
+
+// Primary sample space Metropolis light transport, after Kelemen et al.'s "A
+// Simple and Robust Mutation Strategy for the Metropolis Light Transport
+// Algorithm" (2002). The path tracer is driven entirely by its random
+// numbers, so the numbers make up a point in "primary sample space" that
+// determines a path. Instead of drawing new numbers for every path, chains
+// of paths are formed by mutating the numbers of the current path, either a
+// little, for a small step, or completely, for a large step. The proposed
+// path is accepted with a probability by how bright it is compared to the
+// current one. Once a bright path is found, the chain explores the paths
+// near it, which makes light arriving through narrow openings much easier
+// to find than for the path tracer.
+//
+// The chains visit paths in proportion to their brightness, which only says
+// where light lands relative to elsewhere. How bright the image is overall
+// is estimated from a bootstrap of independent paths when the accumulation
+// starts over, and refined by every large step after that. Each frame makes
+// about one mutation per pixel, and all the light is splatted.
+pub struct Metropolis {
+    chains: Vec<Chain>,
+    // The sum of the brightnesses of the independent paths traced since the
+    // accumulation started over, and their number
+    brightness_sum: f64,
+    brightness_n: u64,
+}
+
+struct Chain {
+    samples: PrimarySamples,
+    current: Contribution,
+}
+
+// Where a path lands on the image, and the light it brings there in RGB
+#[derive(Clone, Copy)]
+struct Contribution {
+    pixel: (u32, u32),
+    color: Vec3,
+}
+
+// The random numbers of a path, as a point in primary sample space. The
+// numbers are mutated lazily, only once the path tracer asks for them, as
+// paths of different lengths use different amounts of them.
+struct PrimarySamples {
+    samples: Vec<PrimarySample>,
+    // For mutating the samples, and deciding whether to accept the result
+    rng: SmallRng,
+    large_step_prob: f32,
+    // The number of the mutation in progress
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    // The index of the sample the path tracer gets next
+    next: usize,
+}
+
+struct PrimarySample {
+    value: f32,
+    // The iteration the sample was last mutated in
+    modified: u64,
+    // The sample before the mutation in progress, in case it's rejected
+    backup: f32,
+    backup_modified: u64,
+}
+
+impl Metropolis {
+    pub fn new() -> Self {
+        Metropolis {
+            chains: vec![],
+            brightness_sum: 0.0,
+            brightness_n: 0,
+        }
+    }
+
+    // Trace independent paths to estimate how bright the image is, and
+    // start the chains at paths picked among them by their brightness
+    fn bootstrap(
+        &mut self,
+        scene: &[Sphere],
+        view: &View,
+        depths: Depths,
+        mutations: Mutations,
+        spectral: bool,
+    ) {
+        let brightnesses: Vec<f32> = (0..BOOTSTRAP_PATHS)
+            .into_par_iter()
+            .map(|i| {
+                let mut samples =
+                    PrimarySamples::new(view.seed + i as u64, mutations);
+                contribute(&mut samples, scene, depths, view, spectral)
+                    .brightness()
+            })
+            .collect();
+        let cdf: Vec<f64> = brightnesses
+            .iter()
+            .scan(0.0, |sum, &f| {
+                *sum += f as f64;
+                Some(*sum)
+            })
+            .collect();
+        self.brightness_sum = cdf[cdf.len() - 1];
+        self.brightness_n = BOOTSTRAP_PATHS as u64;
+        self.chains.clear();
+        if self.brightness_sum == 0.0 {
+            return;
+        }
+        let mut rng = SmallRng::seed_from_u64(view.seed);
+        for c in 0..CHAINS {
+            let u = rng.gen::<f64>() * self.brightness_sum;
+            let i = cdf.iter().take_while(|&&sum| sum <= u).count();
+            let i = cmp::min(i, cdf.len() - 1);
+            // The same seed gives the same path again
+            let mut samples =
+                PrimarySamples::new(view.seed + i as u64, mutations);
+            let current =
+                contribute(&mut samples, scene, depths, view, spectral);
+            // But chains starting at the same path shouldn't mutate it the
+            // same way
+            samples.rng = SmallRng::seed_from_u64(
+                view.seed + (BOOTSTRAP_PATHS as usize + c) as u64,
+            );
+            self.chains.push(Chain { samples, current });
+        }
+    }
+}
+
+impl Integrator for Metropolis {
+    fn name(&self) -> &'static str {
+        "mlt"
+    }
+
+    fn prepare(
+        &mut self,
+        scene: &[Sphere],
+        view: &View,
+        depths: Depths,
+        _: Photons,
+        mutations: Mutations,
+        spectral: bool,
+    ) {
+        if view.frame == 0 || self.chains.is_empty() {
+            self.bootstrap(scene, view, depths, mutations, spectral)
+        }
+        if self.chains.is_empty() {
+            return;
+        }
+        let pixels = view.dims[0] as usize * view.dims[1] as usize;
+        let steps = (pixels + CHAINS - 1) / CHAINS;
+        // Every mutation splats a fraction of the image's brightness, by
+        // how likely the paths involved are to be accepted
+        let brightness = self.brightness_sum / self.brightness_n as f64;
+        let scale = (brightness * pixels as f64
+            / (steps * self.chains.len()) as f64) as f32;
+        let (sum, n) = self
+            .chains
+            .par_iter_mut()
+            .map(|chain| {
+                let (mut sum, mut n) = (0.0, 0);
+                for _ in 0..steps {
+                    let large_step = chain
+                        .step(scene, depths, view, spectral, scale)
+                        .map(|f| f as f64);
+                    if let Some(f) = large_step {
+                        sum += f;
+                        n += 1;
+                    }
+                }
+                (sum, n)
+            })
+            .reduce(|| (0.0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+        self.brightness_sum += sum;
+        self.brightness_n += n;
+    }
+
+    fn radiance(&self, _: Path, _: &[Sphere], _: Depths, _: &View) -> Vec3 {
+        Vec3::zeros()
+    }
+}
+
+impl Chain {
+    // Propose a mutation of the current path. Both paths are splatted,
+    // weighted by the probability of the chain moving to them, with `scale`
+    // applied on top. Returns the brightness of the proposed path if it was
+    // a large step, and so independent of the current one.
+    fn step(
+        &mut self,
+        scene: &[Sphere],
+        depths: Depths,
+        view: &View,
+        spectral: bool,
+        scale: f32,
+    ) -> Option<f32> {
+        self.samples.mutate();
+        let proposed =
+            contribute(&mut self.samples, scene, depths, view, spectral);
+        let fc = self.current.brightness();
+        let fp = proposed.brightness();
+        let accept = if fc > 0.0 { (fp / fc).min(1.0) } else { 1.0 };
+        if fp > 0.0 {
+            view.splats
+                .add(proposed.pixel, proposed.color * (scale * accept / fp))
+        }
+        if fc > 0.0 {
+            let current = self.current.color * (scale * (1.0 - accept) / fc);
+            view.splats.add(self.current.pixel, current)
+        }
+        let large_step = if self.samples.large_step {
+            Some(fp)
+        } else {
+            None
+        };
+        if rand(&mut self.samples.rng) < accept {
+            self.samples.accept();
+            self.current = proposed;
+        } else {
+            self.samples.reject();
+        }
+        large_step
+    }
+}
+
+impl Contribution {
+    // What the chains visit paths in proportion to
+    fn brightness(&self) -> f32 {
+        let y = luminance(self.color);
+        if y.is_finite() {
+            y.max(0.0)
+        } else {
+            0.0
+        }
+    }
+}
+
+// Trace a path from the camera with the path tracer, driven by `samples`
+fn contribute(
+    samples: &mut PrimarySamples,
+    scene: &[Sphere],
+    depths: Depths,
+    view: &View,
+    spectral: bool,
+) -> Contribution {
+    let [w, h] = view.dims;
+    // The first samples pick where on the screen the path goes through. The
+    // path tracer's rays go through the pixel corners, so the pixels are
+    // shifted by half a pixel to match.
+    let x = rand(samples) * w as f32;
+    let y = rand(samples) * h as f32;
+    let pixel = (cmp::min(x as u32, w - 1), cmp::min(y as u32, h - 1));
+    let wavelengths = if spectral {
+        Some(sample_wavelengths(rand(samples)))
+    } else {
+        None
+    };
+    let path = Path {
+        pixel,
+        ray: view.ray((x - 0.5) / w as f32, (y - 0.5) / h as f32),
+        depth: 0,
+        throughput: Vec3::repeat(1.0),
+        radiance: Vec3::zeros(),
+        media: view.media(wavelengths),
+        wavelengths,
+        hero_only: false,
+        rng: samples,
+    };
+    let color = PathTracer.radiance(path, scene, depths, view);
+    Contribution {
+        pixel,
+        color: match wavelengths {
+            Some(ls) => spectrum_to_rgb(color, ls),
+            None => color,
+        },
+    }
+}
+
+impl PrimarySamples {
+    // Starts out with a large step, so the first path is an independent one
+    fn new(seed: u64, mutations: Mutations) -> Self {
+        PrimarySamples {
+            samples: vec![],
+            rng: SmallRng::seed_from_u64(seed),
+            large_step_prob: mutations.large_step,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            next: 0,
+        }
+    }
+
+    // Start proposing a new path
+    fn mutate(&mut self) {
+        self.iteration += 1;
+        self.large_step = rand(&mut self.rng) < self.large_step_prob;
+        self.next = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    // Go back to the samples of the current path
+    fn reject(&mut self) {
+        for x in &mut self.samples {
+            if x.modified == self.iteration {
+                x.value = x.backup;
+                x.modified = x.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let i = self.next;
+        self.next += 1;
+        if i == self.samples.len() {
+            self.samples.push(PrimarySample {
+                value: 0.0,
+                modified: 0,
+                backup: 0.0,
+                backup_modified: 0,
+            })
+        }
+        let x = &mut self.samples[i];
+        // A sample last used before the last accepted large step would have
+        // been replaced by it
+        if x.modified < self.last_large_step {
+            x.value = rand(&mut self.rng);
+            x.modified = self.last_large_step;
+        }
+        x.backup = x.value;
+        x.backup_modified = x.modified;
+        if self.large_step {
+            x.value = rand(&mut self.rng);
+        } else {
+            // The small steps the sample missed while it wasn't used add up
+            // to a single larger one
+            let n = self.iteration - x.modified;
+            let sigma = SMALL_STEP_SIGMA * (n as f32).sqrt();
+            x.value += sigma * standard_normal(&mut self.rng);
+            x.value -= x.value.floor();
+        }
+        x.modified = self.iteration;
+        x.value
+    }
+}
+
+// The path tracer draws its random numbers from the samples
+impl RngCore for PrimarySamples {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_sample() as f64 * 4_294_967_296.0) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+// A sample of the standard normal distribution, by the Box-Muller transform
+fn standard_normal(rng: &mut dyn RngCore) -> f32 {
+    let (u1, u2) = (rand(rng), rand(rng));
+    (-2.0 * (1.0 - u1).ln()).sqrt() * (2.0 * PI * u2).cos()
+}
@@ -0,0 +1,53 @@
+use nalgebra_glm::Vec3;
+
+use crate::geom::*;
+use crate::intersect::*;
+use crate::material::*;
+use crate::spectrum::*;
+use crate::trace::*;
+
+// Occluders further away than this don't darken a point
+const OCCLUSION_RADIUS: f32 = 4.0;
+
+// Ambient occlusion. Shows how much of the hemisphere above the first
+// surface the camera sees is open, ignoring materials and lights. Points in
+// creases and corners are darker. Useful to look at the geometry alone.
+pub struct AmbientOcclusion;
+
+impl Integrator for AmbientOcclusion {
+    fn name(&self) -> &'static str {
+        "occlusion"
+    }
+
+    fn radiance(
+        &self,
+        path: Path,
+        scene: &[Sphere],
+        _: Depths,
+        _: &View,
+    ) -> Vec3 {
+        let white = upsample(Vec3::repeat(1.0), path.wavelengths);
+        let hit = match closest_hit(&path.ray, scene, path.rng) {
+            Some(hit) => hit,
+            None => return white,
+        };
+        let hit_pos = path.ray.origin + hit.t * path.ray.dir;
+        // Face the side of the surface the camera sees
+        let n = if path.ray.dir.dot(&hit.geo_normal) < 0.0 {
+            hit.geo_normal
+        } else {
+            -hit.geo_normal
+        };
+        // Cosine-weighted directions, so the visibility is just the fraction
+        // of unoccluded rays
+        let wi = Frame::new(n).to_world(cosine_sample_hemisphere(path.rng));
+        let occlusion_ray = Ray {
+            origin: hit_pos + RAY_EPSILON * wi,
+            dir: wi,
+        };
+        match closest_hit(&occlusion_ray, scene, path.rng) {
+            Some(occluder) if occluder.t < OCCLUSION_RADIUS => Vec3::zeros(),
+            _ => white,
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use nalgebra_glm as glm;
+use nalgebra_glm::Vec3;
+use rand::prelude::*;
+use rayon::prelude::*;
+use std::cmp;
+use std::f32::consts::PI;
+
+use crate::geom::*;
+use crate::intersect::*;
+use crate::kdtree::*;
+use crate::material::*;
+use crate::medium::*;
+use crate::spectrum::*;
+use crate::trace::*;
+
+// Photons are traced in parallel in batches of this many, each batch with an
+// RNG of its own
+const PHOTON_BATCH: u32 = 1024;
+
+// Photon mapping, after Jensen's "Realistic Image Synthesis Using Photon
+// Mapping" (2001). Before each frame, photons are emitted from the light and
+// stored wherever they land on a surface that isn't purely delta. Photons
+// that only went through delta lobes to get there, like through glass, go
+// in the caustic map, and those that bounced off anything else go in the
+// global map.
+//
+// Camera rays continue through delta lobes. At the first other surface, the
+// light arriving directly from the light is sampled as in the path tracer,
+// and the rest is estimated from the density of the photons around the hit.
+// The estimate blurs the lighting over the gather radius, but caustics come
+// out clean, where the path tracer can't find them at all. The background
+// doesn't emit photons, as most would land far away on the ground, so from
+// there on the path is traced on as by the path tracer to find the light of
+// the background only. Media only attenuate light here, as if all the light
+// they scatter was absorbed.
+pub struct PhotonMapping {
+    maps: PhotonMaps,
+    radius: f32,
+}
+
+// The photons emitted from the light for a frame, stored wherever they
+// landed
+pub struct PhotonMaps {
+    // Photons that only went through delta lobes before landing
+    caustic: KdTree<Photon>,
+    // Photons that bounced off anything else on the way
+    global: KdTree<Photon>,
+}
+
+// Light arriving at a point on a surface
+struct Photon {
+    // The direction the light came from
+    wi: Vec3,
+    // The flux carried, in RGB even in spectral mode, as every photon has
+    // wavelengths of its own
+    power: Vec3,
+}
+
+impl PhotonMapping {
+    pub fn new() -> Self {
+        PhotonMapping {
+            maps: PhotonMaps::new(),
+            radius: 0.0,
+        }
+    }
+}
+
+impl Integrator for PhotonMapping {
+    fn name(&self) -> &'static str {
+        "photon"
+    }
+
+    fn prepare(
+        &mut self,
+        scene: &[Sphere],
+        view: &View,
+        depths: Depths,
+        photons: Photons,
+        _: Mutations,
+        spectral: bool,
+    ) {
+        self.maps =
+            PhotonMaps::trace(scene, view, depths, photons.count, spectral);
+        self.radius = photons.radius;
+    }
+
+    fn radiance(
+        &self,
+        path: Path,
+        scene: &[Sphere],
+        depths: Depths,
+        _: &View,
+    ) -> Vec3 {
+        photon_radiance(path, scene, depths, &self.maps, self.radius).0
+    }
+}
+
+impl PhotonMaps {
+    pub fn new() -> Self {
+        PhotonMaps {
+            caustic: KdTree::new(vec![]),
+            global: KdTree::new(vec![]),
+        }
+    }
+
+    // Emit `count` photons from the light and store them in the maps
+    pub fn trace(
+        scene: &[Sphere],
+        view: &View,
+        depths: Depths,
+        count: u32,
+        spectral: bool,
+    ) -> Self {
+        let batches = (count + PHOTON_BATCH - 1) / PHOTON_BATCH;
+        let (caustic, global) = (0..batches)
+            .into_par_iter()
+            .map(|batch| {
+                let mut rng = SmallRng::seed_from_u64(view.seed + batch as u64);
+                let (mut caustic, mut global) = (vec![], vec![]);
+                let n = cmp::min(PHOTON_BATCH, count - batch * PHOTON_BATCH);
+                for _ in 0..n {
+                    trace_photon(
+                        &mut rng,
+                        scene,
+                        view,
+                        depths,
+                        count,
+                        spectral,
+                        &mut caustic,
+                        &mut global,
+                    );
+                }
+                (caustic, global)
+            })
+            .reduce(
+                || (vec![], vec![]),
+                |(mut caustic, mut global), (c, g)| {
+                    caustic.extend(c);
+                    global.extend(g);
+                    (caustic, global)
+                },
+            );
+        PhotonMaps {
+            caustic: KdTree::new(caustic),
+            global: KdTree::new(global),
+        }
+    }
+
+    // The flux of the photons within `radius` of the hit, as reflected
+    // towards `wo`, and the number of photons it came from
+    fn gather(
+        &self,
+        hit: &Hit,
+        wo: Vec3,
+        radius: f32,
+        wavelengths: Option<Vec3>,
+    ) -> (Vec3, u32) {
+        let mut sum = Vec3::zeros();
+        let mut n = 0;
+        let mut add = |_: Vec3, photon: &Photon| {
+            if !leaks(hit, photon.wi, wo) {
+                let f = upsample(hit.mat.eval(photon.wi, wo, hit), wavelengths);
+                sum += f.component_mul(&upsample(photon.power, wavelengths));
+                n += 1;
+            }
+        };
+        self.caustic.within(hit.pos, radius, &mut add);
+        self.global.within(hit.pos, radius, &mut add);
+        (sum, n)
+    }
+}
+
+// The radiance arriving at the camera along `path.ray`, with the light that
+// isn't direct estimated from the photons of `maps` within `radius` of the
+// surfaces seen. Also returns the number of photons that went into the
+// estimate.
+pub fn photon_radiance(
+    mut path: Path,
+    scene: &[Sphere],
+    depths: Depths,
+    maps: &PhotonMaps,
+    radius: f32,
+) -> (Vec3, u32) {
+    let mut gathered = 0;
+    // Whether the path is past the surface where the photons were gathered,
+    // and only looks for the background
+    let mut background_only = false;
+    loop {
+        let hit = closest_hit(&path.ray, scene, path.rng);
+        if let Some(medium) = path.media.current() {
+            let t = hit.as_ref().map_or(SKY_DISTANCE, |hit| hit.t);
+            let tr = medium.tr(path.rng, &path.ray, t);
+            path.throughput = path.throughput.component_mul(&tr);
+        }
+        let mut hit = match hit {
+            Some(hit) => hit,
+            None => {
+                let background = upsample(background_color(), path.wavelengths);
+                return (
+                    path.radiance + background.component_mul(&path.throughput),
+                    gathered,
+                );
+            }
+        };
+        hit.wavelength = path.wavelengths.map(|ls| ls.x);
+        let wo = -path.ray.dir;
+        let hit_pos = path.ray.origin + hit.t * path.ray.dir;
+        // Photons aren't stored on purely delta surfaces, so there's nothing
+        // to gather there, and no direct light to sample either
+        let mut radiance = if background_only || hit.mat.lobes().is_delta() {
+            Vec3::zeros()
+        } else {
+            let (flux, n) = maps.gather(&hit, wo, radius, path.wavelengths);
+            gathered += n;
+            direct_light(&hit, hit_pos, wo, &mut path, scene)
+                + flux / (PI * radius * radius)
+        };
+        let sample = hit.mat.sample(path.rng, wo, &hit);
+        let mut throughput = if sample.pdf != 0.0 && !leaks(&hit, sample.wi, wo)
+        {
+            let cosineterm = sample.wi.dot(&hit.normal).abs();
+            path.throughput.component_mul(&upsample(
+                (sample.brdf * cosineterm) / sample.pdf,
+                path.wavelengths,
+            ))
+        } else {
+            Vec3::zeros()
+        };
+        let hero_only = path.hero_only
+            || (path.wavelengths.is_some() && hit.mat.dispersive());
+        if hero_only && !path.hero_only {
+            radiance = keep_hero(radiance);
+            throughput = keep_hero(throughput);
+        }
+        path.radiance += radiance.component_mul(&path.throughput);
+        background_only = background_only || !sample.lobe.is_delta();
+        let survival = if path.depth < depths.min {
+            1.0
+        } else {
+            glm::comp_max(&throughput).min(1.0)
+        };
+        if path.depth >= depths.max || rand(path.rng) >= survival {
+            return (path.radiance, gathered);
+        }
+        if sample.lobe.contains(Lobes::TRANSMISSION) {
+            if sample.wi.dot(&hit.geo_normal) < 0.0 {
+                let interior = interior_of(&hit, path.wavelengths);
+                path.media.enter(hit.object, interior, hit.mat.invisible())
+            } else {
+                path.media.exit(hit.object)
+            }
+        }
+        path.ray = Ray {
+            origin: hit_pos + RAY_EPSILON * sample.wi,
+            dir: sample.wi,
+        };
+        path.depth += 1;
+        path.throughput = throughput / survival;
+        path.hero_only = hero_only;
+    }
+}
+
+// Trace a photon from the light, out of `count` in total, and store it in the
+// caustic or global map wherever it lands. Photons aren't stored where they
+// first land, as direct light is sampled separately.
+fn trace_photon(
+    rng: &mut dyn RngCore,
+    scene: &[Sphere],
+    view: &View,
+    depths: Depths,
+    count: u32,
+    spectral: bool,
+    caustic: &mut Vec<(Vec3, Photon)>,
+    global: &mut Vec<(Vec3, Photon)>,
+) {
+    let wavelengths = if spectral {
+        Some(sample_wavelengths(rng.gen()))
+    } else {
+        None
+    };
+    // The light emits `4π I` in total, shared by all the photons
+    let mut power =
+        upsample(light_intensity(), wavelengths) * 4.0 * PI / count as f32;
+    let mut ray = Ray {
+        origin: light_pos(),
+        dir: uniform_sample_sphere(rng),
+    };
+    let mut media = view.media(wavelengths);
+    let mut hero_only = false;
+    // Whether the photon has only been through delta lobes so far
+    let mut specular = true;
+    for depth in 0..=depths.max {
+        let mut hit = match closest_hit(&ray, scene, rng) {
+            Some(hit) => hit,
+            None => return,
+        };
+        if let Some(medium) = media.current() {
+            power = power.component_mul(&medium.tr(rng, &ray, hit.t));
+        }
+        hit.wavelength = wavelengths.map(|ls| ls.x);
+        let wo = -ray.dir;
+        if depth > 0 && !hit.mat.lobes().is_delta() {
+            let photon = Photon {
+                wi: wo,
+                power: match wavelengths {
+                    Some(ls) => spectrum_to_rgb(power, ls),
+                    None => power,
+                },
+            };
+            if specular {
+                caustic.push((hit.pos, photon))
+            } else {
+                global.push((hit.pos, photon))
+            }
+        }
+        // As for the light subpaths of BDPT, the roles of `wi` and `wo` are
+        // swapped when sampling the BSDF
+        let sample = hit.mat.sample(rng, wo, &hit);
+        if sample.pdf == 0.0 || leaks(&hit, sample.wi, wo) {
+            return;
+        }
+        let cosineterm = sample.wi.dot(&hit.normal).abs();
+        let mut weight =
+            upsample((sample.brdf * cosineterm) / sample.pdf, wavelengths);
+        if !hero_only && wavelengths.is_some() && hit.mat.dispersive() {
+            weight = keep_hero(weight);
+            hero_only = true;
+        }
+        // Russian roulette by the weight of the bounce, keeping the power of
+        // the photons about the same
+        if depth >= depths.min {
+            let survival = glm::comp_max(&weight).min(1.0);
+            if rand(rng) < survival {
+                weight /= survival;
+            } else {
+                return;
+            }
+        }
+        power = power.component_mul(&weight);
+        specular = specular && sample.lobe.is_delta();
+        if sample.lobe.contains(Lobes::TRANSMISSION) {
+            if sample.wi.dot(&hit.geo_normal) < 0.0 {
+                let interior = interior_of(&hit, wavelengths);
+                media.enter(hit.object, interior, hit.mat.invisible())
+            } else {
+                media.exit(hit.object)
+            }
+        }
+        ray = Ray {
+            origin: hit.pos + RAY_EPSILON * sample.wi,
+            dir: sample.wi,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests;
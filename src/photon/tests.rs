@@ -0,0 +1,28 @@
+// Checks of where photons are stored.
+
+use nalgebra_glm::vec3;
+use std::time;
+
+use super::*;
+use crate::matlib::*;
+
+// The number of photons in `map`
+fn count(map: &KdTree<Photon>) -> usize {
+    let mut n = 0;
+    map.within(Vec3::zeros(), f32::INFINITY, |_, _| n += 1);
+    n
+}
+
+// The smooth glass of `scene_3` focuses light onto the ground, so some of the
+// photons must have only been through delta lobes when they land
+#[test]
+fn glass_makes_caustics() {
+    let scene = scene_3(&MatLib::presets(), time::Instant::now());
+    let splats = Splats::new([1, 1]);
+    let view =
+        View::for_tests(vec3(0.0, 0.0, 10.0), vec3(0.0, 0.0, -1.0), &splats);
+    let depths = Depths { min: 3, max: 16 };
+    let maps = PhotonMaps::trace(&scene, &view, depths, 100_000, false);
+    assert!(count(&maps.caustic) > 0, "no caustic photons");
+    assert!(count(&maps.global) > 0, "no global photons");
+}
@@ -0,0 +1,432 @@
+use nalgebra_glm as glm;
+use nalgebra_glm::{vec3, Vec3};
+use rand::prelude::*;
+use std::f32::consts::FRAC_1_PI;
+
+use crate::intersect::*;
+use crate::material::*;
+use crate::microfacet::*;
+use crate::spectrum::*;
+use crate::texture::*;
+
+// The "principled" BSDF of Disney, as described by Burley in "Physically Based
+// Shading at Disney" (2012), extended with rough specular transmission.
+//
+// All parameters except `ior` are in [0, 1].
+#[derive(Clone)]
+pub struct Principled {
+    pub base_color: Tex<Vec3>,
+    // Blends between a dielectric and a conductor
+    pub metallic: Tex<f32>,
+    pub roughness: Tex<f32>,
+    // Amount of dielectric specular reflection. Replaces an explicit index of
+    // refraction for reflections, where 0.5 corresponds to an IOR of 1.5.
+    pub specular: Tex<f32>,
+    // Tints the dielectric specular reflection towards the base color
+    pub specular_tint: Tex<f32>,
+    // Extra grazing retro-reflection, for cloth
+    pub sheen: Tex<f32>,
+    pub sheen_tint: Tex<f32>,
+    // A second, white specular lobe on top of everything else
+    pub clearcoat: Tex<f32>,
+    pub clearcoat_gloss: Tex<f32>,
+    // Blends between an opaque and a transmissive dielectric
+    pub transmission: Tex<f32>,
+    // Blends the diffuse lobe towards an approximation of subsurface
+    // scattering, which flattens the shading
+    pub subsurface: Tex<f32>,
+    // Index of refraction, used for transmission
+    pub ior: Ior,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Principled {
+            base_color: Vec3::repeat(0.8).into(),
+            metallic: 0.0.into(),
+            roughness: 0.5.into(),
+            specular: 0.5.into(),
+            specular_tint: 0.0.into(),
+            sheen: 0.0.into(),
+            sheen_tint: 0.5.into(),
+            clearcoat: 0.0.into(),
+            clearcoat_gloss: 1.0.into(),
+            transmission: 0.0.into(),
+            subsurface: 0.0.into(),
+            ior: 1.5.into(),
+        }
+    }
+}
+
+impl Principled {
+    pub fn plastic(color: Vec3, roughness: f32) -> Self {
+        Principled {
+            base_color: color.into(),
+            roughness: roughness.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn metal(color: Vec3, roughness: f32) -> Self {
+        Principled {
+            base_color: color.into(),
+            metallic: 1.0.into(),
+            roughness: roughness.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn glass(color: Vec3, roughness: f32) -> Self {
+        Principled {
+            base_color: color.into(),
+            roughness: roughness.into(),
+            transmission: 1.0.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn car_paint(color: Vec3) -> Self {
+        Principled {
+            base_color: color.into(),
+            metallic: 0.5.into(),
+            roughness: 0.4.into(),
+            clearcoat: 1.0.into(),
+            clearcoat_gloss: 0.95.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn cloth(color: Vec3) -> Self {
+        Principled {
+            base_color: color.into(),
+            roughness: 1.0.into(),
+            specular: 0.1.into(),
+            sheen: 1.0.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn wax(color: Vec3) -> Self {
+        Principled {
+            base_color: color.into(),
+            roughness: 0.4.into(),
+            subsurface: 1.0.into(),
+            ..Default::default()
+        }
+    }
+
+    fn at(&self, hit: &Hit) -> Params {
+        let base_color = self.base_color.at(hit);
+        let metallic = self.metallic.at(hit);
+        let roughness = self.roughness.at(hit);
+        let transmission = self.transmission.at(hit);
+        let lum = luminance(base_color);
+        let tint = if lum > 0.0 {
+            base_color / lum
+        } else {
+            Vec3::repeat(1.0)
+        };
+        let white = Vec3::repeat(1.0);
+        let spec_color = 0.08
+            * self.specular.at(hit)
+            * glm::lerp(&white, &tint, self.specular_tint.at(hit));
+        Params {
+            base_color,
+            roughness,
+            alpha: ggx_alpha(roughness),
+            smooth: is_smooth(roughness),
+            spec0: glm::lerp(&spec_color, &base_color, metallic),
+            sheen: self.sheen.at(hit)
+                * glm::lerp(&white, &tint, self.sheen_tint.at(hit)),
+            subsurface: self.subsurface.at(hit),
+            clearcoat_alpha: lerp(0.1, 0.001, self.clearcoat_gloss.at(hit)),
+            ior: self.ior.at(hit.wavelength),
+            w_diffuse: (1.0 - metallic) * (1.0 - transmission),
+            w_specular: 1.0 - (1.0 - metallic) * transmission,
+            w_clearcoat: 0.25 * self.clearcoat.at(hit),
+            w_glass: (1.0 - metallic) * transmission,
+        }
+    }
+}
+
+impl Bsdf for Principled {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        let frame = Frame::new(hit.normal);
+        self.at(hit).eval(frame.to_local(wi), frame.to_local(wo))
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        let frame = Frame::new(hit.normal);
+        let params = self.at(hit);
+        let wo_l = frame.to_local(wo);
+        match params.sample_wi(rng, wo_l) {
+            Some((wi_l, lobe)) if lobe.contains(Lobes::DELTA) => {
+                let (f, pdf) = params.delta(wi_l, wo_l);
+                DirSample {
+                    wi: frame.to_world(wi_l),
+                    pdf,
+                    brdf: f / cos_theta(wi_l).abs(),
+                    lobe,
+                }
+            }
+            Some((wi_l, lobe)) => DirSample {
+                wi: frame.to_world(wi_l),
+                pdf: params.pdf(wi_l, wo_l),
+                brdf: params.eval(wi_l, wo_l),
+                lobe,
+            },
+            None => DirSample {
+                wi: hit.normal,
+                pdf: 0.0,
+                brdf: Vec3::zeros(),
+                lobe: Lobes::NONE,
+            },
+        }
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        let frame = Frame::new(hit.normal);
+        self.at(hit).pdf(frame.to_local(wi), frame.to_local(wo))
+    }
+
+    fn lobes(&self) -> Lobes {
+        // The specular and glass lobes may be smooth
+        let mut lobes = ggx_lobes(&self.roughness);
+        // No diffuse lobe for pure metals or glass, so that smooth ones are
+        // all delta
+        let metal = self.metallic.constant() == Some(1.0);
+        let glass = self.transmission.constant() == Some(1.0);
+        if !metal && !glass {
+            lobes = lobes | Lobes::DIFFUSE;
+        }
+        // The clearcoat is always glossy
+        if self.clearcoat.constant() != Some(0.0) {
+            lobes = lobes | Lobes::GLOSSY;
+        }
+        // Opaque unless there's some transmission somewhere
+        if self.transmission.constant() == Some(0.0) {
+            lobes
+        } else {
+            lobes | Lobes::TRANSMISSION
+        }
+    }
+
+    fn dispersive(&self) -> bool {
+        self.ior.is_dispersive()
+    }
+}
+
+// The parameters of a `Principled` at some specific point, premultiplied into
+// the values the lobes actually use.
+//
+// All directions are in the local shading frame.
+struct Params {
+    base_color: Vec3,
+    roughness: f32,
+    alpha: f32,
+    // Whether the specular and glass lobes are delta lobes
+    smooth: bool,
+    // Specular reflectance at normal incidence
+    spec0: Vec3,
+    sheen: Vec3,
+    subsurface: f32,
+    clearcoat_alpha: f32,
+    ior: f32,
+    // The weights of each lobe. Also used as the relative probabilities of
+    // sampling each lobe.
+    w_diffuse: f32,
+    w_specular: f32,
+    w_clearcoat: f32,
+    w_glass: f32,
+}
+
+impl Params {
+    // Probabilities of sampling the diffuse, specular, clearcoat, and glass
+    // lobes respectively. Only the glass lobe is defined from below the
+    // surface.
+    fn lobe_probabilities(&self, wo: Vec3) -> [f32; 4] {
+        let ws = if cos_theta(wo) > 0.0 {
+            [
+                self.w_diffuse,
+                self.w_specular,
+                self.w_clearcoat,
+                self.w_glass,
+            ]
+        } else {
+            [0.0, 0.0, 0.0, self.w_glass]
+        };
+        let sum: f32 = ws.iter().sum();
+        if sum > 0.0 {
+            [ws[0] / sum, ws[1] / sum, ws[2] / sum, ws[3] / sum]
+        } else {
+            [0.0; 4]
+        }
+    }
+
+    fn eval(&self, wi: Vec3, wo: Vec3) -> Vec3 {
+        let (cos_i, cos_o) = (cos_theta(wi), cos_theta(wo));
+        let mut f = Vec3::zeros();
+        if cos_i > 0.0 && cos_o > 0.0 {
+            let wh = (wi + wo).normalize();
+            let cos_d = wi.dot(&wh);
+            if self.w_diffuse > 0.0 {
+                f += self.w_diffuse * self.diffuse(cos_i, cos_o, cos_d);
+            }
+            if self.w_specular > 0.0 && !self.smooth {
+                let fresnel = glm::lerp(
+                    &self.spec0,
+                    &Vec3::repeat(1.0),
+                    schlick_weight(cos_d),
+                );
+                f += self.w_specular
+                    * fresnel
+                    * ggx_d(wh, self.alpha)
+                    * ggx_g(wi, wo, self.alpha)
+                    / (4.0 * cos_i * cos_o);
+            }
+            if self.w_clearcoat > 0.0 {
+                let fresnel = lerp(0.04, 1.0, schlick_weight(cos_d));
+                f += Vec3::repeat(
+                    self.w_clearcoat
+                        * fresnel
+                        * gtr1_d(wh, self.clearcoat_alpha)
+                        * ggx_g(wi, wo, 0.25)
+                        / (4.0 * cos_i * cos_o),
+                );
+            }
+        }
+        if self.w_glass > 0.0 && cos_i != 0.0 && cos_o != 0.0 && !self.smooth {
+            f += self.w_glass
+                * self.base_color
+                * rough_dielectric_f(wi, wo, self.alpha, self.ior);
+        }
+        f
+    }
+
+    // Burley's diffuse model with retro-reflection at grazing angles, blended
+    // with the Hanrahan-Krueger-like subsurface approximation, plus sheen.
+    fn diffuse(&self, cos_i: f32, cos_o: f32, cos_d: f32) -> Vec3 {
+        let fl = schlick_weight(cos_i);
+        let fv = schlick_weight(cos_o);
+        let fd90 = 0.5 + 2.0 * cos_d * cos_d * self.roughness;
+        let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+        let fss90 = cos_d * cos_d * self.roughness;
+        let fss = (1.0 + (fss90 - 1.0) * fl) * (1.0 + (fss90 - 1.0) * fv);
+        let ss = 1.25 * (fss * (1.0 / (cos_i + cos_o) - 0.5) + 0.5);
+        self.base_color * FRAC_1_PI * lerp(fd, ss, self.subsurface)
+            + self.sheen * schlick_weight(cos_d)
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3) -> f32 {
+        let [p_diffuse, p_specular, p_clearcoat, p_glass] =
+            self.lobe_probabilities(wo);
+        let mut pdf = 0.0;
+        if cos_theta(wi) > 0.0 && cos_theta(wo) > 0.0 {
+            let wh = (wi + wo).normalize();
+            pdf += p_diffuse * cos_theta(wi) * FRAC_1_PI;
+            if !self.smooth {
+                pdf += p_specular
+                    * reflected_pdf(ggx_pdf_wh(wh, self.alpha), wo, wh);
+            }
+            pdf += p_clearcoat
+                * reflected_pdf(gtr1_pdf_wh(wh, self.clearcoat_alpha), wo, wh);
+        }
+        if p_glass > 0.0 && cos_theta(wi) != 0.0 && !self.smooth {
+            pdf += p_glass * rough_dielectric_pdf(wi, wo, self.alpha, self.ior);
+        }
+        pdf
+    }
+
+    fn sample_wi(
+        &self,
+        rng: &mut dyn RngCore,
+        wo: Vec3,
+    ) -> Option<(Vec3, Lobes)> {
+        let [p_diffuse, p_specular, p_clearcoat, _] =
+            self.lobe_probabilities(wo);
+        let (u, u1, u2) = (rand(rng), rand(rng), rand(rng));
+        let sampled = if u < p_diffuse {
+            (cosine_sample_hemisphere(rng), Lobes::DIFFUSE)
+        } else if u < p_diffuse + p_specular {
+            if self.smooth {
+                (mirror(wo), Lobes::DELTA)
+            } else {
+                let wh = ggx_sample_wh(self.alpha, u1, u2);
+                (reflect(wo, wh), Lobes::GLOSSY)
+            }
+        } else if u < p_diffuse + p_specular + p_clearcoat {
+            let wh = gtr1_sample_wh(self.clearcoat_alpha, u1, u2);
+            (reflect(wo, wh), Lobes::GLOSSY)
+        } else if self.w_glass > 0.0 {
+            let u3 = rand(rng);
+            let (wi, lobe) = if self.smooth {
+                let eta = relative_eta(wo, self.ior);
+                let wi = if u3 < fresnel_dielectric(cos_theta(wo), eta) {
+                    mirror(wo)
+                } else {
+                    let n = vec3(0.0, 0.0, cos_theta(wo).signum());
+                    refract(wo, n, eta)?
+                };
+                (wi, Lobes::DELTA)
+            } else {
+                let wi = rough_dielectric_sample_wi(
+                    wo, self.alpha, self.ior, u1, u2, u3,
+                )?;
+                (wi, Lobes::GLOSSY)
+            };
+            if cos_theta(wi) == 0.0 {
+                return None;
+            }
+            return Some(if cos_theta(wi) * cos_theta(wo) > 0.0 {
+                (wi, lobe)
+            } else {
+                (wi, lobe | Lobes::TRANSMISSION)
+            });
+        } else {
+            return None;
+        };
+        if cos_theta(sampled.0) > 0.0 && cos_theta(wo) > 0.0 {
+            Some(sampled)
+        } else {
+            None
+        }
+    }
+
+    // The BSDF, times the cosine term, and the pdf of the delta lobes of a
+    // smooth surface in the direction `wi`, which is the exact reflection or
+    // refraction of `wo`. The specular and glass lobes both reflect in the
+    // same direction, so both count for reflections.
+    fn delta(&self, wi: Vec3, wo: Vec3) -> (Vec3, f32) {
+        let [_, p_specular, _, p_glass] = self.lobe_probabilities(wo);
+        let fresnel =
+            fresnel_dielectric(cos_theta(wo), relative_eta(wo, self.ior));
+        if cos_theta(wi) * cos_theta(wo) > 0.0 {
+            let mut f = self.w_glass * fresnel * self.base_color;
+            let mut pdf = p_glass * fresnel;
+            if cos_theta(wo) > 0.0 {
+                f += self.w_specular
+                    * glm::lerp(
+                        &self.spec0,
+                        &Vec3::repeat(1.0),
+                        schlick_weight(cos_theta(wo)),
+                    );
+                pdf += p_specular;
+            }
+            (f, pdf)
+        } else {
+            (
+                self.w_glass * (1.0 - fresnel) * self.base_color,
+                p_glass * (1.0 - fresnel),
+            )
+        }
+    }
+}
+
+// The direction of perfect reflection of `wo`
+fn mirror(wo: Vec3) -> Vec3 {
+    vec3(-wo.x, -wo.y, wo.z)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
@@ -0,0 +1,219 @@
+use nalgebra_glm::Vec3;
+use noise::{
+    Cylinders, Fbm, MultiFractal, NoiseFn, Perlin, Turbulence, Worley,
+};
+use std::ops::{Add, Mul};
+
+use crate::intersect::*;
+use crate::texture::*;
+
+// In which coordinate system a procedural texture is evaluated.
+#[derive(Clone, Copy)]
+pub enum Space {
+    // The pattern is fixed in the world, and objects move through it.
+    World,
+    // The pattern follows the object it's applied to.
+    Object,
+}
+
+// Maps a hit to the point at which a procedural pattern is evaluated.
+#[derive(Clone, Copy)]
+pub struct Mapping {
+    pub space: Space,
+    pub scale: Vec3,
+    pub offset: Vec3,
+}
+
+impl Mapping {
+    pub fn new(space: Space) -> Self {
+        Mapping {
+            space,
+            scale: Vec3::repeat(1.0),
+            offset: Vec3::zeros(),
+        }
+    }
+
+    pub fn scaled(self, s: f32) -> Self {
+        Mapping {
+            scale: self.scale * s,
+            ..self
+        }
+    }
+
+    pub fn offset(self, offset: Vec3) -> Self {
+        Mapping { offset, ..self }
+    }
+
+    fn point(&self, hit: &Hit) -> Vec3 {
+        let p = match self.space {
+            Space::World => hit.pos,
+            Space::Object => hit.local_pos,
+        };
+        p.component_mul(&self.scale) + self.offset
+    }
+
+    fn point_f64(&self, hit: &Hit) -> [f64; 3] {
+        let p = self.point(hit);
+        [p.x as f64, p.y as f64, p.z as f64]
+    }
+}
+
+// Alternating cubes of 0 and 1, each of side 1 before mapping.
+pub struct Checker(pub Mapping);
+
+impl Texture<f32> for Checker {
+    fn at(&self, hit: &Hit) -> f32 {
+        let p = self.0.point(hit);
+        let parity =
+            p.x.floor() as i64 + p.y.floor() as i64 + p.z.floor() as i64;
+        (parity & 1) as f32
+    }
+}
+
+// Any noise function of the `noise` crate, remapped from [-1, 1] to [0, 1].
+pub struct Noise<N> {
+    pub noise: N,
+    pub mapping: Mapping,
+}
+
+impl Noise<Perlin> {
+    pub fn perlin(mapping: Mapping) -> Self {
+        Noise {
+            noise: Perlin::new(),
+            mapping,
+        }
+    }
+}
+
+impl Noise<Fbm> {
+    // Fractal brownian motion, i.e. a sum of octaves of Perlin noise.
+    pub fn fbm(mapping: Mapping, octaves: usize) -> Self {
+        Noise {
+            noise: Fbm::new().set_octaves(octaves),
+            mapping,
+        }
+    }
+}
+
+impl Noise<Worley> {
+    // Cellular noise. Each cell gets a random constant value.
+    pub fn worley(mapping: Mapping) -> Self {
+        Noise {
+            noise: Worley::new(),
+            mapping,
+        }
+    }
+
+    // Cellular noise where the value is the distance to the cell centre
+    // instead, which makes the cell borders visible.
+    pub fn worley_distance(mapping: Mapping) -> Self {
+        Noise {
+            noise: Worley::new().enable_range(true),
+            mapping,
+        }
+    }
+}
+
+impl<N: NoiseFn<[f64; 3]> + Send + Sync> Texture<f32> for Noise<N> {
+    fn at(&self, hit: &Hit) -> f32 {
+        let x = self.noise.get(self.mapping.point_f64(hit)) as f32;
+        (0.5 + 0.5 * x).max(0.0).min(1.0)
+    }
+}
+
+// Veins of marble. Parallel stripes along the x-axis, distorted by turbulence.
+pub struct Marble {
+    turbulence: Fbm,
+    // How much the stripes are distorted
+    pub power: f32,
+    pub mapping: Mapping,
+}
+
+impl Marble {
+    pub fn new(mapping: Mapping, power: f32) -> Self {
+        Marble {
+            turbulence: Fbm::new().set_octaves(4),
+            power,
+            mapping,
+        }
+    }
+}
+
+impl Texture<f32> for Marble {
+    fn at(&self, hit: &Hit) -> f32 {
+        let p = self.mapping.point(hit);
+        let t = self.turbulence.get(self.mapping.point_f64(hit)) as f32;
+        0.5 + 0.5 * (p.x + self.power * t).sin()
+    }
+}
+
+// Growth rings of wood. Concentric cylinders around the y-axis, with some
+// turbulence to make the rings uneven.
+pub struct Wood {
+    rings: Turbulence<Cylinders>,
+    pub mapping: Mapping,
+}
+
+impl Wood {
+    pub fn new(mapping: Mapping, power: f32) -> Self {
+        Wood {
+            rings: Turbulence::new(Cylinders::new()).set_power(power as f64),
+            mapping,
+        }
+    }
+}
+
+impl Texture<f32> for Wood {
+    fn at(&self, hit: &Hit) -> f32 {
+        let x = self.rings.get(self.mapping.point_f64(hit)) as f32;
+        (0.5 + 0.5 * x).max(0.0).min(1.0)
+    }
+}
+
+// Linear interpolation between two textures, `a` where `t` is 0 and `b` where
+// `t` is 1.
+pub struct Mix<T> {
+    pub a: Tex<T>,
+    pub b: Tex<T>,
+    pub t: Tex<f32>,
+}
+
+impl<T> Texture<T> for Mix<T>
+where
+    T: Add<Output = T> + Mul<f32, Output = T>,
+{
+    fn at(&self, hit: &Hit) -> T {
+        let t = self.t.at(hit);
+        self.a.at(hit) * (1.0 - t) + self.b.at(hit) * t
+    }
+}
+
+// Remaps the values of a texture as `x * scale + offset`.
+pub struct Remap<T> {
+    pub tex: Tex<T>,
+    pub scale: f32,
+    pub offset: T,
+}
+
+impl<T> Texture<T> for Remap<T>
+where
+    T: Clone + Send + Sync + Add<Output = T> + Mul<f32, Output = T>,
+{
+    fn at(&self, hit: &Hit) -> T {
+        self.tex.at(hit) * self.scale + self.offset.clone()
+    }
+}
+
+// Shorthand for mixing two colors by a scalar pattern.
+pub fn mix<T, X>(a: T, b: T, t: X) -> Tex<T>
+where
+    T: Clone + Send + Sync + 'static,
+    T: Add<Output = T> + Mul<f32, Output = T>,
+    X: Texture<f32> + 'static,
+{
+    Tex::new(Mix {
+        a: a.into(),
+        b: b.into(),
+        t: Tex::new(t),
+    })
+}
@@ -0,0 +1,240 @@
+use nalgebra_glm as glm;
+use nalgebra_glm::Vec3;
+use rand::prelude::*;
+use std::f32::consts::FRAC_1_PI;
+
+use crate::intersect::*;
+use crate::material::*;
+use crate::microfacet::*;
+use crate::texture::*;
+
+// Directional albedo of a white `charlie_d` sheen lobe. Rows are for
+// roughness, and columns for the cosine of the outgoing direction, both
+// evenly spaced over [0, 1].
+//
+// Integrated numerically. Above 1 at grazing angles for low roughness, as
+// the visibility term isn't energy conserving there.
+const SHEEN_ALBEDO: [[f32; 16]; 16] = [
+    [
+        1.505, 1.086, 0.728, 0.497, 0.338, 0.228, 0.151, 0.097, 0.060, 0.036,
+        0.020, 0.010, 0.005, 0.002, 0.001, 0.000,
+    ],
+    [
+        1.348, 1.010, 0.712, 0.512, 0.368, 0.263, 0.185, 0.128, 0.086, 0.056,
+        0.035, 0.020, 0.011, 0.005, 0.002, 0.000,
+    ],
+    [
+        1.040, 0.838, 0.650, 0.514, 0.409, 0.325, 0.256, 0.201, 0.155, 0.118,
+        0.087, 0.062, 0.043, 0.027, 0.015, 0.006,
+    ],
+    [
+        0.902, 0.750, 0.606, 0.500, 0.415, 0.344, 0.285, 0.235, 0.192, 0.155,
+        0.123, 0.095, 0.071, 0.051, 0.034, 0.020,
+    ],
+    [
+        0.820, 0.695, 0.576, 0.486, 0.413, 0.352, 0.300, 0.255, 0.215, 0.179,
+        0.148, 0.120, 0.095, 0.073, 0.053, 0.035,
+    ],
+    [
+        0.766, 0.657, 0.553, 0.474, 0.410, 0.356, 0.308, 0.267, 0.230, 0.196,
+        0.166, 0.138, 0.113, 0.091, 0.070, 0.051,
+    ],
+    [
+        0.726, 0.629, 0.536, 0.465, 0.407, 0.357, 0.314, 0.275, 0.240, 0.209,
+        0.180, 0.153, 0.129, 0.106, 0.084, 0.065,
+    ],
+    [
+        0.696, 0.607, 0.522, 0.457, 0.403, 0.357, 0.317, 0.281, 0.248, 0.218,
+        0.191, 0.165, 0.141, 0.119, 0.097, 0.077,
+    ],
+    [
+        0.673, 0.590, 0.511, 0.450, 0.400, 0.357, 0.319, 0.285, 0.254, 0.226,
+        0.200, 0.175, 0.152, 0.130, 0.109, 0.089,
+    ],
+    [
+        0.654, 0.576, 0.501, 0.444, 0.397, 0.357, 0.321, 0.289, 0.259, 0.232,
+        0.207, 0.183, 0.160, 0.139, 0.119, 0.099,
+    ],
+    [
+        0.638, 0.564, 0.493, 0.439, 0.395, 0.356, 0.322, 0.292, 0.263, 0.237,
+        0.213, 0.190, 0.168, 0.147, 0.127, 0.108,
+    ],
+    [
+        0.625, 0.554, 0.486, 0.435, 0.392, 0.356, 0.323, 0.294, 0.267, 0.242,
+        0.218, 0.196, 0.175, 0.155, 0.135, 0.116,
+    ],
+    [
+        0.614, 0.546, 0.481, 0.431, 0.390, 0.355, 0.324, 0.295, 0.269, 0.245,
+        0.223, 0.201, 0.181, 0.161, 0.142, 0.124,
+    ],
+    [
+        0.604, 0.538, 0.475, 0.428, 0.388, 0.354, 0.324, 0.297, 0.272, 0.248,
+        0.226, 0.206, 0.186, 0.167, 0.148, 0.130,
+    ],
+    [
+        0.596, 0.532, 0.471, 0.425, 0.387, 0.354, 0.325, 0.298, 0.274, 0.251,
+        0.230, 0.210, 0.190, 0.172, 0.154, 0.136,
+    ],
+    [
+        0.588, 0.526, 0.467, 0.422, 0.385, 0.353, 0.325, 0.299, 0.276, 0.254,
+        0.233, 0.213, 0.194, 0.176, 0.159, 0.141,
+    ],
+];
+
+// Sheen, like the soft highlight at the edges of velvet and other fabrics
+// with fibers sticking out. Can be used on its own, or on top of some base
+// material, like a diffuse one for the color of the cloth.
+//
+// Energy compensated, in that the base only gets the light which isn't
+// reflected by the sheen.
+#[derive(Clone)]
+pub struct Sheen {
+    pub color: Tex<Vec3>,
+    pub roughness: Tex<f32>,
+    pub base: Option<Mat>,
+}
+
+// The parameters of a `Sheen` at some specific point
+struct SheenAt<'m> {
+    color: Vec3,
+    roughness: f32,
+    base: Option<&'m Mat>,
+}
+
+impl Sheen {
+    fn at(&self, hit: &Hit) -> SheenAt<'_> {
+        SheenAt {
+            color: self.color.at(hit),
+            roughness: self.roughness.at(hit).max(0.0).min(1.0),
+            base: self.base.as_ref(),
+        }
+    }
+}
+
+impl<'m> SheenAt<'m> {
+    fn alpha(&self) -> f32 {
+        self.roughness.max(0.05)
+    }
+
+    // Albedo of the sheen lobe for the outgoing direction `wo`, capped at 1
+    fn albedo(&self, wo: Vec3) -> Vec3 {
+        self.color * sheen_albedo(cos_theta(wo), self.roughness).min(1.0)
+    }
+
+    // Probability of sampling the sheen lobe over the base
+    fn sheen_probability(&self, wo: Vec3) -> f32 {
+        match self.base {
+            Some(_) => {
+                let a = luminance(self.albedo(wo));
+                let b = 1.0 - glm::comp_max(&self.albedo(wo));
+                if a + b > 0.0 {
+                    a / (a + b)
+                } else {
+                    1.0
+                }
+            }
+            None => 1.0,
+        }
+    }
+
+    // In the local frame of the surface
+    fn eval(&self, hit: &Hit, frame: &Frame, wi: Vec3, wo: Vec3) -> Vec3 {
+        if cos_theta(wi) <= 0.0 || cos_theta(wo) <= 0.0 {
+            return Vec3::zeros();
+        }
+        let wh = (wi + wo).normalize();
+        // Where the lobe reflects more than it gets, scale it down
+        let norm = sheen_albedo(cos_theta(wo), self.roughness).max(1.0);
+        let sheen =
+            self.color * charlie_d(wh, self.alpha()) * sheen_visibility(wi, wo)
+                / norm;
+        match self.base {
+            Some(base) => {
+                let scale = 1.0 - glm::comp_max(&self.albedo(wo));
+                sheen
+                    + base.eval(frame.to_world(wi), frame.to_world(wo), hit)
+                        * scale
+            }
+            None => sheen,
+        }
+    }
+
+    fn pdf(&self, hit: &Hit, frame: &Frame, wi: Vec3, wo: Vec3) -> f32 {
+        if cos_theta(wo) <= 0.0 {
+            return 0.0;
+        }
+        let p = self.sheen_probability(wo);
+        let sheen_pdf = cos_theta(wi).max(0.0) * FRAC_1_PI;
+        match self.base {
+            Some(base) => {
+                p * sheen_pdf
+                    + (1.0 - p)
+                        * base.pdf(frame.to_world(wi), frame.to_world(wo), hit)
+            }
+            None => sheen_pdf,
+        }
+    }
+}
+
+impl Bsdf for Sheen {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        let frame = Frame::new(hit.normal);
+        self.at(hit)
+            .eval(hit, &frame, frame.to_local(wi), frame.to_local(wo))
+    }
+
+    // The sheen lobe is broad, so it's sampled by the cosine, and the base
+    // by itself. The lobe is chosen by their albedos.
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        let frame = Frame::new(hit.normal);
+        let s = self.at(hit);
+        let wo_l = frame.to_local(wo);
+        let (wi, lobe) = match s.base {
+            Some(base) if rand(rng) >= s.sheen_probability(wo_l) => {
+                let sample = base.sample(rng, wo, hit);
+                if sample.pdf == 0.0 {
+                    return sample;
+                }
+                (sample.wi, sample.lobe)
+            }
+            _ => (frame.to_world(cosine_sample_hemisphere(rng)), Lobes::GLOSSY),
+        };
+        let wi_l = frame.to_local(wi);
+        DirSample {
+            wi,
+            pdf: s.pdf(hit, &frame, wi_l, wo_l),
+            brdf: s.eval(hit, &frame, wi_l, wo_l),
+            lobe,
+        }
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        let frame = Frame::new(hit.normal);
+        self.at(hit)
+            .pdf(hit, &frame, frame.to_local(wi), frame.to_local(wo))
+    }
+
+    fn lobes(&self) -> Lobes {
+        match &self.base {
+            Some(base) => Lobes::GLOSSY | base.lobes(),
+            None => Lobes::GLOSSY,
+        }
+    }
+
+    fn dispersive(&self) -> bool {
+        self.base.as_ref().map_or(false, |base| base.dispersive())
+    }
+}
+
+// Bilinear lookup in `SHEEN_ALBEDO`
+fn sheen_albedo(cos_o: f32, roughness: f32) -> f32 {
+    let n = SHEEN_ALBEDO.len() - 1;
+    let x = cos_o.max(0.0).min(1.0) * n as f32;
+    let y = roughness.max(0.0).min(1.0) * n as f32;
+    let (x0, y0) = ((x as usize).min(n - 1), (y as usize).min(n - 1));
+    let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+    let row = |y: usize| {
+        SHEEN_ALBEDO[y][x0] * (1.0 - tx) + SHEEN_ALBEDO[y][x0 + 1] * tx
+    };
+    row(y0) * (1.0 - ty) + row(y0 + 1) * ty
+}
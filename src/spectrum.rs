@@ -0,0 +1,180 @@
+// Spectral rendering. Instead of red, green, and blue, paths carry the
+// radiance at a few wavelengths, which are converted to RGB through the CIE
+// XYZ color space once the path is done.
+//
+// To fit in the rest of the tracer, a path carries exactly three wavelengths,
+// so that the components of a `Vec3` can be used as-is for them instead of
+// for the RGB channels.
+
+use nalgebra_glm::{mat3, vec3, Mat3, Vec3};
+
+// The range of wavelengths sampled, in nm
+pub const LAMBDA_MIN: f32 = 360.0;
+pub const LAMBDA_MAX: f32 = 830.0;
+
+// The wavelength at which the IOR of a non-spectral path is evaluated. The
+// sodium D-line, where IORs are usually given.
+const LAMBDA_D: f32 = 589.3;
+
+// Integral of the CIE Y matching function over all wavelengths
+const CIE_Y_INTEGRAL: f32 = 106.856_895;
+
+// Sample a set of wavelengths by "hero wavelength sampling", as described by
+// Wilkie et al. in "Hero Wavelength Spectral Sampling" (2014). The first
+// component is the hero wavelength, picked uniformly, and the others are
+// spaced evenly from it, wrapping around the range.
+pub fn sample_wavelengths(u: f32) -> Vec3 {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let hero = u * range;
+    vec3(hero, hero + range / 3.0, hero + 2.0 * range / 3.0)
+        .map(|l| LAMBDA_MIN + l % range)
+}
+
+// Each wavelength of a set has the same pdf
+pub fn wavelength_pdf() -> f32 {
+    1.0 / (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+// The value of an RGB color at `wavelength`, when upsampled to a smooth
+// spectrum.
+//
+// The spectrum is a blend of three smooth bumps that sum to one everywhere,
+// one per channel. It's linear in the color, so white stays a flat spectrum of
+// one and any reflectance in [0, 1] stays energy conserving, but saturated
+// colors don't survive the round trip through XYZ exactly.
+pub fn rgb_to_spectrum(rgb: Vec3, wavelength: f32) -> f32 {
+    let r = smoothstep(560.0, 610.0, wavelength);
+    let b = 1.0 - smoothstep(470.0, 520.0, wavelength);
+    let g = 1.0 - r - b;
+    rgb.x * r + rgb.y * g + rgb.z * b
+}
+
+// Upsample an RGB color at each wavelength of a spectral path. Leaves the
+// color as is for an RGB path.
+pub fn upsample(rgb: Vec3, wavelengths: Option<Vec3>) -> Vec3 {
+    match wavelengths {
+        Some(ls) => ls.map(|l| rgb_to_spectrum(rgb, l)),
+        None => rgb,
+    }
+}
+
+// Convert the radiance carried by a spectral path to linear sRGB. Each
+// wavelength sample is one estimate of the color, weighted by the CIE color
+// matching functions.
+pub fn spectrum_to_rgb(radiance: Vec3, wavelengths: Vec3) -> Vec3 {
+    let xyz = (0..3).fold(Vec3::zeros(), |acc, i| {
+        acc + cie_xyz(wavelengths[i]) * radiance[i]
+    }) / (3.0 * wavelength_pdf() * CIE_Y_INTEGRAL);
+    xyz_to_srgb() * xyz
+}
+
+// The linear sRGB color of a spectral reflectance `f(λ)` under white light,
+// integrated numerically at `n` evenly spaced wavelengths. For when an RGB
+// path meets something that is only described spectrally. Colors outside of
+// the sRGB gamut are clipped to a reflectance in [0, 1].
+pub fn reflectance_to_rgb(n: usize, f: impl Fn(f32) -> f32) -> Vec3 {
+    let step = (LAMBDA_MAX - LAMBDA_MIN) / n as f32;
+    let xyz = (0..n).fold(Vec3::zeros(), |acc, i| {
+        let l = LAMBDA_MIN + (i as f32 + 0.5) * step;
+        acc + cie_xyz(l) * f(l)
+    }) * step
+        / CIE_Y_INTEGRAL;
+    (xyz_to_srgb() * xyz).map(|c| c.max(0.0).min(1.0))
+}
+
+// The CIE 1931 2° color matching functions, approximated by the multi-lobe
+// fit of Wyman et al. "Simple Analytic Approximations to the CIE XYZ Color
+// Matching Functions" (2013).
+fn cie_xyz(l: f32) -> Vec3 {
+    let g = |mu: f32, s1: f32, s2: f32| {
+        let t = (l - mu) / if l < mu { s1 } else { s2 };
+        (-0.5 * t * t).exp()
+    };
+    vec3(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7)
+            - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+// From XYZ with an equal-energy white point to linear sRGB, adapting the
+// white point to D65 with the Bradford transform. That way a flat spectrum
+// comes out white, like it does in RGB mode.
+fn xyz_to_srgb() -> Mat3 {
+    #[rustfmt::skip]
+    let xyz_to_srgb = mat3(
+        3.240_454_2, -1.537_138_5, -0.498_531_4,
+        -0.969_266, 1.876_010_8, 0.041_556,
+        0.055_643_4, -0.204_025_9, 1.057_225_2,
+    );
+    #[rustfmt::skip]
+    let bradford_e_to_d65 = mat3(
+        0.953_187_4, -0.026_590_6, 0.023_873_1,
+        -0.038_246_7, 1.028_840_6, 0.009_406,
+        0.002_606_8, -0.003_033_2, 1.089_256_5,
+    );
+    xyz_to_srgb * bradford_e_to_d65
+}
+
+fn smoothstep(a: f32, b: f32, x: f32) -> f32 {
+    let t = ((x - a) / (b - a)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Index of refraction of a dielectric, possibly varying with wavelength.
+// Dispersion is what splits white light into a rainbow through a prism.
+#[derive(Clone, Copy)]
+pub enum Ior {
+    Const(f32),
+    // Cauchy's equation `n = a + b / λ²`, with λ in μm
+    Cauchy { a: f32, b: f32 },
+    // The Sellmeier equation `n² = 1 + Σ b λ² / (λ² - c)`, with λ in μm.
+    // Glass manufacturers give their coefficients in this form.
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Ior {
+    // Common borosilicate crown glass, Schott N-BK7
+    pub fn bk7() -> Self {
+        Ior::Sellmeier {
+            b: [1.039_612_1, 0.231_792_34, 1.010_469_5],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        }
+    }
+
+    // Dense flint glass, Schott SF11. Disperses a lot more than crown glass.
+    pub fn dense_flint() -> Self {
+        Ior::Sellmeier {
+            b: [1.737_597, 0.313_747_35, 1.898_781],
+            c: [0.013_188_707, 0.062_306_814, 155.236_29],
+        }
+    }
+
+    // The IOR at `wavelength` in nm, or at the D-line if there is none, as
+    // for RGB paths.
+    pub fn at(&self, wavelength: Option<f32>) -> f32 {
+        let l = wavelength.unwrap_or(LAMBDA_D) / 1000.0;
+        let l2 = l * l;
+        match *self {
+            Ior::Const(n) => n,
+            Ior::Cauchy { a, b } => a + b / l2,
+            Ior::Sellmeier { b, c } => (1.0
+                + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>())
+            .sqrt(),
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        match self {
+            Ior::Const(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl From<f32> for Ior {
+    fn from(n: f32) -> Self {
+        Ior::Const(n)
+    }
+}
@@ -0,0 +1,100 @@
+use nalgebra_glm::Vec3;
+use std::sync::Mutex;
+
+use crate::geom::*;
+use crate::photon::*;
+use crate::trace::*;
+
+// The fraction of the photons found at a pixel in a frame that are kept when
+// shrinking its radius. Lower values shrink the radii faster, trading less
+// blur for more noise.
+const ALPHA: f32 = 2.0 / 3.0;
+
+// Stochastic progressive photon mapping, after Hachisuka and Jensen's
+// "Stochastic Progressive Photon Mapping" (2009). Every frame is rendered as
+// by the photon mapping integrator, with new photons, but with a gather
+// radius of its own for every pixel. The radius of a pixel shrinks a little
+// after every frame, the more so the more photons were found there.
+//
+// The frames are simply averaged as they accumulate, which as shown by Knaus
+// and Zwicker in "Progressive Photon Mapping: A Probabilistic Approach"
+// (2011) converges just as well as keeping track of the flux per pixel. The
+// blur of photon mapping fades away over the frames, and with caustics
+// showing up from the start, the image converges to the same result as the
+// path tracer in scenes without participating media. As in photon mapping,
+// media only attenuate light, and the light they would scatter is missing.
+// The radii start over from the gather radius whenever the accumulation
+// does.
+pub struct ProgressivePhotonMapping {
+    maps: PhotonMaps,
+    pixels: Vec<Mutex<PixelStats>>,
+}
+
+// The progress of a pixel over the accumulated frames
+#[derive(Clone, Copy)]
+struct PixelStats {
+    radius: f32,
+    // The number of photons found at the pixel so far, of which only the
+    // fraction `ALPHA` of every frame is kept
+    photons: f32,
+}
+
+impl ProgressivePhotonMapping {
+    pub fn new() -> Self {
+        ProgressivePhotonMapping {
+            maps: PhotonMaps::new(),
+            pixels: vec![],
+        }
+    }
+}
+
+impl Integrator for ProgressivePhotonMapping {
+    fn name(&self) -> &'static str {
+        "sppm"
+    }
+
+    fn prepare(
+        &mut self,
+        scene: &[Sphere],
+        view: &View,
+        depths: Depths,
+        photons: Photons,
+        _: Mutations,
+        spectral: bool,
+    ) {
+        let n = view.dims[0] as usize * view.dims[1] as usize;
+        if view.frame == 0 || self.pixels.len() != n {
+            let stats = PixelStats {
+                radius: photons.radius,
+                photons: 0.0,
+            };
+            self.pixels = (0..n).map(|_| Mutex::new(stats)).collect();
+        }
+        self.maps =
+            PhotonMaps::trace(scene, view, depths, photons.count, spectral);
+    }
+
+    fn radiance(
+        &self,
+        path: Path,
+        scene: &[Sphere],
+        depths: Depths,
+        view: &View,
+    ) -> Vec3 {
+        let (x, y) = path.pixel;
+        let i = y as usize * view.dims[0] as usize + x as usize;
+        // Every pixel is only traced once per frame, so this never blocks
+        let mut stats = self.pixels[i].lock().unwrap();
+        let (radiance, found) =
+            photon_radiance(path, scene, depths, &self.maps, stats.radius);
+        // Shrink the radius so that the photons kept would have the same
+        // density in the smaller disc
+        if found > 0 {
+            let found = found as f32;
+            let photons = stats.photons + ALPHA * found;
+            stats.radius *= (photons / (stats.photons + found)).sqrt();
+            stats.photons = photons;
+        }
+        radiance
+    }
+}
@@ -0,0 +1,48 @@
+use nalgebra_glm::Vec3;
+use rand::prelude::*;
+
+use crate::intersect::*;
+use crate::layered::*;
+use crate::material::*;
+use crate::medium::*;
+
+// A translucent object, like skin, wax, or marble. The surface is a dielectric
+// interface, and light that makes it inside does a random walk through a
+// scattering medium until it leaves the object again.
+//
+// Only makes sense on closed shapes.
+#[derive(Clone)]
+pub struct Subsurface {
+    pub surface: Coat,
+    // The color of the object as a whole
+    pub albedo: Vec3,
+    // The average distance light travels inside before scattering, per color
+    // channel
+    pub mfp: Vec3,
+}
+
+impl Bsdf for Subsurface {
+    fn eval(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> Vec3 {
+        self.surface.eval(wi, wo, hit)
+    }
+
+    fn sample(&self, rng: &mut dyn RngCore, wo: Vec3, hit: &Hit) -> DirSample {
+        self.surface.sample(rng, wo, hit)
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, hit: &Hit) -> f32 {
+        self.surface.pdf(wi, wo, hit)
+    }
+
+    fn lobes(&self) -> Lobes {
+        self.surface.lobes()
+    }
+
+    fn dispersive(&self) -> bool {
+        self.surface.dispersive()
+    }
+
+    fn interior(&self) -> Option<Medium> {
+        Some(Medium::subsurface(self.albedo, self.mfp))
+    }
+}
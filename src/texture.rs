@@ -0,0 +1,188 @@
+use image::{hdr::HDRDecoder, ImageResult};
+use nalgebra_glm::{vec3, Vec2, Vec3};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::intersect::*;
+
+// Something that can be looked up at a surface point to give a value of type
+// `T`, e.g. the color of a material.
+pub trait Texture<T>: Send + Sync {
+    fn at(&self, hit: &Hit) -> T;
+
+    // The value everywhere, if it's the same everywhere. Lets materials tell
+    // e.g. whether they can let any light through at all.
+    fn constant(&self) -> Option<T> {
+        None
+    }
+}
+
+// A shared handle to some texture. Like `Mat`, cloning is cheap.
+#[derive(Clone)]
+pub struct Tex<T>(Arc<dyn Texture<T>>);
+
+impl<T> Tex<T> {
+    pub fn new<X: Texture<T> + 'static>(tex: X) -> Self {
+        Tex(Arc::new(tex))
+    }
+
+    pub fn at(&self, hit: &Hit) -> T {
+        self.0.at(hit)
+    }
+
+    pub fn constant(&self) -> Option<T> {
+        self.0.constant()
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> From<T> for Tex<T> {
+    fn from(x: T) -> Self {
+        Tex::new(Const(x))
+    }
+}
+
+// The same value everywhere.
+pub struct Const<T>(pub T);
+
+impl<T: Clone + Send + Sync> Texture<T> for Const<T> {
+    fn at(&self, _: &Hit) -> T {
+        self.0.clone()
+    }
+
+    fn constant(&self) -> Option<T> {
+        Some(self.0.clone())
+    }
+}
+
+// How to handle texture coordinates outside of [0, 1].
+#[derive(Clone, Copy)]
+pub enum Wrap {
+    Repeat,
+    Mirror,
+    Clamp,
+}
+
+impl Wrap {
+    fn apply(self, i: isize, n: usize) -> usize {
+        let n = n as isize;
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Mirror => {
+                let j = i.rem_euclid(2 * n);
+                if j < n {
+                    j
+                } else {
+                    2 * n - 1 - j
+                }
+            }
+            Wrap::Clamp => i.max(0).min(n - 1),
+        };
+        i as usize
+    }
+}
+
+// A decoded image with linear RGB texels, row-major with the first row at the
+// top.
+pub struct Image {
+    w: usize,
+    h: usize,
+    texels: Vec<Vec3>,
+}
+
+impl Image {
+    // Load an image holding colors, e.g. albedo. 8-bit formats such as PNG
+    // and PPM are assumed to be sRGB encoded and are converted to linear.
+    pub fn load_color<P: AsRef<Path>>(path: P) -> ImageResult<Self> {
+        Self::load(path.as_ref(), srgb_to_linear)
+    }
+
+    // Load an image holding non-color data, e.g. roughness, which is used
+    // as-is.
+    pub fn load_data<P: AsRef<Path>>(path: P) -> ImageResult<Self> {
+        Self::load(path.as_ref(), |x| x)
+    }
+
+    fn load(path: &Path, decode: fn(f32) -> f32) -> ImageResult<Self> {
+        let is_hdr = path
+            .extension()
+            .map_or(false, |ext| ext.eq_ignore_ascii_case("hdr"));
+        if is_hdr {
+            // Radiance HDR images are always linear.
+            let decoder = HDRDecoder::new(BufReader::new(File::open(path)?))?;
+            let meta = decoder.metadata();
+            let texels = decoder
+                .read_image_hdr()?
+                .into_iter()
+                .map(|p| vec3(p[0], p[1], p[2]))
+                .collect();
+            Ok(Image {
+                w: meta.width as usize,
+                h: meta.height as usize,
+                texels,
+            })
+        } else {
+            let img = image::open(path)?.to_rgb();
+            let texels = img
+                .pixels()
+                .map(|p| {
+                    vec3(p[0], p[1], p[2]).map(|c| decode(c as f32 / 255.0))
+                })
+                .collect();
+            Ok(Image {
+                w: img.width() as usize,
+                h: img.height() as usize,
+                texels,
+            })
+        }
+    }
+
+    fn texel(&self, x: isize, y: isize, wrap: Wrap) -> Vec3 {
+        self.texels[wrap.apply(y, self.h) * self.w + wrap.apply(x, self.w)]
+    }
+
+    // Bilinearly filtered lookup. `uv` of (0, 0) is the top left corner of
+    // the image.
+    pub fn bilinear(&self, uv: Vec2, wrap: Wrap) -> Vec3 {
+        // Texel centers are at half-integer coordinates
+        let x = uv.x * self.w as f32 - 0.5;
+        let y = uv.y * self.h as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let top = self.texel(x0, y0, wrap) * (1.0 - fx)
+            + self.texel(x0 + 1, y0, wrap) * fx;
+        let bottom = self.texel(x0, y0 + 1, wrap) * (1.0 - fx)
+            + self.texel(x0 + 1, y0 + 1, wrap) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+// An image mapped onto a surface by its UV-coordinates.
+pub struct ImageTex {
+    pub image: Arc<Image>,
+    pub wrap: Wrap,
+}
+
+impl Texture<Vec3> for ImageTex {
+    fn at(&self, hit: &Hit) -> Vec3 {
+        self.image.bilinear(hit.uv, self.wrap)
+    }
+}
+
+// Scalar lookups, e.g. for roughness, just read the red channel. For
+// grayscale images all channels are the same anyways.
+impl Texture<f32> for ImageTex {
+    fn at(&self, hit: &Hit) -> f32 {
+        self.image.bilinear(hit.uv, self.wrap).x
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
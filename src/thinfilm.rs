@@ -0,0 +1,102 @@
+use nalgebra_glm::Vec3;
+use num_complex::Complex32;
+use std::f32::consts::PI;
+
+use crate::intersect::*;
+use crate::spectrum::*;
+use crate::texture::*;
+
+// Number of wavelengths the reflectance of a film is integrated over for RGB
+// paths
+const FILM_RGB_SAMPLES: usize = 16;
+
+// A transparent film on top of a surface, only about as thick as the
+// wavelength of light. Light reflected off the top of the film interferes
+// with light reflected off the bottom, so that some wavelengths are
+// reflected more than others depending on the thickness and the angle. This
+// is the iridescence of soap bubbles, oil slicks, and heat-tinted metal.
+#[derive(Clone)]
+pub struct ThinFilm {
+    // In nm. Interesting colors happen between roughly 100 and 1000.
+    pub thickness: Tex<f32>,
+    pub ior: f32,
+}
+
+impl ThinFilm {
+    // Reflectance of the film on top of a substrate with the complex IOR
+    // `eta + i k`, for light incident from the outside at the cosine `cos_i`.
+    // The IOR of the substrate may vary per color channel, and is upsampled
+    // at each wavelength.
+    pub fn reflectance(
+        &self,
+        hit: &Hit,
+        cos_i: f32,
+        eta: Vec3,
+        k: Vec3,
+    ) -> Vec3 {
+        let d = self.thickness.at(hit);
+        let f = |l: f32| {
+            let n3 =
+                Complex32::new(rgb_to_spectrum(eta, l), rgb_to_spectrum(k, l));
+            airy_reflectance(cos_i, self.ior, n3, d, l)
+        };
+        match hit.wavelength {
+            // Only the hero wavelength is right, but a film is dispersive, so
+            // it's the only one that survives anyway.
+            Some(l) => Vec3::repeat(f(l)),
+            None => reflectance_to_rgb(FILM_RGB_SAMPLES, f),
+        }
+    }
+
+    // Reflectance of the film on top of a dielectric interface, with `eta`
+    // being the relative IOR as seen from the side of the incident light,
+    // like for `fresnel_dielectric`. The film is on the outside.
+    pub fn dielectric_reflectance(
+        &self,
+        hit: &Hit,
+        cos_i: f32,
+        eta: f32,
+    ) -> Vec3 {
+        let cos_i = cos_i.abs().min(1.0);
+        if eta >= 1.0 {
+            self.reflectance(hit, cos_i, Vec3::repeat(eta), Vec3::zeros())
+        } else {
+            // Coming from the inside. The film is lossless, so reflectance is
+            // the same from either side given the corresponding angle of the
+            // light on the outside.
+            let sin2_o = (1.0 - cos_i * cos_i) / (eta * eta);
+            if sin2_o >= 1.0 {
+                Vec3::repeat(1.0)
+            } else {
+                let cos_o = (1.0 - sin2_o).sqrt();
+                let n = Vec3::repeat(1.0 / eta);
+                self.reflectance(hit, cos_o, n, Vec3::zeros())
+            }
+        }
+    }
+}
+
+// Reflectance of a film of IOR `n2` and thickness `d` between air and a
+// substrate of complex IOR `n3`, at wavelength `l`, by summing up all the
+// internal reflections of the film. See e.g. "Fundamentals of Photonics" by
+// Saleh & Teich.
+fn airy_reflectance(cos_i: f32, n2: f32, n3: Complex32, d: f32, l: f32) -> f32 {
+    let one = Complex32::new(1.0, 0.0);
+    let n2c = Complex32::new(n2, 0.0);
+    let sin2_i = 1.0 - cos_i * cos_i;
+    let cos1 = Complex32::new(cos_i, 0.0);
+    let cos2 = (one - sin2_i / (n2c * n2c)).sqrt();
+    let cos3 = (one - sin2_i / (n3 * n3)).sqrt();
+    // Amplitude reflection coefficients of the top and bottom interfaces,
+    // for s and p polarized light
+    let r12_s = (cos1 - n2c * cos2) / (cos1 + n2c * cos2);
+    let r12_p = (n2c * cos1 - cos2) / (n2c * cos1 + cos2);
+    let r23_s = (n2c * cos2 - n3 * cos3) / (n2c * cos2 + n3 * cos3);
+    let r23_p = (n3 * cos2 - n2c * cos3) / (n3 * cos2 + n2c * cos3);
+    // Phase difference from the extra trip through the film
+    let delta = 4.0 * PI * n2c * d * cos2 / l;
+    let e = (Complex32::i() * delta).exp();
+    let r_s = (r12_s + r23_s * e) / (one + r12_s * r23_s * e);
+    let r_p = (r12_p + r23_p * e) / (one + r12_p * r23_p * e);
+    ((r_s.norm_sqr() + r_p.norm_sqr()) / 2.0).min(1.0)
+}
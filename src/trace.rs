@@ -3,23 +3,275 @@ use nalgebra_glm::{vec3, Vec3};
 use rand::prelude::*;
 use rayon::prelude::*;
 use std::cmp;
+use std::sync::atomic::{AtomicU32, Ordering};
 
+use crate::bdpt::*;
+use crate::cache::*;
 use crate::cam::*;
+use crate::direct::*;
 use crate::geom::*;
+use crate::guiding::*;
 use crate::intersect::*;
 use crate::material::*;
+use crate::medium::*;
+use crate::mlt::*;
+use crate::occlusion::*;
+use crate::photon::*;
+use crate::spectrum::*;
+use crate::sppm::*;
+use crate::whitted::*;
 
 type Pixel = (f32, f32, f32);
 
-const RAY_EPSILON: f32 = 0.0001;
-const MAX_BOUNCES: u8 = 3;
+// Paths are never terminated by Russian roulette before this many bounces
+const DEFAULT_MIN_DEPTH: u8 = 3;
+// No path bounces more than this many times
+const DEFAULT_MAX_DEPTH: u8 = 16;
+// Photons emitted from the light per frame, by the integrators that use them
+const DEFAULT_PHOTON_COUNT: u32 = 50_000;
+// Photons within this distance of a point contribute to the light there
+const DEFAULT_GATHER_RADIUS: f32 = 0.25;
+// The probability of the Metropolis integrator proposing a whole new path
+// instead of a small change to the current one
+const DEFAULT_LARGE_STEP: f32 = 0.3;
+// Max number of scattering events of a ray inside a medium. Only a failsafe,
+// as Russian roulette ends walks through absorbing media long before this.
+const MAX_MEDIUM_STEPS: u32 = 4096;
+// How far off the sky is, as seen through fog
+pub const SKY_DISTANCE: f32 = 100.0;
+// The scattering coefficient of the fog filling the scene, when turned on
+const DEFAULT_FOG_DENSITY: f32 = 0.03;
+// Fog scatters light mostly forwards, so shafts of light stand out the most
+// when looking towards the light
+const FOG_ANISOTROPY: f32 = 0.6;
 
 pub const ERR_COLOR: (f32, f32, f32) = (1_000_000.0, 0.0, 1_000_000.0);
 
-fn background_color() -> Vec3 {
+pub fn background_color() -> Vec3 {
     vec3(0.5, 0.7, 1.0)
 }
 
+// The scene is lit by a single point light
+pub fn light_pos() -> Vec3 {
+    vec3(10.0, 20.0, -10.0)
+}
+
+// The radiant intensity of the light, the same in every direction
+pub fn light_intensity() -> Vec3 {
+    vec3(1.0, 0.95, 0.9) * 1_400.0
+}
+
+// When to stop tracing paths. Paths always continue for `min` bounces, after
+// which they're terminated by Russian roulette. No path bounces more than
+// `max` times.
+#[derive(Clone, Copy)]
+pub struct Depths {
+    pub min: u8,
+    pub max: u8,
+}
+
+// How many photons to emit per frame, and how far away from a point photons
+// are gathered from
+#[derive(Clone, Copy)]
+pub struct Photons {
+    pub count: u32,
+    pub radius: f32,
+}
+
+// How the Metropolis integrator proposes new paths. With the probability
+// `large_step`, a whole new path is traced, and otherwise the current one is
+// changed a little.
+#[derive(Clone, Copy)]
+pub struct Mutations {
+    pub large_step: f32,
+}
+
+// A way of computing the light that reaches the camera. Different
+// integrators trade correctness for speed, or show only some aspect of the
+// lighting.
+pub trait Integrator: Sync {
+    // Short name to show in the GUI
+    fn name(&self) -> &'static str;
+
+    // Called before the pixels of each frame are traced, for integrators
+    // that need to trace something for the frame as a whole first
+    fn prepare(
+        &mut self,
+        _scene: &[Sphere],
+        _view: &View,
+        _depths: Depths,
+        _photons: Photons,
+        _mutations: Mutations,
+        _spectral: bool,
+    ) {
+    }
+
+    // The radiance arriving at the camera along `path.ray`, for a path that
+    // has just left the camera
+    fn radiance(
+        &self,
+        path: Path,
+        scene: &[Sphere],
+        depths: Depths,
+        view: &View,
+    ) -> Vec3;
+}
+
+// The camera and image of the frame being rendered, for integrators that
+// trace paths from elsewhere than the camera and need to find out where on
+// the image they end up.
+pub struct View<'s> {
+    pub cam_pos: Vec3,
+    pub screen_origin: Vec3,
+    pub screen_x_dir: Vec3,
+    pub screen_y_dir: Vec3,
+    pub dims: [u32; 2],
+    // The number of frames accumulated into the image before this one. 0
+    // whenever the accumulation starts over.
+    pub frame: u64,
+    // Different for every frame, unless the seed is fixed
+    pub seed: u64,
+    pub splats: &'s Splats,
+    // The medium filling the scene outside of all objects, if any
+    pub fog: Option<&'s Medium>,
+}
+
+impl View<'_> {
+    // The media of a ray starting outside of all objects, like the camera
+    // and the light do
+    pub fn media(&self, wavelengths: Option<Vec3>) -> MediumStack {
+        MediumStack::in_medium(self.fog.map(|m| m.upsampled(wavelengths)))
+    }
+
+    // The ray from the camera through the point `(u, v)` of the screen,
+    // where `(0, 0)` is the top left corner and `(1, 1)` the bottom right
+    pub fn ray(&self, u: f32, v: f32) -> Ray {
+        Ray {
+            origin: self.cam_pos,
+            dir: (self.screen_origin
+                + u * self.screen_x_dir
+                + v * self.screen_y_dir)
+                .normalize(),
+        }
+    }
+
+    // The pixel seen in the direction `dir` from the camera, if any. Camera
+    // rays go through the corners of the pixels, so a pixel covers the part
+    // of the screen closer to its corner than any other.
+    pub fn pixel(&self, dir: Vec3) -> Option<(u32, u32)> {
+        let centre = self.screen_centre();
+        let cos = dir.dot(&centre.normalize());
+        if cos <= 0.0 {
+            return None;
+        }
+        let on_screen = dir * centre.magnitude() / cos - self.screen_origin;
+        let [w, h] = self.dims;
+        let x = on_screen.dot(&self.screen_x_dir)
+            / self.screen_x_dir.magnitude_squared()
+            * w as f32;
+        let y = on_screen.dot(&self.screen_y_dir)
+            / self.screen_y_dir.magnitude_squared()
+            * h as f32;
+        let (x, y) = ((x + 0.5).floor(), (y + 0.5).floor());
+        if x >= 0.0 && x < w as f32 && y >= 0.0 && y < h as f32 {
+            Some((x as u32, y as u32))
+        } else {
+            None
+        }
+    }
+
+    // The density of the camera sending a ray in the direction `dir`, per
+    // unit solid angle. As if the rays were spread uniformly over the screen,
+    // rather than going through the pixel corners.
+    pub fn dir_pdf(&self, dir: Vec3) -> f32 {
+        if self.pixel(dir).is_none() {
+            return 0.0;
+        }
+        let centre = self.screen_centre();
+        let cos = dir.dot(&centre.normalize());
+        // Area of the screen scaled to a distance of 1 from the camera
+        let area = self.screen_x_dir.magnitude()
+            * self.screen_y_dir.magnitude()
+            / centre.magnitude_squared();
+        1.0 / (area * cos * cos * cos)
+    }
+
+    // The middle of the screen, relative to the camera
+    fn screen_centre(&self) -> Vec3 {
+        self.screen_origin + 0.5 * self.screen_x_dir + 0.5 * self.screen_y_dir
+    }
+}
+
+#[cfg(test)]
+impl<'s> View<'s> {
+    // A single pixel view from `cam_pos`, whose camera ray `ray(0.0, 0.0)`
+    // goes in the direction `dir`, without fog
+    pub fn for_tests(cam_pos: Vec3, dir: Vec3, splats: &'s Splats) -> Self {
+        View {
+            cam_pos,
+            screen_origin: dir,
+            screen_x_dir: vec3(1.0, 0.0, 0.0),
+            screen_y_dir: vec3(0.0, 1.0, 0.0),
+            dims: [1, 1],
+            frame: 0,
+            seed: 0,
+            splats,
+            fog: None,
+        }
+    }
+}
+
+// Light added to arbitrary pixels during a frame, on top of what the camera
+// rays through them gather. Pixels are traced in parallel, so the sums are
+// kept as atomic floats.
+pub struct Splats {
+    buf: Vec<AtomicU32>,
+    w: u32,
+}
+
+impl Splats {
+    pub fn new([w, h]: [u32; 2]) -> Self {
+        let n = w as usize * h as usize * 3;
+        Splats {
+            buf: (0..n).map(|_| AtomicU32::new(0.0f32.to_bits())).collect(),
+            w,
+        }
+    }
+
+    pub fn add(&self, (x, y): (u32, u32), color: Vec3) {
+        let i = 3 * (y as usize * self.w as usize + x as usize);
+        for (c, v) in self.buf[i..i + 3].iter().zip(color.iter()) {
+            let mut old = c.load(Ordering::Relaxed);
+            loop {
+                let new = (f32::from_bits(old) + v).to_bits();
+                match c.compare_exchange_weak(
+                    old,
+                    new,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => old = current,
+                }
+            }
+        }
+    }
+
+    fn get(&self, n: usize) -> Vec3 {
+        let c = |i: usize| {
+            f32::from_bits(self.buf[3 * n + i].load(Ordering::Relaxed))
+        };
+        vec3(c(0), c(1), c(2))
+    }
+
+    // Set every pixel back to black, for the next frame
+    fn clear(&mut self) {
+        for c in &mut self.buf {
+            *c.get_mut() = 0.0f32.to_bits()
+        }
+    }
+}
+
 pub struct Tracer {
     pixel_buf: Vec<Pixel>,
     random_seed: bool,
@@ -27,7 +279,24 @@ pub struct Tracer {
     accum_n_max: u64,
     accum_n: u64,
     reset_on_move: bool,
+    // Trace wavelengths instead of RGB
+    spectral: bool,
+    depths: Depths,
+    photons: Photons,
+    mutations: Mutations,
+    integrators: Vec<Box<dyn Integrator>>,
+    // Index of the integrator in use
+    integrator_i: usize,
+    // Trace at full resolution, with the indirect light from a radiance
+    // cache at the subsampled resolution
+    radiance_cache: bool,
+    cache: RadianceCache,
+    // Fill the scene with fog
+    fog: bool,
+    fog_density: f32,
     dims: [u32; 2],
+    // Reused from frame to frame, rather than allocated anew for each
+    splats: Splats,
     prev_cam: Cam,
 }
 
@@ -40,7 +309,36 @@ impl Tracer {
             accum_n_max: 0,
             accum_n: 0,
             reset_on_move: false,
+            spectral: false,
+            depths: Depths {
+                min: DEFAULT_MIN_DEPTH,
+                max: DEFAULT_MAX_DEPTH,
+            },
+            photons: Photons {
+                count: DEFAULT_PHOTON_COUNT,
+                radius: DEFAULT_GATHER_RADIUS,
+            },
+            mutations: Mutations {
+                large_step: DEFAULT_LARGE_STEP,
+            },
+            integrators: vec![
+                Box::new(PathTracer),
+                Box::new(GuidedPathTracer::new()),
+                Box::new(Bidirectional),
+                Box::new(PhotonMapping::new()),
+                Box::new(ProgressivePhotonMapping::new()),
+                Box::new(Metropolis::new()),
+                Box::new(Whitted),
+                Box::new(DirectLighting),
+                Box::new(AmbientOcclusion),
+            ],
+            integrator_i: 0,
+            radiance_cache: false,
+            cache: RadianceCache::new(),
+            fog: false,
+            fog_density: DEFAULT_FOG_DENSITY,
             dims: [0, 0],
+            splats: Splats::new([0, 0]),
             prev_cam: Cam::new(Vec3::zeros(), Vec3::zeros()),
         }
     }
@@ -51,10 +349,13 @@ impl Tracer {
         dims: [u32; 2],
         scene: &Scene,
     ) -> &[Pixel] {
-        if self.accum_n_max == 0
-            || (self.reset_on_move && cam != &self.prev_cam)
-        {
-            self.reset_accum()
+        let cam_moved = cam != &self.prev_cam;
+        if cam_moved {
+            // The cache is of the surfaces seen from the old position
+            self.cache.clear()
+        }
+        if self.accum_n_max == 0 || (self.reset_on_move && cam_moved) {
+            self.accum_n = 0
         }
         self.prev_cam = cam.clone();
         if dims != self.dims {
@@ -70,6 +371,41 @@ impl Tracer {
             self.accum_n
         };
         let a = 1.0 / (self.accum_n + 1) as f32;
+        let spectral = self.spectral;
+        let depths = self.depths;
+        self.splats.clear();
+        let fog = if self.fog {
+            Some(Medium::fog(self.fog_density, FOG_ANISOTROPY))
+        } else {
+            None
+        };
+        let view = View {
+            cam_pos,
+            screen_origin,
+            screen_x_dir,
+            screen_y_dir,
+            dims,
+            frame: self.accum_n,
+            seed,
+            splats: &self.splats,
+            fog: fog.as_ref(),
+        };
+        let radiance_cache = self.radiance_cache;
+        if radiance_cache {
+            self.cache
+                .update(scene, &view, depths, spectral, self.subsampling)
+        } else {
+            self.integrators[self.integrator_i].prepare(
+                scene,
+                &view,
+                depths,
+                self.photons,
+                self.mutations,
+                spectral,
+            )
+        }
+        let integrator = &*self.integrators[self.integrator_i];
+        let cache = &self.cache;
         self.pixel_buf
             .par_iter_mut()
             .enumerate()
@@ -77,18 +413,42 @@ impl Tracer {
                 let n = n as u64;
                 let (x, y) = (n % w, n / w);
                 let (u, v) = (x as f32 / w as f32, y as f32 / h as f32);
-                let primary_ray = Ray {
-                    origin: cam_pos,
-                    dir: (screen_origin + u * screen_x_dir + v * screen_y_dir)
-                        .normalize(),
-                    bounces: MAX_BOUNCES,
+                let mut rng = SmallRng::seed_from_u64(seed + x * y);
+                let wavelengths = if spectral {
+                    Some(sample_wavelengths(rng.gen()))
+                } else {
+                    None
+                };
+                let path = Path {
+                    pixel: (x as u32, y as u32),
+                    ray: view.ray(u, v),
+                    depth: 0,
                     throughput: Vec3::repeat(1.0),
-                    rng: &mut SmallRng::seed_from_u64(seed + x * y),
+                    radiance: Vec3::zeros(),
+                    media: view.media(wavelengths),
+                    wavelengths,
+                    hero_only: false,
+                    rng: &mut rng,
                 };
-                let color = trace(primary_ray, &scene);
+                let mut color = if radiance_cache {
+                    cache.radiance(path, &scene, depths, &view)
+                } else {
+                    integrator.radiance(path, &scene, depths, &view)
+                };
+                if let Some(ls) = wavelengths {
+                    color = spectrum_to_rgb(color, ls)
+                }
                 let old_color = from_triple(*pixel);
                 *pixel = to_triple(glm::lerp(&old_color, &color, a));
             });
+        // Splats can only be added once every pixel is done
+        let splats = &self.splats;
+        self.pixel_buf
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(n, pixel)| {
+                *pixel = to_triple(from_triple(*pixel) + a * splats.get(n))
+            });
         if self.accum_n < self.accum_n_max {
             self.accum_n += 1
         }
@@ -105,6 +465,11 @@ impl Tracer {
         self.reset_accum()
     }
 
+    pub fn toggle_spectral(&mut self) {
+        self.spectral = !self.spectral;
+        self.reset_accum()
+    }
+
     pub fn toggle_accum(&mut self) {
         self.accum_n_max = if self.accum_n_max == 0 {
             std::u64::MAX
@@ -114,6 +479,122 @@ impl Tracer {
         self.reset_accum()
     }
 
+    pub fn next_integrator(&mut self) {
+        self.integrator_i = (self.integrator_i + 1) % self.integrators.len();
+        self.reset_accum()
+    }
+
+    pub fn integrator_name(&self) -> &'static str {
+        if self.radiance_cache {
+            "path (cached)"
+        } else {
+            self.integrators[self.integrator_i].name()
+        }
+    }
+
+    pub fn radiance_cache(&self) -> bool {
+        self.radiance_cache
+    }
+
+    pub fn toggle_radiance_cache(&mut self) {
+        self.radiance_cache = !self.radiance_cache;
+        self.reset_accum()
+    }
+
+    pub fn fog(&self) -> bool {
+        self.fog
+    }
+
+    pub fn toggle_fog(&mut self) {
+        self.fog = !self.fog;
+        self.reset_accum()
+    }
+
+    pub fn fog_density(&self) -> f32 {
+        self.fog_density
+    }
+
+    pub fn decrease_fog_density(&mut self) {
+        self.fog_density /= 1.25;
+        self.reset_accum()
+    }
+
+    pub fn increase_fog_density(&mut self) {
+        self.fog_density *= 1.25;
+        self.reset_accum()
+    }
+
+    pub fn min_depth(&self) -> u8 {
+        self.depths.min
+    }
+
+    pub fn max_depth(&self) -> u8 {
+        self.depths.max
+    }
+
+    pub fn decrease_min_depth(&mut self) {
+        self.depths.min = self.depths.min.saturating_sub(1);
+        self.reset_accum()
+    }
+
+    pub fn increase_min_depth(&mut self) {
+        self.depths.min = cmp::min(self.depths.max, self.depths.min + 1);
+        self.reset_accum()
+    }
+
+    pub fn decrease_max_depth(&mut self) {
+        self.depths.max = self.depths.max.saturating_sub(1);
+        self.depths.min = cmp::min(self.depths.min, self.depths.max);
+        self.reset_accum()
+    }
+
+    pub fn increase_max_depth(&mut self) {
+        self.depths.max = self.depths.max.saturating_add(1);
+        self.reset_accum()
+    }
+
+    pub fn photon_count(&self) -> u32 {
+        self.photons.count
+    }
+
+    pub fn gather_radius(&self) -> f32 {
+        self.photons.radius
+    }
+
+    pub fn decrease_photon_count(&mut self) {
+        self.photons.count = cmp::max(1_000, self.photons.count / 2);
+        self.reset_accum()
+    }
+
+    pub fn increase_photon_count(&mut self) {
+        self.photons.count = self.photons.count.saturating_mul(2);
+        self.reset_accum()
+    }
+
+    pub fn decrease_gather_radius(&mut self) {
+        self.photons.radius /= 1.25;
+        self.reset_accum()
+    }
+
+    pub fn increase_gather_radius(&mut self) {
+        self.photons.radius *= 1.25;
+        self.reset_accum()
+    }
+
+    pub fn large_step(&self) -> f32 {
+        self.mutations.large_step
+    }
+
+    pub fn decrease_large_step(&mut self) {
+        self.mutations.large_step = (self.mutations.large_step - 0.05).max(0.0);
+        self.reset_accum()
+    }
+
+    pub fn increase_large_step(&mut self) {
+        self.mutations.large_step = (self.mutations.large_step + 0.05).min(1.0);
+        self.reset_accum()
+    }
+
     pub fn decrease_accum_n_max(&mut self) {
         self.accum_n_max = self.accum_n_max.saturating_sub(1);
         self.reset_accum()
@@ -134,12 +615,22 @@ impl Tracer {
         self.reset_accum()
     }
 
+    // Whatever made the accumulated image outdated likely did the same to
+    // the radiance cache
     pub fn reset_accum(&mut self) {
         self.accum_n = 0;
+        self.cache.clear()
     }
 
-    pub fn subsampling(&self) -> u8 {
-        self.subsampling
+    // The dimensions to trace frames at, for a window of `w` by `h` pixels.
+    // With the radiance cache, only the cache is subsampled.
+    pub fn frame_dims(&self, [w, h]: [u32; 2]) -> [u32; 2] {
+        if self.radiance_cache {
+            [w, h]
+        } else {
+            let s = self.subsampling as u32;
+            [w / s, h / s]
+        }
     }
 
     fn resize_pixel_buf(&mut self, dims: [u32; 2]) {
@@ -149,70 +640,349 @@ impl Tracer {
         let n_additional = n.saturating_sub(self.pixel_buf.len());
         self.pixel_buf.reserve_exact(n_additional);
         self.pixel_buf.resize(n, ERR_COLOR);
+        self.splats = Splats::new(dims);
         self.dims = dims;
         self.reset_accum()
     }
 }
 
-fn trace(ray: Ray, scene: &[Sphere]) -> Vec3 {
-    if let Some(hit) = closest_hit(&ray, scene) {
-        let wo = -ray.dir;
-        let hit_pos = ray.origin + hit.t * ray.dir;
-        let radiance = direct_light(&hit, hit_pos, wo, scene);
-        let sample = sample_wi(ray.rng, wo, hit.normal, hit.mat);
+// The state of a path being traced from the camera out into the scene,
+// carried over from one bounce to the next
+pub struct Path<'r> {
+    // The pixel the path was traced through
+    pub pixel: (u32, u32),
+    // The ray leaving the current vertex of the path
+    pub ray: Ray,
+    // The number of times the path has bounced before `ray`
+    pub depth: u8,
+    // The product of the BSDF weights along the path so far
+    pub throughput: Vec3,
+    // The light gathered by the path so far
+    pub radiance: Vec3,
+    // The media of the objects the ray is currently inside of
+    pub media: MediumStack,
+    // The wavelengths carried by the path in spectral mode, in nm. The
+    // components of `throughput` and `radiance` are then for these
+    // wavelengths instead of the RGB channels.
+    pub wavelengths: Option<Vec3>,
+    // Whether all but the hero wavelength have been dropped
+    pub hero_only: bool,
+    pub rng: &'r mut dyn RngCore,
+}
+
+// Unidirectional path tracing with next event estimation. Unbiased, and
+// handles every kind of material and medium.
+pub struct PathTracer;
+
+impl Integrator for PathTracer {
+    fn name(&self) -> &'static str {
+        "path"
+    }
+
+    fn radiance(
+        &self,
+        path: Path,
+        scene: &[Sphere],
+        depths: Depths,
+        _: &View,
+    ) -> Vec3 {
+        trace(path, scene, depths)
+    }
+}
+
+fn trace(mut path: Path, scene: &[Sphere], depths: Depths) -> Vec3 {
+    loop {
+        let mut hit = closest_hit(&path.ray, scene, path.rng);
+        if !walk_medium(&mut path, &mut hit, scene, depths) {
+            return path.radiance;
+        }
+        let mut hit = match hit {
+            Some(hit) => hit,
+            None => {
+                let background = upsample(background_color(), path.wavelengths);
+                return path.radiance
+                    + background.component_mul(&path.throughput);
+            }
+        };
+        hit.wavelength = path.wavelengths.map(|ls| ls.x);
+        let wo = -path.ray.dir;
+        let hit_pos = path.ray.origin + hit.t * path.ray.dir;
+        let mut radiance = direct_light(&hit, hit_pos, wo, &mut path, scene);
+        let sample = hit.mat.sample(path.rng, wo, &hit);
         let cosineterm = sample.wi.dot(&hit.normal).abs();
         // A probability of 0 means our sampled wi is actually impossible, and
         // the resulting BRDF won't make sense. Avoid nonsensical computations
         // (which will result in NaNs) by just setting throughput to 0.
-        let throughput = if sample.pdf != 0.0 {
-            ray.throughput
-                .component_mul(&((sample.brdf * cosineterm) / sample.pdf))
+        //
+        // Same thing if the direction would leak light through the actual
+        // surface due to the shading normal.
+        let mut throughput = if sample.pdf != 0.0 && !leaks(&hit, sample.wi, wo)
+        {
+            path.throughput.component_mul(&upsample(
+                (sample.brdf * cosineterm) / sample.pdf,
+                path.wavelengths,
+            ))
         } else {
             Vec3::zeros()
         };
-        let mut result = radiance.component_mul(&ray.throughput);
-        if ray.bounces > 0 && glm::comp_max(&throughput) > 0.01 {
-            let indirect_ray = Ray {
+        // The secondary wavelengths would have scattered in other directions
+        // than the hero. Keep only the hero, and compensate for the others.
+        let hero_only = path.hero_only
+            || (path.wavelengths.is_some() && hit.mat.dispersive());
+        if hero_only && !path.hero_only {
+            radiance = keep_hero(radiance);
+            throughput = keep_hero(throughput);
+        }
+        if sample.lobe.contains(Lobes::TRANSMISSION) {
+            cross_surface(&mut path.media, &hit, sample.wi, path.wavelengths)
+        }
+        path.radiance += radiance.component_mul(&path.throughput);
+        // Russian roulette. Continue paths with a probability proportional to
+        // their throughput, and weight the survivors to make up for the
+        // terminated ones. Unlike just cutting paths off, this is unbiased.
+        let survival = if path.depth < depths.min {
+            1.0
+        } else {
+            glm::comp_max(&throughput).min(1.0)
+        };
+        if path.depth < depths.max && rand(path.rng) < survival {
+            path.ray = Ray {
                 origin: hit_pos + RAY_EPSILON * sample.wi,
                 dir: sample.wi,
-                bounces: ray.bounces - 1,
-                throughput,
-                ..ray
             };
-            result += trace(indirect_ray, scene)
+            path.depth += 1;
+            path.throughput = throughput / survival;
+            path.hero_only = hero_only;
+        } else {
+            return path.radiance;
+        }
+    }
+}
+
+// Walk the path through the medium it's in, if any, up to the surface `hit`,
+// or off to the sky if `None`. Light from the light source scattered towards
+// the path is gathered along the way, and `hit` updated to the surface the
+// path goes on to. Returns whether the path survived the walk.
+pub fn walk_medium(
+    path: &mut Path,
+    hit: &mut Option<Hit>,
+    scene: &[Sphere],
+    depths: Depths,
+) -> bool {
+    let medium = match path.media.current() {
+        Some(medium) => medium,
+        None => return true,
+    };
+    let mut steps = 0;
+    loop {
+        let t_max = hit.as_ref().map_or(SKY_DISTANCE, |hit| hit.t);
+        if path.media.lit() {
+            let light = medium_light(&medium, t_max, path, scene);
+            path.radiance += light.component_mul(&path.throughput);
+        }
+        match medium.sample(path.rng, &path.ray, t_max) {
+            MediumSample::Passed { weight } => {
+                path.throughput = path.throughput.component_mul(&weight);
+                return true;
+            }
+            MediumSample::Scattered { t, weight } => {
+                steps += 1;
+                path.throughput = path.throughput.component_mul(&weight);
+                path.ray.origin += t * path.ray.dir;
+                // Russian roulette, counting scattering like bounces
+                if path.depth as u32 + steps > depths.min as u32 {
+                    let survival = glm::comp_max(&path.throughput).min(1.0);
+                    if survival < 1.0 {
+                        if rand(path.rng) >= survival {
+                            return false;
+                        }
+                        path.throughput /= survival;
+                    }
+                }
+                // Roulette never ends walks that lose no light at all, like
+                // in a medium that only scatters. Cutting them off loses
+                // their light, which biases the result, but only that of
+                // walks that have scattered thousands of times already.
+                if steps > MAX_MEDIUM_STEPS {
+                    return false;
+                }
+                path.ray.dir = medium.sample_phase(path.rng, path.ray.dir);
+                *hit = closest_hit(&path.ray, scene, path.rng);
+            }
         }
-        result
+    }
+}
+
+// The light from the light source scattered back along `path.ray` in
+// `medium`, anywhere within `t_max` of its origin. A single point along the
+// ray is picked by equiangular sampling, after Kulla and Fajardo's
+// "Importance Sampling Techniques for Path Tracing in Participating Media"
+// (2012). Points are picked by the angle they're seen at from the light, which
+// makes their density cancel out the falloff of the light with distance. Just
+// gathering light where paths happen to scatter would make fireflies of the
+// few that scatter close to the light.
+fn medium_light(
+    medium: &Medium,
+    t_max: f32,
+    path: &mut Path,
+    scene: &[Sphere],
+) -> Vec3 {
+    if medium.sigma_s == Vec3::zeros() {
+        return Vec3::zeros();
+    }
+    let ray = &path.ray;
+    let light_pos = light_pos();
+    // The distance along the ray to the point closest to the light, and the
+    // distance between them
+    let delta = (light_pos - ray.origin).dot(&ray.dir);
+    let d = (ray.origin + delta * ray.dir - light_pos).magnitude();
+    if d < RAY_EPSILON {
+        return Vec3::zeros();
+    }
+    let theta_a = (-delta).atan2(d);
+    let theta_b = (t_max - delta).atan2(d);
+    let theta = theta_a + rand(path.rng) * (theta_b - theta_a);
+    let t = delta + d * theta.tan();
+    let pos = ray.origin + t * ray.dir;
+    let wl = (light_pos - pos).normalize();
+    let phase = medium.phase(ray.dir, wl);
+    let tr = transmittance(
+        pos,
+        light_pos,
+        path.media.clone(),
+        path.wavelengths,
+        scene,
+        path.rng,
+    );
+    let light_emission = upsample(light_intensity(), path.wavelengths);
+    // The density of `t` is `d / ((θb - θa) * dist²)`, with `dist` the
+    // distance to the light
+    light_emission
+        .component_mul(&tr)
+        .component_mul(&medium.tr(path.rng, &path.ray, t))
+        .component_mul(&(medium.sigma_s * medium.density_at(pos)))
+        * phase
+        * (theta_b - theta_a)
+        / d
+}
+
+// The fraction of light making it from `from` to `to`, through the media
+// `media` the way starts out in. Surfaces block the light, except for the
+// invisible bounds of media.
+pub fn transmittance(
+    from: Vec3,
+    to: Vec3,
+    mut media: MediumStack,
+    wavelengths: Option<Vec3>,
+    scene: &[Sphere],
+    rng: &mut dyn RngCore,
+) -> Vec3 {
+    let mut ray = Ray {
+        origin: from,
+        dir: (to - from).normalize(),
+    };
+    let mut dist = (to - from).magnitude();
+    let mut tr = Vec3::repeat(1.0);
+    loop {
+        let hit = closest_hit(&ray, scene, rng).filter(|hit| hit.t < dist);
+        let t = hit.as_ref().map_or(dist, |hit| hit.t);
+        if let Some(medium) = media.current() {
+            tr = tr.component_mul(&medium.tr(rng, &ray, t))
+        }
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return tr,
+        };
+        if !hit.mat.invisible() {
+            return Vec3::zeros();
+        }
+        cross_surface(&mut media, &hit, ray.dir, wavelengths);
+        ray.origin += (hit.t + RAY_EPSILON) * ray.dir;
+        dist -= hit.t + RAY_EPSILON;
+    }
+}
+
+// Going through the surface at `hit` along `dir` takes the ray into the
+// object if it's going against the geometric normal, and out of it otherwise.
+pub fn cross_surface(
+    media: &mut MediumStack,
+    hit: &Hit,
+    dir: Vec3,
+    wavelengths: Option<Vec3>,
+) {
+    if dir.dot(&hit.geo_normal) < 0.0 {
+        media.enter(
+            hit.object,
+            interior_of(hit, wavelengths),
+            hit.mat.invisible(),
+        )
     } else {
-        background_color().component_mul(&ray.throughput)
+        media.exit(hit.object)
     }
 }
 
-fn direct_light(hit: &Hit, hit_pos: Vec3, wo: Vec3, scene: &[Sphere]) -> Vec3 {
-    let light_pos = vec3(10.0, 20.0, -10.0);
-    let light_emission = vec3(1.0, 0.95, 0.9) * 1_400.0;
+pub fn direct_light(
+    hit: &Hit,
+    hit_pos: Vec3,
+    wo: Vec3,
+    path: &mut Path,
+    scene: &[Sphere],
+) -> Vec3 {
+    let wavelengths = path.wavelengths;
+    let light_pos = light_pos();
+    let light_emission = upsample(light_intensity(), wavelengths);
     let dist = (light_pos - hit_pos).magnitude();
     let wl = (light_pos - hit_pos).normalize();
-    // If surface and light aren't facing eachother at all, there can't be any
-    // light contribution
-    if hit.normal.dot(&wl) <= 0.0 {
+    // If surface and light aren't facing eachother at all, or the surface
+    // only scatters in discrete directions, there can't be any light
+    // contribution
+    if hit.normal.dot(&wl) <= 0.0
+        || leaks(hit, wl, wo)
+        || hit.mat.lobes().is_delta()
+    {
         return Vec3::zeros();
     }
-    let shadow_ray = BasicRay {
-        origin: hit_pos + RAY_EPSILON * wl,
-        dir: wl,
-    };
-    let in_shadow = any_hit(&shadow_ray, scene).is_some();
-    if in_shadow {
+    // The light may be on the other side of a surface that lets light
+    // through, and in another medium
+    let mut media = path.media.clone();
+    if wl.dot(&hit.geo_normal) * wo.dot(&hit.geo_normal) < 0.0 {
+        cross_surface(&mut media, hit, wl, wavelengths)
+    }
+    let tr = transmittance(
+        hit_pos + RAY_EPSILON * wl,
+        light_pos,
+        media,
+        wavelengths,
+        scene,
+        path.rng,
+    );
+    if tr == Vec3::zeros() {
         return Vec3::zeros();
     }
     // convert area based pdf to solid angle
-    let weight = brdf(wl, wo, hit.normal, &hit.mat)
+    let weight = upsample(hit.mat.eval(wl, wo, hit), wavelengths)
 	// Optimal lighting conditions if the center point of both the light and
 	// surface are exactly facing eachother
 	* hit.normal.dot(&wl)
 	// Falloff. Intensity drops proportionally to the square of the distance
         / (dist * dist);
-    light_emission.component_mul(&weight)
+    light_emission.component_mul(&weight).component_mul(&tr)
+}
+
+// Whether light traveling between `wi` and `wo` would pass through the
+// surface according to the geometric normal, but not according to the
+// shading normal, or vice versa. Following such a path would let light leak
+// through solid objects, or create black spots where it shouldn't.
+pub fn leaks(hit: &Hit, wi: Vec3, wo: Vec3) -> bool {
+    let geo_side = wi.dot(&hit.geo_normal) * wo.dot(&hit.geo_normal);
+    let shading_side = wi.dot(&hit.normal) * wo.dot(&hit.normal);
+    (geo_side > 0.0) != (shading_side > 0.0)
+}
+
+// Keep only the hero wavelength of a spectral value, weighted to make up for
+// the dropped ones.
+pub fn keep_hero(v: Vec3) -> Vec3 {
+    vec3(3.0 * v.x, 0.0, 0.0)
 }
 
 fn to_triple(v: Vec3) -> (f32, f32, f32) {
@@ -0,0 +1,81 @@
+use nalgebra_glm::Vec3;
+
+use crate::geom::*;
+use crate::intersect::*;
+use crate::material::*;
+use crate::spectrum::*;
+use crate::trace::*;
+
+// Classic Whitted-style ray tracing. Every surface is lit directly by the
+// light source, and rays only continue through delta lobes, i.e. perfect
+// reflection and refraction. Much faster to converge than path tracing, but
+// misses all indirect light from diffuse and glossy surfaces, as well as
+// participating media.
+pub struct Whitted;
+
+impl Integrator for Whitted {
+    fn name(&self) -> &'static str {
+        "whitted"
+    }
+
+    fn radiance(
+        &self,
+        mut path: Path,
+        scene: &[Sphere],
+        depths: Depths,
+        _: &View,
+    ) -> Vec3 {
+        loop {
+            let mut hit = match closest_hit(&path.ray, scene, path.rng) {
+                Some(hit) => hit,
+                None => {
+                    let background =
+                        upsample(background_color(), path.wavelengths);
+                    return path.radiance
+                        + background.component_mul(&path.throughput);
+                }
+            };
+            hit.wavelength = path.wavelengths.map(|ls| ls.x);
+            let wo = -path.ray.dir;
+            let hit_pos = path.ray.origin + hit.t * path.ray.dir;
+            let mut radiance =
+                direct_light(&hit, hit_pos, wo, &mut path, scene);
+            // Materials with both delta and non-delta lobes pick one of them
+            // at random, so the delta lobes are still followed on average.
+            let sample = hit.mat.sample(path.rng, wo, &hit);
+            let follow = path.depth < depths.max
+                && sample.lobe.contains(Lobes::DELTA)
+                && sample.pdf != 0.0
+                && !leaks(&hit, sample.wi, wo);
+            let mut throughput = if follow {
+                let cosineterm = sample.wi.dot(&hit.normal).abs();
+                path.throughput.component_mul(&upsample(
+                    (sample.brdf * cosineterm) / sample.pdf,
+                    path.wavelengths,
+                ))
+            } else {
+                Vec3::zeros()
+            };
+            let hero_only = path.hero_only
+                || (path.wavelengths.is_some() && hit.mat.dispersive());
+            if hero_only && !path.hero_only {
+                radiance = keep_hero(radiance);
+                throughput = keep_hero(throughput);
+            }
+            path.radiance += radiance.component_mul(&path.throughput);
+            if !follow {
+                return path.radiance;
+            }
+            path.ray = Ray {
+                origin: hit_pos + RAY_EPSILON * sample.wi,
+                dir: sample.wi,
+            };
+            path.depth += 1;
+            path.throughput = throughput;
+            path.hero_only = hero_only;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
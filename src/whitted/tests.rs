@@ -0,0 +1,67 @@
+// Checks that Whitted-style tracing follows the smooth surfaces of the scenes.
+
+use nalgebra_glm::{vec3, Vec3};
+use rand::prelude::*;
+use std::time;
+
+use super::*;
+use crate::matlib::*;
+use crate::medium::*;
+
+// The mean radiance seen from `origin` in the direction `dir`
+fn radiance(scene: &[Sphere], origin: Vec3, dir: Vec3) -> Vec3 {
+    const SAMPLES: usize = 1_000;
+    let splats = Splats::new([1, 1]);
+    let view = View::for_tests(origin, dir, &splats);
+    let depths = Depths { min: 3, max: 16 };
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut sum = Vec3::zeros();
+    for _ in 0..SAMPLES {
+        let path = Path {
+            pixel: (0, 0),
+            ray: Ray { origin, dir },
+            depth: 0,
+            throughput: Vec3::repeat(1.0),
+            radiance: Vec3::zeros(),
+            media: MediumStack::new(),
+            wavelengths: None,
+            hero_only: false,
+            rng: &mut rng,
+        };
+        sum += Whitted.radiance(path, scene, depths, &view);
+    }
+    sum / SAMPLES as f32
+}
+
+// Looking through the smooth flint lens of `scene_3`, and the smooth glass
+// sphere behind it, at the sky. Both only reflect a few percent of the light,
+// so the sky should come through almost as it is.
+#[test]
+fn sees_through_glass() {
+    let scene = scene_3(&MatLib::presets(), time::Instant::now());
+    let seen = radiance(&scene, vec3(0.0, -0.2, 10.0), vec3(0.0, 0.0, -1.0));
+    let sky = background_color();
+    assert!(
+        (seen - sky).amax() < 0.1 * sky.amax(),
+        "saw {:?} instead of the sky {:?}",
+        seen,
+        sky
+    );
+}
+
+// The chrome sphere of `scene_1` is a perfect mirror, which reflects the sky
+// straight back when looked at head on.
+#[test]
+fn reflects_in_mirrors() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/materials.toml");
+    let lib = MatLib::load(path).unwrap();
+    let scene = scene_1(&lib, time::Instant::now());
+    let seen = radiance(&scene, vec3(0.0, 2.0, 10.0), vec3(0.0, 0.0, -1.0));
+    let sky = background_color();
+    assert!(
+        (seen - sky).amax() < 1e-3,
+        "saw {:?} instead of the sky {:?}",
+        seen,
+        sky
+    );
+}